@@ -0,0 +1,88 @@
+use sdl2::ttf::{Font, Sdl2TtfContext};
+
+use std::fmt;
+
+use crate::{
+    audio::{sfx_path, SfxPlayer},
+    rhythm::Judgement,
+};
+
+pub const FONT_PATH: &str = "font.ttf";
+pub const FONT_POINT_SIZE: u16 = 24;
+
+// Files loaded at startup. The font is required, nothing on screen can be read without it. Hit
+// sounds are optional: a missing one is logged and its judgement plays silently. The song is
+// required too but belongs to a chart, so load_song opens it and fails with GameError::Song.
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(PartialEq)]
+pub enum Asset {
+    Font,
+    HitSound(Judgement),
+}
+
+impl Asset {
+    pub fn required(&self) -> bool {
+        match self {
+            Asset::Font => true,
+            Asset::HitSound(_) => false,
+        }
+    }
+
+    pub fn path(&self) -> &'static str {
+        match self {
+            Asset::Font => FONT_PATH,
+            Asset::HitSound(judgement) => sfx_path(*judgement),
+        }
+    }
+}
+
+impl fmt::Display for Asset {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Asset::Font => write!(f, "font"),
+            Asset::HitSound(judgement) => write!(f, "{:?} hit sound", judgement),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct AssetError {
+    pub asset: Asset,
+    pub message: String,
+}
+
+impl fmt::Display for AssetError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "couldn't load the {} from {}: {}", self.asset, self.asset.path(), self.message)
+    }
+}
+
+// The font borrows the ttf context it was loaded with, which run() keeps for as long as the game
+// is up.
+pub struct Assets<'ttf> {
+    pub font: Font<'ttf, 'static>,
+}
+
+impl<'ttf> Assets<'ttf> {
+    pub fn load(ttf_context: &'ttf Sdl2TtfContext) -> Result<Assets<'ttf>, AssetError> {
+        let font = ttf_context.load_font(Asset::Font.path(), FONT_POINT_SIZE)
+            .map_err(|message| AssetError { asset: Asset::Font, message })?;
+        Ok(Assets { font })
+    }
+
+    pub fn load_hit_sounds(sfx_player: &mut SfxPlayer) {
+        for &judgement in &[Judgement::Perfect, Judgement::Great, Judgement::Good, Judgement::Miss] {
+            let asset = Asset::HitSound(judgement);
+            if let Err(message) = sfx_player.load(judgement, asset.path()) {
+                let err = AssetError { asset, message };
+                if asset.required() {
+                    log::error!("{}", err);
+                } else {
+                    log::warn!("{}, playing without it", err);
+                }
+            }
+        }
+    }
+}
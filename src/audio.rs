@@ -0,0 +1,336 @@
+use rodio::{buffer::SamplesBuffer, Device, Sink, Source};
+
+use sdl2::keyboard::Keycode;
+
+use serde::{Deserialize, Serialize};
+
+use specs::prelude::*;
+
+use std::{
+    collections::{HashMap, HashSet},
+    thread,
+    time::Duration,
+};
+
+use crate::{
+    AudioTime,
+    TargetInput,
+    lanes::LaneLayout,
+    rhythm::{AudioContext, BarIndex, Judged, Judgement, TargetBarTime},
+    song::open_audio,
+};
+
+pub const VOLUME_STEP: f32 = 0.05;
+pub const FADE_OUT_MILLIS: u64 = 200;
+pub const FADE_OUT_STEPS: u32 = 20;
+
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(PartialEq)]
+#[derive(Serialize, Deserialize)]
+pub struct Volume(pub f32);
+
+impl Default for Volume {
+    fn default() -> Volume { Volume(0.05) }
+}
+
+impl Volume {
+    pub fn nudge(&mut self, delta: f32) {
+        self.0 = (self.0 + delta).clamp(0.0, 1.0);
+    }
+}
+
+// Ramps the sink down to silence over `millis` and stops it, rather than cutting the song off
+// mid-sample. It blocks for the whole fade, so it's only for shutting down. A paused sink is
+// silent already and just stops.
+pub fn fade_out(sink: &Sink, millis: u64) {
+    if !sink.is_paused() {
+        let start = sink.volume();
+        for step in 1..=FADE_OUT_STEPS {
+            sink.set_volume(start * (1.0 - step as f32 / FADE_OUT_STEPS as f32));
+            thread::sleep(Duration::from_millis(millis / FADE_OUT_STEPS as u64));
+        }
+    }
+    sink.stop();
+}
+
+#[derive(Default)]
+pub struct VolumeSystem {
+    applied: Option<f32>,
+}
+
+impl<'a> System<'a> for VolumeSystem {
+    type SystemData = (Read<'a, Volume>,
+                       Option<Read<'a, Sink>>);
+
+    fn run(&mut self, data: Self::SystemData) {
+        let (volume, maybe_sink) = data;
+
+        if let Some(sink) = maybe_sink {
+            if self.applied != Some(volume.0) {
+                sink.set_volume(volume.0);
+                self.applied = Some(volume.0);
+            }
+        }
+    }
+}
+
+pub const SFX_POOL_SIZE: usize = 8;
+
+pub fn sfx_path(judgement: Judgement) -> &'static str {
+    match judgement {
+        Judgement::Perfect => "sfx/perfect.wav",
+        Judgement::Great => "sfx/great.wav",
+        Judgement::Good => "sfx/good.wav",
+        Judgement::Miss => "sfx/miss.wav",
+    }
+}
+
+// Decoded up front so playing one is a copy rather than a trip to the disk.
+struct SfxSample {
+    channels: u16,
+    sample_rate: u32,
+    samples: Vec<i16>,
+}
+
+// Hit sounds get a pool of sinks of their own on the song's device, each sound taking an idle sink
+// so overlapping hits play over each other instead of queueing up behind the one before. With
+// every sink busy the new sound is dropped rather than played late.
+pub struct SfxPlayer {
+    sinks: Vec<Sink>,
+    next: usize,
+    samples: HashMap<Judgement, SfxSample>,
+}
+
+impl SfxPlayer {
+    pub fn new(device: &Device, pool_size: usize) -> SfxPlayer {
+        SfxPlayer {
+            sinks: (0..pool_size.max(1)).map(|_| Sink::new(device)).collect(),
+            next: 0,
+            samples: HashMap::new(),
+        }
+    }
+
+    pub fn load(&mut self, judgement: Judgement, path: &str) -> Result<(), String> {
+        let decoder = open_audio(path).map_err(|err| err.to_string())?;
+        let channels = decoder.channels();
+        let sample_rate = decoder.sample_rate();
+        self.samples.insert(judgement, SfxSample { channels, sample_rate, samples: decoder.collect() });
+        Ok(())
+    }
+
+    // `pan` runs from -1.0 for hard left to 1.0 for hard right, None leaving the sample as it is.
+    pub fn play(&mut self, judgement: Judgement, volume: f32, pan: Option<f32>) {
+        let sample = match self.samples.get(&judgement) {
+            Some(sample) => sample,
+            None => return,
+        };
+
+        let index = match (0..self.sinks.len())
+            .map(|offset| (self.next + offset) % self.sinks.len())
+            .find(|&index| self.sinks[index].empty())
+        {
+            Some(index) => index,
+            None => {
+                log::debug!("Every sfx sink is busy, dropping {:?} sound", judgement);
+                return;
+            },
+        };
+        self.next = (index + 1) % self.sinks.len();
+
+        let sink = &self.sinks[index];
+        sink.set_volume(volume);
+        match pan {
+            Some(pan) => sink.append(SamplesBuffer::new(2, sample.sample_rate, panned(sample, pan))),
+            None => sink.append(SamplesBuffer::new(sample.channels, sample.sample_rate, sample.samples.clone())),
+        }
+    }
+}
+
+// Mixes each frame of the sample, whatever its channel count, down to mono and spreads it over a
+// stereo pair with an equal power pan.
+fn panned(sample: &SfxSample, pan: f32) -> Vec<i16> {
+    let angle = (pan.clamp(-1.0, 1.0) + 1.0) * std::f32::consts::FRAC_PI_4;
+    let (left_gain, right_gain) = (angle.cos(), angle.sin());
+    sample.samples
+        .chunks(sample.channels.max(1) as usize)
+        .flat_map(|frame| {
+            let mono = frame.iter().map(|&channel| channel as f32).sum::<f32>() / frame.len() as f32;
+            vec![(mono * left_gain) as i16, (mono * right_gain) as i16]
+        })
+        .collect()
+}
+
+// Pans hit sounds toward the side of the lane that was hit.
+pub struct SpatialSfx(pub bool);
+
+impl Default for SpatialSfx {
+    fn default() -> SpatialSfx { SpatialSfx(true) }
+}
+
+// The first lane is hard left, the last hard right and the ones between spread evenly.
+fn lane_pan(lane_layout: &LaneLayout, key: Keycode) -> Option<f32> {
+    let index = lane_layout.0.iter().position(|lane| lane.key == key)?;
+    let last = lane_layout.0.len().saturating_sub(1);
+    if last == 0 {
+        return Some(0.0);
+    }
+    Some(index as f32 / last as f32 * 2.0 - 1.0)
+}
+
+pub const MAX_SFX_OFFSET: i64 = 200;
+
+// Moves hit sounds in ms relative to their note, positive later and negative earlier, for players
+// who hear them as late. Only the feedback moves, judging is InputLatency's business.
+#[derive(Debug)]
+#[derive(Default)]
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(PartialEq)]
+#[derive(Serialize, Deserialize)]
+pub struct SfxOffset(pub i64);
+
+impl SfxOffset {
+    pub fn new(offset: i64) -> SfxOffset {
+        SfxOffset(offset.clamp(-MAX_SFX_OFFSET, MAX_SFX_OFFSET))
+    }
+}
+
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(Copy)]
+pub struct ScheduledSfx {
+    pub due: u64,
+    pub entity: Entity,
+    // Queued ahead of its judgement by a negative SfxOffset.
+    pub early: bool,
+}
+
+// Sounds waiting to play, soonest first. Sounds due at the same time keep the order they were
+// scheduled in.
+#[derive(Default)]
+pub struct SfxQueue(Vec<ScheduledSfx>);
+
+impl SfxQueue {
+    pub fn schedule(&mut self, sfx: ScheduledSfx) {
+        let index = self.0.iter().position(|queued| queued.due > sfx.due).unwrap_or(self.0.len());
+        self.0.insert(index, sfx);
+    }
+
+    pub fn pop_due(&mut self, now: u64) -> Vec<ScheduledSfx> {
+        let count = self.0.iter().take_while(|sfx| sfx.due <= now).count();
+        self.0.drain(..count).collect()
+    }
+}
+
+// Sounds each note once. Without an SfxOffset that's when its Judged first shows up; a positive
+// offset queues it that long after. A note can't be heard before it's judged, so a negative offset
+// works from the chart instead: a note coming up within the offset is queued at its target time
+// plus the offset, and sounds with its judgement's sample if that's in by then, Perfect's if not.
+// One already judged a Miss by then stays silent. Notes judged before they came up, like an
+// expired chord, still sound on their judgement.
+#[derive(Default)]
+pub(crate) struct HitSoundSystem {
+    sounded: HashSet<Entity>,
+    queue: SfxQueue,
+}
+
+impl<'a> System<'a> for HitSoundSystem {
+    type SystemData = (Entities<'a>,
+                       Read<'a, AudioTime>,
+                       Read<'a, AudioContext>,
+                       Read<'a, SfxOffset>,
+                       Read<'a, Volume>,
+                       Read<'a, SpatialSfx>,
+                       Read<'a, LaneLayout>,
+                       Option<Write<'a, SfxPlayer>>,
+                       ReadStorage<'a, Judged>,
+                       ReadStorage<'a, TargetInput>,
+                       ReadStorage<'a, TargetBarTime>,
+                       ReadStorage<'a, BarIndex>);
+
+    fn run(&mut self, data: Self::SystemData) {
+        let (
+            entities,
+            audio_time,
+            audio_context,
+            sfx_offset,
+            volume,
+            spatial_sfx,
+            lane_layout,
+            maybe_sfx_player,
+            judged_storage,
+            target_input_storage,
+            target_bar_time_storage,
+            bar_index_storage,
+        ) = data;
+
+        let mut sfx_player = match maybe_sfx_player {
+            Some(sfx_player) => sfx_player,
+            None => return,
+        };
+
+        let now = audio_time.0;
+        if sfx_offset.0 < 0 && audio_context.bar_millis > 0 {
+            let look_ahead = (-sfx_offset.0) as u64;
+            for (entity, target_bar_time, _) in (&*entities, &target_bar_time_storage, !&bar_index_storage).join() {
+                let target = audio_context.next_target_time(*target_bar_time, now);
+                if target <= now + look_ahead && self.sounded.insert(entity) {
+                    self.queue.schedule(ScheduledSfx { due: target - look_ahead, entity, early: true });
+                }
+            }
+        }
+        for (entity, _) in (&*entities, &judged_storage).join() {
+            if self.sounded.insert(entity) {
+                self.queue.schedule(ScheduledSfx { due: now + sfx_offset.0.max(0) as u64, entity, early: false });
+            }
+        }
+
+        for sfx in self.queue.pop_due(now) {
+            // A playlist may have cleared the note away since.
+            if !entities.is_alive(sfx.entity) {
+                continue;
+            }
+            let judgement = judged_storage.get(sfx.entity).map_or(Judgement::Perfect, |judged| judged.0);
+            if sfx.early && judgement == Judgement::Miss {
+                continue;
+            }
+            let pan = match (spatial_sfx.0, target_input_storage.get(sfx.entity)) {
+                (true, Some(input)) => lane_pan(&lane_layout, input.lane_key()),
+                _ => None,
+            };
+            sfx_player.play(judgement, volume.0, pan);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn queued_sounds_play_soonest_first_and_ties_in_order() {
+        let mut world = World::new();
+        let notes: Vec<Entity> = (0..4).map(|_| world.create_entity().build()).collect();
+        let mut queue = SfxQueue::default();
+        // An early sound for a note coming up is due before a late one queued ahead of it.
+        queue.schedule(ScheduledSfx { due: 300, entity: notes[0], early: false });
+        queue.schedule(ScheduledSfx { due: 250, entity: notes[1], early: true });
+        queue.schedule(ScheduledSfx { due: 300, entity: notes[2], early: false });
+        queue.schedule(ScheduledSfx { due: 100, entity: notes[3], early: true });
+
+        let popped = |queue: &mut SfxQueue, now| queue.pop_due(now).iter().map(|sfx| sfx.entity).collect::<Vec<_>>();
+        assert_eq!(popped(&mut queue, 99), vec![]);
+        assert_eq!(popped(&mut queue, 260), vec![notes[3], notes[1]]);
+        assert_eq!(popped(&mut queue, 300), vec![notes[0], notes[2]]);
+        assert_eq!(popped(&mut queue, 1000), vec![]);
+    }
+
+    #[test]
+    fn sfx_offset_is_clamped() {
+        assert_eq!(SfxOffset::new(-1000), SfxOffset(-MAX_SFX_OFFSET));
+        assert_eq!(SfxOffset::new(40), SfxOffset(40));
+        assert_eq!(SfxOffset::new(1000), SfxOffset(MAX_SFX_OFFSET));
+    }
+}
@@ -0,0 +1,129 @@
+use specs::prelude::*;
+
+use crate::{
+    AudioTime,
+    TargetInput,
+    countdown::LeadIn,
+    rhythm::{
+        BarIndex,
+        HitOffset,
+        Judged,
+        InputTimeline,
+        Judgement,
+        JudgementHistory,
+        LaneIndex,
+        LastHitError,
+        LastHitKey,
+        LastHitNote,
+        TargetBarTime,
+    },
+    state::GameState,
+};
+
+// Plays the chart by itself. While set, BarIndexTaggingSystem ignores presses and AutoplaySystem
+// does the judging instead.
+#[derive(Debug)]
+#[derive(Default)]
+#[derive(Clone)]
+#[derive(Copy)]
+pub struct Autoplay(pub bool);
+
+// Scores every unhit note Perfect on the frame audio_time reaches it, through the same resources a
+// real hit updates, so flashes, hit sounds and the combo all follow along.
+// Chord members share a target time and so land on the same frame.
+pub(crate) struct AutoplaySystem;
+
+impl<'a> System<'a> for AutoplaySystem {
+    type SystemData = (Entities<'a>,
+                       Read<'a, Autoplay>,
+                       Read<'a, AudioTime>,
+                       Read<'a, LeadIn>,
+                       Read<'a, GameState>,
+                       Write<'a, JudgementHistory>,
+                       Write<'a, InputTimeline>,
+                       Write<'a, LaneIndex>,
+                       Write<'a, LastHitError>,
+                       Write<'a, LastHitKey>,
+                       Write<'a, LastHitNote>,
+                       ReadStorage<'a, TargetInput>,
+                       ReadStorage<'a, TargetBarTime>,
+                       WriteStorage<'a, BarIndex>,
+                       WriteStorage<'a, Judged>,
+                       WriteStorage<'a, HitOffset>);
+
+    fn run(&mut self, data: Self::SystemData) {
+        let (
+            entities,
+            autoplay,
+            audio_time,
+            lead_in,
+            game_state,
+            mut judgement_history,
+            mut input_timeline,
+            mut lane_index,
+            mut last_hit_error,
+            mut last_hit_key,
+            mut last_hit_note,
+            target_input_storage,
+            target_bar_time_storage,
+            mut bar_index_storage,
+            mut judged_storage,
+            mut hit_offset_storage,
+        ) = data;
+
+        if !autoplay.0 || lead_in.0 > 0 || *game_state != GameState::Playing {
+            return;
+        }
+
+        let due: Vec<_> = (&*entities, &target_input_storage, &target_bar_time_storage, !&bar_index_storage)
+            .join()
+            .filter(|&(_, _, target_bar_time, _)| target_bar_time.0 <= audio_time.0)
+            .map(|(entity, input, target_bar_time, _)| (entity, input.0.clone(), target_bar_time.0))
+            .collect();
+
+        for (entity, keys, target_bar_time) in due {
+            if let Err(err) = bar_index_storage.insert(entity, BarIndex(0)) {
+                log::warn!("Couldn't tag autoplayed target: {:?}", err);
+            }
+            lane_index.remove(&keys, entity);
+            if let Err(err) = judged_storage.insert(entity, Judged(Judgement::Perfect)) {
+                log::warn!("Couldn't judge autoplayed target: {:?}", err);
+            }
+            if let Err(err) = hit_offset_storage.insert(entity, HitOffset(0)) {
+                log::warn!("Couldn't record autoplayed hit offset: {:?}", err);
+            }
+            judgement_history.0.push(Judgement::Perfect);
+            input_timeline.record(target_bar_time as i64, keys[0], Judgement::Perfect);
+            last_hit_error.0 = Some(0);
+            last_hit_key.0 = Some(keys[0]);
+            last_hit_note.0 = Some(entity);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use sdl2::keyboard::Keycode;
+    use specs::RunNow;
+
+    #[test]
+    fn notes_past_the_first_bar_are_played_at_their_own_time() {
+        let mut world = World::new();
+        System::setup(&mut AutoplaySystem, &mut world.res);
+        world.add_resource(Autoplay(true));
+        world.add_resource(GameState::Playing);
+        // Bar 2 at 120 BPM.
+        let note = world.create_entity().with(TargetInput(vec![Keycode::D])).with(TargetBarTime(2500)).build();
+
+        world.add_resource(AudioTime(500));
+        AutoplaySystem.run_now(&world.res);
+        assert!(world.read_storage::<Judged>().get(note).is_none());
+
+        world.add_resource(AudioTime(2500));
+        AutoplaySystem.run_now(&world.res);
+        assert_eq!(world.read_storage::<Judged>().get(note).map(|judged| judged.0), Some(Judgement::Perfect));
+        assert_eq!(world.read_resource::<InputTimeline>().0[0].audio_time, 2500);
+    }
+}
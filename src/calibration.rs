@@ -0,0 +1,135 @@
+use serde::{Deserialize, Serialize};
+
+use specs::prelude::*;
+
+#[derive(Debug)]
+#[derive(Default)]
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(PartialEq)]
+#[derive(Serialize, Deserialize)]
+pub struct InputLatency(pub i64);
+
+pub const GLOBAL_OFFSET_STEP: i64 = 1;
+pub const MAX_GLOBAL_OFFSET: i64 = 500;
+
+// A by-feel adjustment on top of InputLatency, nudged live with `[` and `]`. Presses are judged
+// against audio_time minus the offset, so a positive offset has notes expect their press later and
+// a negative one earlier. Playback itself is untouched.
+#[derive(Debug)]
+#[derive(Default)]
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(PartialEq)]
+#[derive(Serialize, Deserialize)]
+pub struct GlobalOffset(pub i64);
+
+impl GlobalOffset {
+    pub fn nudge(&mut self, delta: i64) {
+        self.0 = (self.0 + delta).clamp(-MAX_GLOBAL_OFFSET, MAX_GLOBAL_OFFSET);
+    }
+}
+
+pub struct LatencyCalibration {
+    pub enabled: bool,
+    pub samples: Vec<i64>,
+    pub min_samples: usize,
+    pub max_std_dev: f64,
+}
+
+impl Default for LatencyCalibration {
+    fn default() -> LatencyCalibration {
+        LatencyCalibration {
+            enabled: false,
+            samples: Vec::new(),
+            min_samples: 16,
+            max_std_dev: 15.0,
+        }
+    }
+}
+
+impl LatencyCalibration {
+    pub fn mean_and_std_dev(&self) -> Option<(f64, f64)> {
+        if self.samples.is_empty() {
+            return None;
+        }
+
+        let count = self.samples.len() as f64;
+        let mean = self.samples.iter().sum::<i64>() as f64 / count;
+        let variance = self.samples.iter()
+            .map(|&sample| (sample as f64 - mean).powi(2))
+            .sum::<f64>() / count;
+
+        Some((mean, variance.sqrt()))
+    }
+
+    // Returns the offset to add to the current latency once enough consistent samples have been
+    // collected. Inconsistent windows slide forward instead so one bad stretch doesn't block
+    // calibration forever.
+    pub fn try_commit(&mut self) -> Option<i64> {
+        if self.samples.len() < self.min_samples.max(1) {
+            return None;
+        }
+
+        let (mean, std_dev) = self.mean_and_std_dev()?;
+        if std_dev <= self.max_std_dev {
+            self.samples.clear();
+            Some(mean.round() as i64)
+        } else {
+            let excess = self.samples.len() + 1 - self.min_samples.max(1);
+            self.samples.drain(..excess);
+            None
+        }
+    }
+}
+
+pub(crate) struct LatencyCalibrationSystem;
+
+impl<'a> System<'a> for LatencyCalibrationSystem {
+    type SystemData = (Write<'a, LatencyCalibration>,
+                       Write<'a, InputLatency>);
+
+    fn run(&mut self, data: Self::SystemData) {
+        let (mut latency_calibration, mut input_latency) = data;
+
+        if !latency_calibration.enabled {
+            latency_calibration.samples.clear();
+            return;
+        }
+
+        if let Some(offset) = latency_calibration.try_commit() {
+            input_latency.0 += offset;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn calibration(samples: Vec<i64>) -> LatencyCalibration {
+        LatencyCalibration { enabled: true, samples, min_samples: 4, max_std_dev: 5.0 }
+    }
+
+    #[test]
+    fn consistent_samples_commit_their_mean() {
+        let mut latency_calibration = calibration(vec![20, 22, 18, 21]);
+        assert_eq!(latency_calibration.try_commit(), Some(20));
+        assert!(latency_calibration.samples.is_empty());
+    }
+
+    #[test]
+    fn scattered_samples_keep_the_previous_latency() {
+        let mut latency_calibration = calibration(vec![-40, 60, 0, 90]);
+        assert_eq!(latency_calibration.try_commit(), None);
+        // The oldest sample slides out so the next one can make a fresh window.
+        assert_eq!(latency_calibration.samples, vec![60, 0, 90]);
+    }
+
+    #[test]
+    fn too_few_samples_never_commit() {
+        let mut latency_calibration = calibration(vec![20, 20, 20]);
+        assert_eq!(latency_calibration.try_commit(), None);
+        assert_eq!(latency_calibration.samples.len(), 3);
+    }
+}
@@ -0,0 +1,758 @@
+use sdl2::keyboard::Keycode;
+
+use serde::{Deserialize, Serialize};
+
+use specs::prelude::*;
+
+use std::{
+    collections::HashMap,
+    fmt,
+    fs::File,
+    io::{self, BufRead, BufReader},
+    path::Path,
+};
+
+use crate::{
+    TargetInput,
+    lanes::{LaneCount, NoteColor},
+    render::Color,
+    rhythm::{AudioContext, ChordGroup, HitWindow, JudgementWindows, NoteIndex, RhythmCombo},
+};
+
+#[derive(Debug)]
+pub enum ChartError {
+    Io(io::Error),
+    Parse { line: usize, message: String },
+    MissingField(&'static str),
+    Json(serde_json::Error),
+    UnmappedKey(Keycode),
+    TooManyLanes { used: usize, count: usize },
+    InvertedWindows(ChartWindows),
+    UnsupportedOsuMode(u8),
+}
+
+impl From<io::Error> for ChartError {
+    fn from(err: io::Error) -> ChartError {
+        ChartError::Io(err)
+    }
+}
+
+impl From<serde_json::Error> for ChartError {
+    fn from(err: serde_json::Error) -> ChartError {
+        ChartError::Json(err)
+    }
+}
+
+impl fmt::Display for ChartError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ChartError::Io(err) => write!(f, "couldn't read chart: {}", err),
+            ChartError::Parse { line, message } => write!(f, "chart line {}: {}", line, message),
+            ChartError::MissingField(field) => write!(f, "chart is missing `{}`", field),
+            ChartError::Json(err) => write!(f, "bad chart json: {}", err),
+            ChartError::UnmappedKey(key) => write!(f, "chart uses {} but no lane is bound to it", key.name()),
+            ChartError::TooManyLanes { used, count } => write!(f, "chart uses {} lanes but only {} are set up", used, count),
+            ChartError::InvertedWindows(windows) => write!(
+                f,
+                "chart windows must widen from perfect to good, got perfect {} great {} good {}",
+                windows.perfect, windows.great, windows.good,
+            ),
+            ChartError::UnsupportedOsuMode(mode) => write!(f, "only osu!mania beatmaps (mode 3) can be imported, not mode {}", mode),
+        }
+    }
+}
+
+// Authoring mistakes a chart still loads with. Notes are numbered by their place in the chart.
+// Lanes without a key aren't among them, load_song refuses those charts outright, and note times
+// are unsigned so none can be negative.
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(PartialEq)]
+pub enum ChartWarning {
+    // A note after the audio ends, which can't be played.
+    PastSongEnd { note: usize, time: u64, song_length: u64 },
+    // Two notes in one lane closer together than the hit window, so presses for the second can be
+    // taken by the first.
+    TooClose { first: usize, second: usize, key: Keycode, gap: u64 },
+}
+
+impl fmt::Display for ChartWarning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ChartWarning::PastSongEnd { note, time, song_length } => {
+                write!(f, "note {} at {}ms is past the end of the {}ms song", note, time, song_length)
+            },
+            ChartWarning::TooClose { first, second, key, gap } => {
+                write!(f, "notes {} and {} in {} are only {}ms apart, inside the hit window", first, second, key.name(), gap)
+            },
+        }
+    }
+}
+
+// A chart's own judgement windows in ms, each the widest error its judgement takes. `good` is the
+// hit window too, on the late side as well unless `hit_window_late` says otherwise.
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(PartialEq)]
+#[derive(Serialize, Deserialize)]
+pub struct ChartWindows {
+    pub perfect: u64,
+    pub great: u64,
+    pub good: u64,
+}
+
+impl ChartWindows {
+    pub fn validate(&self) -> Result<(), ChartError> {
+        if self.perfect <= self.great && self.great <= self.good {
+            Ok(())
+        } else {
+            Err(ChartError::InvertedWindows(*self))
+        }
+    }
+}
+
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(PartialEq)]
+#[derive(Serialize, Deserialize)]
+pub struct ChartMetadata {
+    #[serde(default)]
+    pub title: Option<String>,
+    pub milli_bpm: u64,
+    pub first_beat_offset: u64,
+    pub beats_per_bar: u8,
+    pub audio_path: String,
+    #[serde(default)]
+    pub hit_window: Option<u64>,
+    // A late bound of its own, `hit_window` (or the global one) covering the early side alone.
+    #[serde(default)]
+    pub hit_window_late: Option<u64>,
+    // Replaces the global JudgementWindows, and `hit_window` with its `good`.
+    #[serde(default)]
+    pub windows: Option<ChartWindows>,
+    #[serde(default)]
+    pub auto_offset: bool,
+}
+
+// What from_osu snaps hit objects to, in divisions of a beat: fine enough for 16ths, triplets and
+// their halves, and within a couple of ms anywhere else at any usual BPM.
+pub const OSU_DIVISION: u64 = 192;
+
+// Notes are stored in the musical multiple/division/index form that `make_bar_time` takes so a
+// chart doesn't depend on the millisecond rounding at any particular BPM.
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(PartialEq)]
+#[derive(Serialize, Deserialize)]
+pub struct NoteData {
+    pub multiple: u64,
+    pub division: u64,
+    pub index: u64,
+    // Any of these hits the note, the first being its lane. Written `A+;`, a single key name on
+    // its own as before.
+    #[serde(rename = "key", with = "keycode_names")]
+    pub keys: Vec<Keycode>,
+    #[serde(default)]
+    pub chord: Option<u32>,
+    // Drawn instead of the lane color. Written as `#rrggbb` in JSON.
+    #[serde(default, with = "hex_color")]
+    pub color: Option<Color>,
+}
+
+mod hex_color {
+    use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
+
+    use crate::render::Color;
+
+    pub fn serialize<S: Serializer>(color: &Option<Color>, serializer: S) -> Result<S::Ok, S::Error> {
+        color.map(|color| color.to_hex()).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Color>, D::Error> {
+        match Option::<String>::deserialize(deserializer)? {
+            Some(hex) => Color::from_hex(&hex).map(Some).map_err(|err| D::Error::custom(format!("bad color `{}`: {}", hex, err))),
+            None => Ok(None),
+        }
+    }
+}
+
+mod keycode_names {
+    use sdl2::keyboard::Keycode;
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(keys: &[Keycode], serializer: S) -> Result<S::Ok, S::Error> {
+        let names: Vec<String> = keys.iter().map(|key| key.name()).collect();
+        serializer.serialize_str(&names.join("+"))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<Keycode>, D::Error> {
+        let names = String::deserialize(deserializer)?;
+        super::parse_keys(&names).ok_or_else(|| D::Error::custom(format!("unknown key `{}`", names)))
+    }
+}
+
+// `Left`, or `A+;` for a note either key hits, by SDL's key names. Some key names have a `+` of
+// their own, like `Keypad +`, so the whole name is tried before it's split.
+fn parse_keys(names: &str) -> Option<Vec<Keycode>> {
+    if let Some(key) = Keycode::from_name(names) {
+        return Some(vec![key]);
+    }
+    names.split('+').map(|name| Keycode::from_name(name.trim())).collect()
+}
+
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(PartialEq)]
+#[derive(Serialize, Deserialize)]
+pub struct Chart {
+    pub metadata: ChartMetadata,
+    pub notes: Vec<NoteData>,
+}
+
+fn parse_field<T: std::str::FromStr>(line: usize, name: &str, value: Option<&str>) -> Result<T, ChartError> {
+    value
+        .and_then(|value| value.parse().ok())
+        .ok_or_else(|| ChartError::Parse { line, message: format!("bad or missing {}", name) })
+}
+
+// The flat format is one `key value...` directive per line, `#` starts a comment:
+//
+//     title Top Fixed        (optional, the menu falls back to the file name)
+//     bpm 159850             (milli-bpm)
+//     offset 110             (first beat offset in ms, or `auto` to detect it from the audio)
+//     beats_per_bar 4
+//     audio top-fixed.ogg
+//     hit_window 100         (optional, overrides the global HitWindow)
+//     hit_window_late 80     (optional, a different bound for late presses)
+//     windows 25 50 100      (optional, perfect great good, overriding the global judgement
+//                             windows and hit_window, each no narrower than the last)
+//     note 4 3 2 Right       (multiple division index key, or keys like `A+;`)
+//     chord 1                (following notes form chord group 1 until the next chord directive)
+//     chord none             (following notes are single notes again)
+//     color ff00aa           (following notes are drawn in this color, hex without the `#` since
+//                             that starts a comment, until `color none`)
+impl Chart {
+    pub fn from_reader<R: BufRead>(reader: R) -> Result<Chart, ChartError> {
+        let mut title = None;
+        let mut milli_bpm = None;
+        let mut first_beat_offset = None;
+        let mut beats_per_bar = None;
+        let mut audio_path = None;
+        let mut hit_window = None;
+        let mut hit_window_late = None;
+        let mut windows = None;
+        let mut auto_offset = false;
+        let mut chord = None;
+        let mut color = None;
+        let mut notes = Vec::new();
+
+        for (index, line) in reader.lines().enumerate() {
+            let line_number = index + 1;
+            let line = line?;
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let mut fields = line.split_whitespace();
+            match fields.next() {
+                Some("title") => title = Some(fields.collect::<Vec<_>>().join(" ")),
+                Some("bpm") => {
+                    let value: u64 = parse_field(line_number, "bpm", fields.next())?;
+                    if value == 0 {
+                        return Err(ChartError::Parse { line: line_number, message: "bpm can't be 0".to_string() });
+                    }
+                    milli_bpm = Some(value);
+                },
+                Some("offset") => match fields.next() {
+                    Some("auto") => auto_offset = true,
+                    value => first_beat_offset = Some(parse_field(line_number, "offset", value)?),
+                },
+                Some("beats_per_bar") => {
+                    let value: u8 = parse_field(line_number, "beats_per_bar", fields.next())?;
+                    if value == 0 {
+                        return Err(ChartError::Parse { line: line_number, message: "beats_per_bar can't be 0".to_string() });
+                    }
+                    beats_per_bar = Some(value);
+                },
+                Some("audio") => audio_path = Some(fields.collect::<Vec<_>>().join(" ")),
+                Some("hit_window") => hit_window = Some(parse_field(line_number, "hit_window", fields.next())?),
+                Some("hit_window_late") => hit_window_late = Some(parse_field(line_number, "hit_window_late", fields.next())?),
+                Some("windows") => {
+                    let chart_windows = ChartWindows {
+                        perfect: parse_field(line_number, "perfect window", fields.next())?,
+                        great: parse_field(line_number, "great window", fields.next())?,
+                        good: parse_field(line_number, "good window", fields.next())?,
+                    };
+                    chart_windows.validate()?;
+                    windows = Some(chart_windows);
+                },
+                Some("note") => {
+                    let multiple = parse_field(line_number, "multiple", fields.next())?;
+                    let division: u64 = parse_field(line_number, "division", fields.next())?;
+                    let index = parse_field(line_number, "index", fields.next())?;
+                    let key_name = fields.collect::<Vec<_>>().join(" ");
+                    let keys = parse_keys(&key_name).ok_or_else(|| ChartError::Parse {
+                        line: line_number,
+                        message: format!("unknown key `{}`", key_name),
+                    })?;
+                    if division == 0 {
+                        return Err(ChartError::Parse { line: line_number, message: "division can't be 0".to_string() });
+                    }
+                    notes.push(NoteData { multiple, division, index, keys, chord, color });
+                },
+                Some("chord") => chord = match fields.next() {
+                    Some("none") => None,
+                    value => Some(parse_field(line_number, "chord", value)?),
+                },
+                Some("color") => color = match fields.next() {
+                    Some("none") => None,
+                    // `#` starts a comment here, so flat charts leave it off.
+                    Some(digits) => Some(Color::from_hex(&format!("#{}", digits)).map_err(|err| ChartError::Parse {
+                        line: line_number,
+                        message: format!("bad color `{}`: {}", digits, err),
+                    })?),
+                    None => return Err(ChartError::Parse { line: line_number, message: "missing color".to_string() }),
+                },
+                Some(directive) => {
+                    return Err(ChartError::Parse { line: line_number, message: format!("unknown directive `{}`", directive) });
+                },
+                None => {},
+            }
+        }
+
+        Ok(Chart {
+            metadata: ChartMetadata {
+                title,
+                milli_bpm: milli_bpm.ok_or(ChartError::MissingField("bpm"))?,
+                first_beat_offset: first_beat_offset.unwrap_or(0),
+                beats_per_bar: beats_per_bar.unwrap_or(4),
+                audio_path: audio_path.ok_or(ChartError::MissingField("audio"))?,
+                hit_window,
+                hit_window_late,
+                windows,
+                auto_offset,
+            },
+            notes,
+        })
+    }
+
+    // Reads `.json` charts as JSON, `.osu` beatmaps with from_osu and anything else as the flat
+    // format.
+    pub fn load(path: &Path) -> Result<Chart, ChartError> {
+        let reader = BufReader::new(File::open(path)?);
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("json") => Chart::from_json(reader),
+            Some("osu") => Chart::from_osu(reader),
+            _ => Chart::from_reader(reader),
+        }
+    }
+
+    // Imports an osu!mania beatmap. The BPM, meter and offset come from the first uninherited timing
+    // point, later ones are ignored, and each column becomes a lane of a LaneCount of the
+    // beatmap's CircleSize, which is its key count. Hit objects are snapped to 1/OSU_DIVISION of a
+    // beat. Hold notes come in as taps on their heads, charts have no holds of their own.
+    pub fn from_osu<R: BufRead>(reader: R) -> Result<Chart, ChartError> {
+        let mut section = String::new();
+        let mut title = None;
+        let mut audio_path = None;
+        let mut mode = 0;
+        let mut columns = 4;
+        let mut timing = None;
+        let mut hit_objects = Vec::new();
+
+        for (index, line) in reader.lines().enumerate() {
+            let line_number = index + 1;
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with("//") {
+                continue;
+            }
+            if line.starts_with('[') && line.ends_with(']') {
+                section = line[1..line.len() - 1].to_string();
+                continue;
+            }
+
+            let mut pair = line.splitn(2, ':').map(str::trim);
+            let (key, value) = (pair.next().unwrap_or(""), pair.next());
+            match (section.as_str(), key) {
+                ("General", "AudioFilename") => audio_path = value.map(str::to_string),
+                ("General", "Mode") => mode = parse_field(line_number, "Mode", value)?,
+                ("Metadata", "Title") => title = value.map(str::to_string),
+                ("Difficulty", "CircleSize") => {
+                    columns = parse_field::<f64>(line_number, "CircleSize", value)?.round().max(1.0) as u8;
+                },
+                ("TimingPoints", _) if timing.is_none() => {
+                    let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+                    let beat_length: f64 = parse_field(line_number, "beat length", fields.get(1).cloned())?;
+                    // Inherited points only change the scroll speed, older files mark them with a
+                    // negative beat length alone.
+                    let uninherited = fields.get(6).map_or(beat_length > 0.0, |&field| field == "1");
+                    if uninherited && beat_length > 0.0 {
+                        let time: f64 = parse_field(line_number, "timing point time", fields.first().cloned())?;
+                        let meter = match fields.get(2) {
+                            Some(&meter) => parse_field(line_number, "meter", Some(meter))?,
+                            None => 4,
+                        };
+                        if meter == 0 {
+                            return Err(ChartError::Parse { line: line_number, message: "meter can't be 0".to_string() });
+                        }
+                        timing = Some((line_number, time.max(0.0).round() as u64, beat_length, meter));
+                    }
+                },
+                ("HitObjects", _) => {
+                    let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+                    let x: f64 = parse_field(line_number, "x", fields.first().cloned())?;
+                    let time: f64 = parse_field(line_number, "time", fields.get(2).cloned())?;
+                    hit_objects.push((line_number, x, time));
+                },
+                _ => {},
+            }
+        }
+
+        if mode != 3 {
+            return Err(ChartError::UnsupportedOsuMode(mode));
+        }
+        let (timing_line, first_beat_offset, beat_length, beats_per_bar) = timing.ok_or(ChartError::MissingField("TimingPoints"))?;
+        // A beat length too long rounds to 0 BPM and a too short one, or an infinite one, would
+        // saturate the cast, so both are refused rather than imported as nonsense.
+        let milli_bpm = (60_000_000.0 / beat_length).round();
+        if !(1.0..=u32::MAX as f64).contains(&milli_bpm) {
+            return Err(ChartError::Parse { line: timing_line, message: format!("unusable beat length {}", beat_length) });
+        }
+        let milli_bpm = milli_bpm as u64;
+        let lane_keys = LaneCount::new(columns).lane_keys();
+
+        let mut notes = Vec::new();
+        for (line, x, time) in hit_objects {
+            let since_offset = time - first_beat_offset as f64;
+            if since_offset < 0.0 {
+                return Err(ChartError::Parse { line, message: "hit object before the first timing point".to_string() });
+            }
+            let column = ((x * lane_keys.len() as f64 / 512.0).floor().max(0.0) as usize).min(lane_keys.len() - 1);
+            notes.push(NoteData {
+                multiple: 1,
+                division: OSU_DIVISION,
+                index: (since_offset * milli_bpm as f64 * OSU_DIVISION as f64 / 60_000_000.0).round() as u64,
+                keys: vec![lane_keys[column]],
+                chord: None,
+                color: None,
+            });
+        }
+
+        Ok(Chart {
+            metadata: ChartMetadata {
+                title,
+                milli_bpm,
+                first_beat_offset,
+                beats_per_bar,
+                audio_path: audio_path.ok_or(ChartError::MissingField("AudioFilename"))?,
+                hit_window: None,
+                hit_window_late: None,
+                windows: None,
+                auto_offset: false,
+            },
+            notes,
+        })
+    }
+
+    pub fn from_json<R: io::Read>(reader: R) -> Result<Chart, ChartError> {
+        let chart: Chart = serde_json::from_reader(reader)?;
+        if let Some(note) = chart.notes.iter().find(|note| note.division == 0) {
+            return Err(ChartError::Parse { line: 0, message: format!("division can't be 0 in {:?}", note) });
+        }
+        if chart.metadata.milli_bpm == 0 {
+            return Err(ChartError::Parse { line: 0, message: "bpm can't be 0".to_string() });
+        }
+        if chart.metadata.beats_per_bar == 0 {
+            return Err(ChartError::Parse { line: 0, message: "beats_per_bar can't be 0".to_string() });
+        }
+        if let Some(windows) = chart.metadata.windows {
+            windows.validate()?;
+        }
+        Ok(chart)
+    }
+
+    // Only the round-trip tests write charts out so far.
+    #[cfg(test)]
+    pub fn to_json<W: io::Write>(&self, writer: W) -> Result<(), ChartError> {
+        serde_json::to_writer_pretty(writer, self)?;
+        Ok(())
+    }
+
+    // Snaps every note to the nearest 1/`division` of a beat, so 4 snaps to 16ths in 4/4. Notes
+    // already on that grid are left exactly as written, which also makes it idempotent. Works on
+    // the musical form rather than milliseconds, so the snap doesn't depend on the BPM.
+    pub fn quantize(&mut self, division: u64) {
+        if division == 0 {
+            return;
+        }
+        for note in &mut self.notes {
+            let scaled = note.index * note.multiple * division;
+            if scaled.is_multiple_of(note.division) {
+                continue;
+            }
+            let snapped = (scaled + note.division / 2) / note.division;
+            note.multiple = 1;
+            note.division = division;
+            note.index = snapped;
+        }
+    }
+
+    pub fn audio_context(&self) -> AudioContext {
+        AudioContext::new(self.metadata.milli_bpm, self.metadata.first_beat_offset, self.metadata.beats_per_bar)
+    }
+
+    pub fn hit_window(&self, default: HitWindow) -> HitWindow {
+        let window = self.metadata.windows.map(|windows| windows.good)
+            .or(self.metadata.hit_window)
+            .map(HitWindow::symmetric)
+            .unwrap_or(default);
+        HitWindow { late: self.metadata.hit_window_late.unwrap_or(window.late), ..window }
+    }
+
+    // Looks for the mistakes ChartWarning lists. `song_length` is the audio's in ms, 0 when it's not
+    // known, which skips that check. Notes are too close when they're closer together than the
+    // chart's hit window, or the default one.
+    pub fn validate(&self, ctx: &AudioContext, song_length: u64) -> Vec<ChartWarning> {
+        let mut warnings = Vec::new();
+        let mut lanes: HashMap<Keycode, Vec<(u64, usize)>> = HashMap::new();
+        for (index, note) in self.notes.iter().enumerate() {
+            let time = ctx.make_bar_time(note.multiple, note.division, note.index).0;
+            if song_length > 0 && time + ctx.first_beat_offset > song_length {
+                warnings.push(ChartWarning::PastSongEnd { note: index, time, song_length });
+            }
+            // A note playable from several keys is in each of their lanes.
+            for &key in &note.keys {
+                lanes.entry(key).or_default().push((time, index));
+            }
+        }
+
+        let window = self.hit_window(HitWindow::default()).widest();
+        for (&key, notes) in &mut lanes {
+            notes.sort();
+            for pair in notes.windows(2) {
+                let ((first_time, first), (second_time, second)) = (pair[0], pair[1]);
+                let gap = second_time - first_time;
+                if gap < window {
+                    warnings.push(ChartWarning::TooClose { first, second, key, gap });
+                }
+            }
+        }
+        // Lanes come out of the map in no particular order, so a pair of notes sharing two lanes is
+        // sorted by key as well.
+        warnings.sort_by_key(|warning| match *warning {
+            ChartWarning::PastSongEnd { note, .. } => (note, 0, 0),
+            ChartWarning::TooClose { first, second, key, .. } => (first, second, key as i32),
+        });
+        warnings
+    }
+
+    pub fn judgement_windows(&self, default: JudgementWindows) -> JudgementWindows {
+        self.metadata.windows.map_or(default, |windows| JudgementWindows { perfect: windows.perfect, great: windows.great, good: windows.good })
+    }
+
+    pub fn spawn_notes(&self, world: &mut World) {
+        let audio_context = self.audio_context();
+        for (index, note) in self.notes.iter().enumerate() {
+            let mut note_entity = world.create_entity()
+                .with(NoteIndex(index))
+                .with(audio_context.make_bar_time(note.multiple, note.division, note.index))
+                .with(TargetInput(note.keys.clone()))
+                .with(RhythmCombo);
+            if let Some(group) = note.chord {
+                note_entity = note_entity.with(ChordGroup(group));
+            }
+            if let Some(color) = note.color {
+                note_entity = note_entity.with(NoteColor(color));
+            }
+            note_entity.build();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> Chart {
+        Chart::from_reader(text.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn zero_bpm_and_beats_per_bar_are_rejected() {
+        for text in &["bpm 0\naudio song.ogg\n", "bpm 120000\nbeats_per_bar 0\naudio song.ogg\n"] {
+            match Chart::from_reader(text.as_bytes()) {
+                Err(ChartError::Parse { .. }) => {},
+                other => panic!("expected a parse error for {:?}, got {:?}", text, other),
+            }
+        }
+
+        let mut chart = parse("bpm 120000\naudio song.ogg\n");
+        chart.metadata.milli_bpm = 0;
+        let mut json = Vec::new();
+        chart.to_json(&mut json).unwrap();
+        assert!(Chart::from_json(&json[..]).is_err());
+
+        let mut chart = parse("bpm 120000\naudio song.ogg\n");
+        chart.metadata.beats_per_bar = 0;
+        let mut json = Vec::new();
+        chart.to_json(&mut json).unwrap();
+        assert!(Chart::from_json(&json[..]).is_err());
+    }
+
+    #[test]
+    fn chart_windows_override_the_defaults() {
+        let chart = parse("bpm 120000\naudio song.ogg\nwindows 20 40 80\nhit_window_late 60\n");
+        assert_eq!(chart.hit_window(HitWindow::default()), HitWindow { early: 80, late: 60 });
+        assert_eq!(
+            chart.judgement_windows(JudgementWindows::default()),
+            JudgementWindows { perfect: 20, great: 40, good: 80 },
+        );
+    }
+
+    #[test]
+    fn chart_hit_window_alone_keeps_the_default_judgement_windows() {
+        let chart = parse("bpm 120000\naudio song.ogg\nhit_window 70\n");
+        assert_eq!(chart.hit_window(HitWindow::default()), HitWindow::symmetric(70));
+        assert_eq!(chart.judgement_windows(JudgementWindows::default()), JudgementWindows::default());
+    }
+
+    #[test]
+    fn chart_without_windows_uses_the_defaults() {
+        let chart = parse("bpm 120000\naudio song.ogg\n");
+        assert_eq!(chart.hit_window(HitWindow::symmetric(90)), HitWindow::symmetric(90));
+        assert_eq!(chart.judgement_windows(JudgementWindows::default()), JudgementWindows::default());
+    }
+
+    #[test]
+    fn osu_sample_imports_as_four_lanes() {
+        let chart = Chart::from_osu(include_str!("../charts/sample-mania.osu").as_bytes()).unwrap();
+        assert_eq!(chart.metadata.title.as_deref(), Some("Top Fixed (osu!mania sample)"));
+        assert_eq!(chart.metadata.audio_path, "top-fixed.ogg");
+        assert_eq!(chart.metadata.milli_bpm, 159850);
+        assert_eq!(chart.metadata.first_beat_offset, 110);
+        assert_eq!(chart.metadata.beats_per_bar, 4);
+
+        let notes: Vec<(u64, Keycode)> = chart.notes.iter().take(4).map(|note| (note.index, note.keys[0])).collect();
+        assert_eq!(notes, vec![(0, Keycode::Left), (384, Keycode::Right), (576, Keycode::Down), (768, Keycode::Up)]);
+        assert!(chart.notes.iter().all(|note| note.multiple == 1 && note.division == OSU_DIVISION));
+    }
+
+    #[test]
+    fn osu_beat_lengths_that_make_no_bpm_are_rejected() {
+        let osu = |beat_length: &str| format!(
+            "[General]\nAudioFilename: song.ogg\nMode: 3\n[TimingPoints]\n0,{},4,2,0,60,1,0\n",
+            beat_length,
+        );
+        assert!(Chart::from_osu(osu("500").as_bytes()).is_ok());
+        for beat_length in &["1e300", "inf", "1e-300"] {
+            match Chart::from_osu(osu(beat_length).as_bytes()) {
+                Err(ChartError::Parse { line: 5, .. }) => {},
+                other => panic!("expected beat length {} to be refused, got {:?}", beat_length, other),
+            }
+        }
+        // NaN and negative lengths aren't uninherited timing points at all.
+        for beat_length in &["NaN", "-500"] {
+            match Chart::from_osu(osu(beat_length).as_bytes()) {
+                Err(ChartError::MissingField("TimingPoints")) => {},
+                other => panic!("expected beat length {} to be skipped, got {:?}", beat_length, other),
+            }
+        }
+    }
+
+    #[test]
+    fn quantize_snaps_to_the_nearest_sixteenth() {
+        // A thousandth of a beat at 120 BPM is half a ms, so 206 of them is 103ms.
+        let mut chart = parse("bpm 120000\naudio song.ogg\nnote 1 1000 206 Left\nnote 2 8 1 Down\n");
+        let ctx = chart.audio_context();
+        assert_eq!(ctx.make_bar_time(1, 1000, 206).0, 103);
+
+        chart.quantize(4);
+        let times: Vec<u64> = chart.notes.iter().map(|note| ctx.make_bar_time(note.multiple, note.division, note.index).0).collect();
+        assert_eq!(times, vec![125, 125]);
+        // The one already on the grid is left as it was written.
+        assert_eq!((chart.notes[1].multiple, chart.notes[1].division, chart.notes[1].index), (2, 8, 1));
+
+        let quantized = chart.clone();
+        chart.quantize(4);
+        assert_eq!(chart, quantized);
+    }
+
+    #[test]
+    fn note_colors_run_until_color_none() {
+        let chart = parse("bpm 120000\naudio song.ogg\ncolor ff00aa\nnote 1 1 0 D\ncolor F80\nnote 1 1 1 D\ncolor none\nnote 1 1 2 D\n");
+        let colors: Vec<Option<Color>> = chart.notes.iter().map(|note| note.color).collect();
+        assert_eq!(colors, vec![Some(Color::rgb(0xff, 0x00, 0xaa)), Some(Color::rgb(0xff, 0x88, 0x00)), None]);
+    }
+
+    #[test]
+    fn bad_note_colors_are_rejected_on_their_line() {
+        for color in &["color ff00a", "color gg00aa", "color #ff00aa", "color"] {
+            let text = format!("bpm 120000\naudio song.ogg\n{}\nnote 1 1 0 D\n", color);
+            match Chart::from_reader(text.as_bytes()) {
+                Err(ChartError::Parse { line: 3, .. }) => {},
+                other => panic!("expected `{}` to be refused, got {:?}", color, other),
+            }
+        }
+    }
+
+    #[test]
+    fn notes_past_the_song_end_are_warned_about() {
+        let chart = parse("bpm 120000\noffset 100\naudio song.ogg\nnote 1 1 0 D\nnote 1 1 3 F\n");
+        let ctx = chart.audio_context();
+        // The second note is 1500ms after the 100ms offset.
+        assert_eq!(chart.validate(&ctx, 1600), vec![]);
+        assert_eq!(chart.validate(&ctx, 1599), vec![ChartWarning::PastSongEnd { note: 1, time: 1500, song_length: 1599 }]);
+        // A song of unknown length isn't checked.
+        assert_eq!(chart.validate(&ctx, 0), vec![]);
+    }
+
+    #[test]
+    fn notes_closer_than_the_hit_window_are_warned_about() {
+        // 80ms apart in D with a 100ms window, 125ms in F and 80ms again across lanes.
+        let chart = parse("bpm 120000\naudio song.ogg\nnote 1 100 20 D\nnote 1 100 36 D\nnote 1 4 4 F\nnote 1 4 5 F\nnote 1 100 52 J\n");
+        let ctx = chart.audio_context();
+        assert_eq!(chart.validate(&ctx, 0), vec![ChartWarning::TooClose { first: 0, second: 1, key: Keycode::D, gap: 80 }]);
+
+        // The chart's own window decides.
+        let narrow = parse("bpm 120000\naudio song.ogg\nhit_window 50\nnote 1 100 20 D\nnote 1 100 36 D\n");
+        assert_eq!(narrow.validate(&ctx, 0), vec![]);
+    }
+
+    #[test]
+    fn notes_a_bar_apart_in_time_are_not_too_close() {
+        // Each note is played once, so the last one, 60ms short of the first plus a 2000ms bar, is
+        // nowhere near it.
+        let chart = parse("bpm 120000\naudio song.ogg\nnote 1 100 4 D\nnote 1 1 2 D\nnote 1 100 392 D\n");
+        let ctx = chart.audio_context();
+        assert_eq!(chart.validate(&ctx, 0), vec![]);
+    }
+
+    #[test]
+    fn notes_are_too_close_in_any_lane_they_share() {
+        // The second note's only lane is the first note's other key.
+        let chart = parse("bpm 120000\naudio song.ogg\nnote 1 100 20 D+F\nnote 1 100 36 F\n");
+        let ctx = chart.audio_context();
+        assert_eq!(chart.validate(&ctx, 0), vec![ChartWarning::TooClose { first: 0, second: 1, key: Keycode::F, gap: 80 }]);
+    }
+
+    #[test]
+    fn json_round_trip_is_lossless() {
+        let chart = parse(concat!(
+            "title Round Trip\nbpm 159850\noffset 110\nbeats_per_bar 3\naudio song.ogg\nwindows 20 40 80\n",
+            "note 4 3 2 Right\nchord 1\nnote 1 4 1 A+;\nnote 1 4 1 Left\nchord none\n",
+            "color ff00aa80\nnote 1 2 1 Up\n",
+        ));
+        let mut json = Vec::new();
+        chart.to_json(&mut json).unwrap();
+        let reloaded = Chart::from_json(&json[..]).unwrap();
+        assert_eq!(reloaded, chart);
+
+        let mut resaved = Vec::new();
+        reloaded.to_json(&mut resaved).unwrap();
+        assert_eq!(resaved, json);
+    }
+}
@@ -0,0 +1,144 @@
+use specs::prelude::*;
+
+use crate::results::Results;
+
+pub const COMBO_MILESTONE: u32 = 50;
+
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(PartialEq)]
+pub enum ComboEvent {
+    // The combo reached this multiple of COMBO_MILESTONE.
+    Milestone(u32),
+    // A combo of at least one was reset.
+    Broken,
+    // The song finished with every note hit.
+    FullCombo,
+}
+
+// Moves the combo on by one judgement or hold tick, a hit adding to it and anything else
+// breaking it, and pushes the events that makes.
+pub fn count_combo(combo: &mut u32, hit: bool, events: &mut Vec<ComboEvent>) {
+    if hit {
+        *combo += 1;
+        if combo.is_multiple_of(COMBO_MILESTONE) {
+            events.push(ComboEvent::Milestone(*combo));
+        }
+    } else if *combo > 0 {
+        *combo = 0;
+        events.push(ComboEvent::Broken);
+    }
+}
+
+// ScoringSystem pushes to `pending` as it changes the combo, over however many update steps a
+// frame runs, and ComboEventSystem moves them to `events` once a frame, so any number of systems
+// can read them as long as they run after it. A combo reset by starting a song or seeking isn't
+// an event.
+#[derive(Default)]
+pub struct ComboEvents {
+    pub pending: Vec<ComboEvent>,
+    pub events: Vec<ComboEvent>,
+}
+
+// Hands the frame its combo events, adding FullCombo once the results of a song without a miss
+// are in.
+#[derive(Default)]
+pub(crate) struct ComboEventSystem {
+    full_combo_checked: bool,
+}
+
+impl<'a> System<'a> for ComboEventSystem {
+    type SystemData = (Read<'a, Results>,
+                       Write<'a, ComboEvents>);
+
+    fn run(&mut self, data: Self::SystemData) {
+        let (results, mut combo_events) = data;
+
+        let ComboEvents { pending, events } = &mut *combo_events;
+        events.clear();
+        events.append(pending);
+
+        // Results go back to incomplete when a playlist starts its next song.
+        if !results.complete {
+            self.full_combo_checked = false;
+        } else if !self.full_combo_checked {
+            self.full_combo_checked = true;
+            if results.total_notes > 0 && results.miss == 0 {
+                events.push(ComboEvent::FullCombo);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use specs::RunNow;
+
+    use crate::{
+        rhythm::{Combo, ComboBreaks, HoldTickCount, Judgement, JudgementHistory},
+        score::{Score, ScoreWeights, ScoringSystem},
+    };
+
+    fn combo_world() -> World {
+        let mut world = World::new();
+        world.add_resource(ScoreWeights::default());
+        world.add_resource(JudgementHistory::default());
+        world.add_resource(ComboBreaks::default());
+        world.add_resource(HoldTickCount::default());
+        world.add_resource(Score::default());
+        world.add_resource(Combo::default());
+        world.add_resource(ComboEvents::default());
+        world.add_resource(Results::default());
+        world
+    }
+
+    fn frame_events(world: &World, judgements: &[Judgement]) -> Vec<ComboEvent> {
+        world.write_resource::<JudgementHistory>().0.extend_from_slice(judgements);
+        ScoringSystem::default().run_now(&world.res);
+        ComboEventSystem::default().run_now(&world.res);
+        world.read_resource::<ComboEvents>().events.clone()
+    }
+
+    #[test]
+    fn break_and_rebuild_within_a_frame_still_breaks() {
+        let world = combo_world();
+        let mut judgements = vec![Judgement::Perfect; 10];
+        judgements.push(Judgement::Miss);
+        judgements.extend(vec![Judgement::Perfect; 12]);
+        assert_eq!(frame_events(&world, &judgements), vec![ComboEvent::Broken]);
+        assert_eq!(world.read_resource::<Combo>().0, 12);
+    }
+
+    #[test]
+    fn each_milestone_is_reported_once() {
+        let world = combo_world();
+        let mut scoring_system = ScoringSystem::default();
+        let mut combo_event_system = ComboEventSystem::default();
+        let mut milestones = 0;
+        for _ in 0..(COMBO_MILESTONE * 3 + 7) {
+            world.write_resource::<JudgementHistory>().0.push(Judgement::Great);
+            scoring_system.run_now(&world.res);
+            combo_event_system.run_now(&world.res);
+            milestones += world.read_resource::<ComboEvents>().events.iter()
+                .filter(|event| matches!(event, ComboEvent::Milestone(_)))
+                .count();
+        }
+        assert_eq!(milestones, 3);
+
+        // Several in one frame are each reported.
+        let world = combo_world();
+        let events = frame_events(&world, &vec![Judgement::Perfect; COMBO_MILESTONE as usize * 2]);
+        assert_eq!(events, vec![ComboEvent::Milestone(COMBO_MILESTONE), ComboEvent::Milestone(COMBO_MILESTONE * 2)]);
+    }
+
+    #[test]
+    fn resetting_the_combo_outside_play_is_no_break() {
+        let world = combo_world();
+        frame_events(&world, &[Judgement::Perfect; 5]);
+        *world.write_resource::<Combo>() = Combo::default();
+        assert_eq!(frame_events(&world, &[]), vec![]);
+    }
+}
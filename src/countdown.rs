@@ -0,0 +1,59 @@
+use specs::prelude::*;
+
+use crate::{
+    render::{Color, Position, Text, LOGICAL_WIDTH},
+    theme::Theme,
+};
+
+// Milliseconds of lead-in left before the song starts. The audio clock counts it down and only
+// starts the sink once it reaches 0; until then audio_time stays at 0 while SongTime counts up to
+// it from below, so presses through the lead-in are still judged.
+#[derive(Default)]
+pub struct LeadIn(pub u64);
+
+pub const DEFAULT_LEAD_IN_MILLIS: u64 = 3000;
+
+// Shows the whole seconds left of the lead-in, the text entity only existing while it runs.
+#[derive(Default)]
+pub struct CountdownSystem {
+    line: Option<Entity>,
+}
+
+impl<'a> System<'a> for CountdownSystem {
+    type SystemData = (Entities<'a>,
+                       Read<'a, LeadIn>,
+                       Read<'a, Theme>,
+                       WriteStorage<'a, Text>,
+                       WriteStorage<'a, Position>,
+                       WriteStorage<'a, Color>);
+
+    fn run(&mut self, data: Self::SystemData) {
+        let (entities, lead_in, theme, mut text_storage, mut position_storage, mut color_storage) = data;
+
+        if lead_in.0 == 0 {
+            if let Some(entity) = self.line.take() {
+                if let Err(err) = entities.delete(entity) {
+                    log::warn!("Couldn't remove countdown: {:?}", err);
+                }
+            }
+            return;
+        }
+
+        let entity = match self.line {
+            Some(entity) => entity,
+            None => {
+                let entity = entities.build_entity()
+                    .with(Text(String::new()), &mut text_storage)
+                    .with(Position { x: LOGICAL_WIDTH / 2.0, y: 40.0 }, &mut position_storage)
+                    .with(theme.text, &mut color_storage)
+                    .build();
+                self.line = Some(entity);
+                entity
+            },
+        };
+
+        if let Some(text) = text_storage.get_mut(entity) {
+            text.0 = lead_in.0.div_ceil(1000).to_string();
+        }
+    }
+}
@@ -0,0 +1,114 @@
+use specs::prelude::*;
+
+use crate::{
+    AudioTime,
+    DebugFlag,
+    calibration::GlobalOffset,
+    draw::DrawStats,
+    render::{Color, Position, Text},
+    rhythm::{musical_position, AudioContext, ChordDiagnostics, Combo, InputTimeline, JudgementWindows, LastHitError},
+    score::Score,
+    sdl::InputEvents,
+    stats::Nps,
+};
+
+// How many of the latest judged inputs the overlay lists.
+const RECENT_INPUTS: usize = 4;
+
+// The overlay's text entities only exist while the debug flag is on, so nothing is pushed to the
+// render list when it's off.
+#[derive(Default)]
+pub(crate) struct DebugOverlaySystem {
+    lines: Vec<Entity>,
+}
+
+impl<'a> System<'a> for DebugOverlaySystem {
+    type SystemData = (Entities<'a>,
+                       Read<'a, DebugFlag>,
+                       Read<'a, AudioTime>,
+                       Read<'a, AudioContext>,
+                       Read<'a, Combo>,
+                       Read<'a, Score>,
+                       Read<'a, LastHitError>,
+                       Read<'a, InputEvents>,
+                       Read<'a, ChordDiagnostics>,
+                       Read<'a, DrawStats>,
+                       Read<'a, Nps>,
+                       Read<'a, GlobalOffset>,
+                       Read<'a, JudgementWindows>,
+                       Read<'a, InputTimeline>,
+                       WriteStorage<'a, Text>,
+                       WriteStorage<'a, Position>,
+                       WriteStorage<'a, Color>);
+
+    fn run(&mut self, data: Self::SystemData) {
+        let (
+            entities,
+            debug_flag,
+            audio_time,
+            audio_context,
+            combo,
+            score,
+            last_hit_error,
+            input_events,
+            chord_diagnostics,
+            draw_stats,
+            nps,
+            global_offset,
+            judgement_windows,
+            input_timeline,
+            mut text_storage,
+            mut position_storage,
+            mut color_storage,
+        ) = data;
+
+        if !debug_flag.0 {
+            for entity in self.lines.drain(..) {
+                if let Err(err) = entities.delete(entity) {
+                    log::warn!("Couldn't remove debug overlay line: {:?}", err);
+                }
+            }
+            return;
+        }
+
+        // Counted from 1 on screen, the way bars and beats are read off a score.
+        let (bar, beat) = musical_position(audio_time.0, &audio_context);
+        let lines = vec![
+            format!("audio_time {}", audio_time.0),
+            format!("bar {}, beat {}", bar + 1, beat + 1),
+            format!("bpm {:.3}", audio_context.milli_bpm as f64 / 1000.0),
+            format!("combo {}", combo.0),
+            format!("score {}", score.0),
+            format!("nps {:.1}", nps.0),
+            format!("offset {:+}ms", global_offset.0),
+            format!("windows {}/{}/{}ms", judgement_windows.perfect, judgement_windows.great, judgement_windows.good),
+            match last_hit_error.0 {
+                Some(error) => format!("last hit {:+}ms", error),
+                None => "last hit -".to_string(),
+            },
+            format!("recent {}", input_timeline.recent(RECENT_INPUTS).iter()
+                .map(|entry| format!("{} {:?}", entry.lane.name(), entry.judgement))
+                .collect::<Vec<_>>()
+                .join(", ")),
+            format!("dropped inputs {}", input_events.dropped),
+            format!("chords missed {}, ghosting? {}", chord_diagnostics.missed, chord_diagnostics.possible_ghosting),
+            format!("rects {} in {} fills", draw_stats.rects, draw_stats.batches),
+        ];
+
+        while self.lines.len() < lines.len() {
+            let y = 10.0 + self.lines.len() as f64 * 26.0;
+            let entity = entities.build_entity()
+                .with(Text(String::new()), &mut text_storage)
+                .with(Position { x: 10.0, y }, &mut position_storage)
+                .with(Color::rgb(255, 0, 0), &mut color_storage)
+                .build();
+            self.lines.push(entity);
+        }
+
+        for (entity, line) in self.lines.iter().zip(lines) {
+            if let Some(text) = text_storage.get_mut(*entity) {
+                text.0 = line;
+            }
+        }
+    }
+}
@@ -0,0 +1,529 @@
+use specs::prelude::*;
+
+use std::path::{Path, PathBuf};
+
+use crate::{
+    effects::BeatPulse,
+    render::{
+        ApproachRing,
+        Background,
+        Circle,
+        ClearColor,
+        Color,
+        Layer,
+        Position,
+        NOTE_LAYER,
+        Rectangle,
+        Scale,
+        Text,
+        Viewport,
+        GRADIENT_BANDS,
+        LOGICAL_HEIGHT,
+        LOGICAL_WIDTH,
+    },
+    state::{GameState, Scene},
+    theme::Theme,
+};
+
+// A rect in physical pixels, `x` and `y` its top left corner.
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(PartialEq)]
+pub struct DrawRect {
+    pub x: i32,
+    pub y: i32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl DrawRect {
+    pub fn from_center(center: (i32, i32), width: u32, height: u32) -> DrawRect {
+        DrawRect { x: center.0 - width as i32 / 2, y: center.1 - height as i32 / 2, width, height }
+    }
+}
+
+// Whatever the frame ends up drawn on. Everything is in physical pixels, laid out and in draw
+// order already, so an implementation only has to put it on screen in the order it's called.
+pub trait Renderer {
+    fn clear(&mut self, color: Color);
+    fn fill_rect(&mut self, color: Color, rect: DrawRect) -> Result<(), String>;
+    fn fill_circle(&mut self, color: Color, center: (i16, i16), radius: i16) -> Result<(), String>;
+    fn stroke_circle(&mut self, color: Color, center: (i16, i16), radius: i16) -> Result<(), String>;
+    fn draw_text(&mut self, color: Color, position: (i32, i32), scale: f64, text: &str) -> Result<(), String>;
+    // Stretched to fill `rect`. The image is the renderer's to load and keep between frames.
+    fn draw_image(&mut self, path: &Path, rect: DrawRect) -> Result<(), String>;
+    fn present(&mut self);
+
+    // Renderers that can fill many rects in one call override this.
+    fn fill_rects(&mut self, color: Color, rects: &[DrawRect]) -> Result<(), String> {
+        rects.iter().try_for_each(|&rect| self.fill_rect(color, rect))
+    }
+}
+
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(PartialEq)]
+pub enum DrawCommand {
+    Clear(Color),
+    FillRect(Color, DrawRect),
+    // Runs of consecutive same-colored rects in draw order. Only neighbours are merged, nothing
+    // is reordered, so the output is the same as filling them one by one.
+    FillRects(Color, Vec<DrawRect>),
+    FillCircle(Color, (i16, i16), i16),
+    StrokeCircle(Color, (i16, i16), i16),
+    Text(Color, (i32, i32), f64, String),
+    Image(PathBuf, DrawRect),
+    Present,
+}
+
+impl DrawCommand {
+    // One line in the snapshot form: colors as hex, positions and sizes in whole pixels and text
+    // scale to two decimals, so the same frame reads the same on every platform.
+    pub fn snapshot_line(&self) -> String {
+        let rect = |rect: &DrawRect| format!("{},{} {}x{}", rect.x, rect.y, rect.width, rect.height);
+        match self {
+            DrawCommand::Clear(color) => format!("clear {}", color.to_hex()),
+            DrawCommand::FillRect(color, draw_rect) => format!("fill_rect {} {}", color.to_hex(), rect(draw_rect)),
+            DrawCommand::FillRects(color, rects) => format!(
+                "fill_rects {} {}",
+                color.to_hex(),
+                rects.iter().map(rect).collect::<Vec<_>>().join("; "),
+            ),
+            DrawCommand::FillCircle(color, (x, y), radius) => format!("fill_circle {} {},{} r{}", color.to_hex(), x, y, radius),
+            DrawCommand::StrokeCircle(color, (x, y), radius) => format!("stroke_circle {} {},{} r{}", color.to_hex(), x, y, radius),
+            DrawCommand::Text(color, (x, y), scale, text) => format!("text {} {},{} x{:.2} {:?}", color.to_hex(), x, y, scale, text),
+            DrawCommand::Image(path, draw_rect) => format!("image {:?} {}", path.display().to_string(), rect(draw_rect)),
+            DrawCommand::Present => "present".to_string(),
+        }
+    }
+
+    pub fn draw<R: Renderer>(&self, renderer: &mut R) -> Result<(), String> {
+        match self {
+            DrawCommand::Clear(color) => renderer.clear(*color),
+            DrawCommand::FillRect(color, rect) => renderer.fill_rect(*color, *rect)?,
+            DrawCommand::FillRects(color, rects) => renderer.fill_rects(*color, rects)?,
+            DrawCommand::FillCircle(color, center, radius) => renderer.fill_circle(*color, *center, *radius)?,
+            DrawCommand::StrokeCircle(color, center, radius) => renderer.stroke_circle(*color, *center, *radius)?,
+            DrawCommand::Text(color, position, scale, text) => renderer.draw_text(*color, *position, *scale, text)?,
+            DrawCommand::Image(path, rect) => renderer.draw_image(path, *rect)?,
+            DrawCommand::Present => renderer.present(),
+        }
+        Ok(())
+    }
+}
+
+// One frame's drawing, built by RenderingSystem and played onto a Renderer by SdlSystem. It stops
+// short of presenting, which is up to whoever draws it.
+#[derive(Default)]
+pub struct DrawList(pub Vec<DrawCommand>);
+
+impl DrawList {
+    // A command that fails is logged and skipped, the rest of the frame still gets drawn.
+    pub fn draw<R: Renderer>(&self, renderer: &mut R) {
+        for command in &self.0 {
+            if let Err(e) = command.draw(renderer) { log::error!("{}", e); }
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct DrawStats {
+    pub rects: usize,
+    pub batches: usize,
+}
+
+// Draws nothing, just keeps every call as the command that would repeat it, for checking what a
+// frame draws without a window.
+#[derive(Default)]
+pub struct RecordingRenderer(pub Vec<DrawCommand>);
+
+impl RecordingRenderer {
+    pub fn snapshot(&self) -> String {
+        self.0.iter().map(|command| command.snapshot_line() + "\n").collect()
+    }
+}
+
+// Lays out the world as it stands and returns the frame in snapshot form, one command a line,
+// for comparing against a known good layout. Only RenderingSystem runs, so whatever else a frame
+// would do to the world should have happened already.
+pub fn snapshot_frame(world: &World) -> String {
+    RenderingSystem::default().run_now(&world.res);
+    let mut renderer = RecordingRenderer::default();
+    world.read_resource::<DrawList>().draw(&mut renderer);
+    renderer.present();
+    renderer.snapshot()
+}
+
+impl Renderer for RecordingRenderer {
+    fn clear(&mut self, color: Color) {
+        self.0.push(DrawCommand::Clear(color));
+    }
+
+    fn fill_rect(&mut self, color: Color, rect: DrawRect) -> Result<(), String> {
+        self.0.push(DrawCommand::FillRect(color, rect));
+        Ok(())
+    }
+
+    fn fill_rects(&mut self, color: Color, rects: &[DrawRect]) -> Result<(), String> {
+        self.0.push(DrawCommand::FillRects(color, rects.to_vec()));
+        Ok(())
+    }
+
+    fn fill_circle(&mut self, color: Color, center: (i16, i16), radius: i16) -> Result<(), String> {
+        self.0.push(DrawCommand::FillCircle(color, center, radius));
+        Ok(())
+    }
+
+    fn stroke_circle(&mut self, color: Color, center: (i16, i16), radius: i16) -> Result<(), String> {
+        self.0.push(DrawCommand::StrokeCircle(color, center, radius));
+        Ok(())
+    }
+
+    fn draw_text(&mut self, color: Color, position: (i32, i32), scale: f64, text: &str) -> Result<(), String> {
+        self.0.push(DrawCommand::Text(color, position, scale, text.to_string()));
+        Ok(())
+    }
+
+    fn draw_image(&mut self, path: &Path, rect: DrawRect) -> Result<(), String> {
+        self.0.push(DrawCommand::Image(path.to_path_buf(), rect));
+        Ok(())
+    }
+
+    fn present(&mut self) {
+        self.0.push(DrawCommand::Present);
+    }
+}
+
+// A rect or circle waiting for its layer's turn to be drawn.
+#[derive(Clone)]
+#[derive(Copy)]
+enum Shape {
+    Rect(DrawRect),
+    Circle((i16, i16), i16),
+}
+
+// Lays the scene out in physical pixels: the window cleared to black, the Background over the
+// letterboxed play area, then rects and circles in layer order, the approach ring and text on top.
+// The sort buffer, and the batch and text buffers of the last frame's commands, are kept and
+// reused rather than grown from empty again every frame.
+#[derive(Default)]
+pub struct RenderingSystem {
+    layered_shapes: Vec<(Layer, Color, Shape)>,
+    spare_batches: Vec<Vec<DrawRect>>,
+    spare_texts: Vec<String>,
+}
+
+impl<'a> System<'a> for RenderingSystem {
+    type SystemData = (ReadStorage<'a, Rectangle>,
+                       ReadStorage<'a, Circle>,
+                       ReadStorage<'a, Position>,
+                       ReadStorage<'a, Color>,
+                       ReadStorage<'a, Text>,
+                       ReadStorage<'a, Scene>,
+                       ReadStorage<'a, Layer>,
+                       ReadStorage<'a, Scale>,
+                       Read<'a, Viewport>,
+                       Read<'a, GameState>,
+                       Read<'a, ClearColor>,
+                       Read<'a, Background>,
+                       Read<'a, BeatPulse>,
+                       Read<'a, ApproachRing>,
+                       Read<'a, Theme>,
+                       Write<'a, DrawList>,
+                       Write<'a, DrawStats>);
+
+    fn run(&mut self, data: Self::SystemData) {
+        let (
+            rect_storage,
+            circle_storage,
+            position_storage,
+            color_storage,
+            text_storage,
+            scene_storage,
+            layer_storage,
+            scale_storage,
+            viewport,
+            game_state,
+            clear_color,
+            background,
+            beat_pulse,
+            approach_ring,
+            theme,
+            mut draw_list,
+            mut draw_stats,
+        ) = data;
+
+        for command in draw_list.0.drain(..) {
+            match command {
+                DrawCommand::FillRects(_, mut rects) => {
+                    rects.clear();
+                    self.spare_batches.push(rects);
+                },
+                DrawCommand::Text(_, _, _, mut text) => {
+                    text.clear();
+                    self.spare_texts.push(text);
+                },
+                _ => {},
+            }
+        }
+
+        let scale = viewport.scale();
+        let (offset_x, offset_y) = viewport.offset();
+        let play_area = DrawRect {
+            x: offset_x.round() as i32,
+            y: offset_y.round() as i32,
+            width: (LOGICAL_WIDTH * scale).round() as u32,
+            height: (LOGICAL_HEIGHT * scale).round() as u32,
+        };
+        draw_list.0.push(DrawCommand::Clear(Color::rgb(0, 0, 0)));
+        match &*background {
+            Background::Solid(_) => draw_list.0.push(DrawCommand::FillRect(clear_color.0, play_area)),
+            Background::Gradient(top, bottom) => {
+                // Band edges are rounded from the same scale, so neighbouring bands always meet.
+                let band_y = |band: u32| play_area.y + (play_area.height as f64 * band as f64 / GRADIENT_BANDS as f64).round() as i32;
+                for band in 0..GRADIENT_BANDS {
+                    let t = band as f64 / (GRADIENT_BANDS - 1) as f64;
+                    draw_list.0.push(DrawCommand::FillRect(Color::lerp(*top, *bottom, t), DrawRect {
+                        x: play_area.x,
+                        y: band_y(band),
+                        width: play_area.width,
+                        height: (band_y(band + 1) - band_y(band)) as u32,
+                    }));
+                }
+            },
+            Background::Image(path) => draw_list.0.push(DrawCommand::Image(path.clone(), play_area)),
+        }
+        if background.solid_color().is_none() && beat_pulse.0 > 0.0 {
+            let pulse = Color { a: (beat_pulse.0 * theme.beat_pulse.a as f64).round() as u8, ..theme.beat_pulse };
+            draw_list.0.push(DrawCommand::FillRect(pulse, play_area));
+        }
+
+        // Rects and circles are collected with their layers and stably sorted together, so a
+        // circle under a rect's layer is drawn under it, and shapes sharing a layer keep the order
+        // the joins gave them, rects first.
+        let layered_shapes = &mut self.layered_shapes;
+        layered_shapes.clear();
+        for (rect, pos, color, scene, layer, entity_scale) in (&rect_storage, &position_storage, &color_storage, scene_storage.maybe(), layer_storage.maybe(), scale_storage.maybe()).join() {
+            if !Scene::visible(scene, *game_state) {
+                continue;
+            }
+            let (x, y) = viewport.to_physical(pos.x, pos.y);
+            let scale = scale * entity_scale.map_or(1.0, |entity_scale| entity_scale.0);
+            layered_shapes.push((
+                *layer.unwrap_or(&NOTE_LAYER),
+                *color,
+                Shape::Rect(DrawRect::from_center(
+                    (x.round() as i32, y.round() as i32),
+                    (rect.width * scale).round() as u32,
+                    (rect.height * scale).round() as u32,
+                )),
+            ));
+        }
+        for (circle, pos, color, scene, layer, entity_scale) in (&circle_storage, &position_storage, &color_storage, scene_storage.maybe(), layer_storage.maybe(), scale_storage.maybe()).join() {
+            if !Scene::visible(scene, *game_state) {
+                continue;
+            }
+            let (x, y) = viewport.to_physical(pos.x, pos.y);
+            let scale = scale * entity_scale.map_or(1.0, |entity_scale| entity_scale.0);
+            layered_shapes.push((
+                *layer.unwrap_or(&NOTE_LAYER),
+                *color,
+                Shape::Circle((x.round() as i16, y.round() as i16), (circle.radius * scale).round() as i16),
+            ));
+        }
+        layered_shapes.sort_by_key(|&(layer, _, _)| layer);
+        draw_stats.rects = 0;
+        draw_stats.batches = 0;
+        for &(_, color, shape) in layered_shapes.iter() {
+            match shape {
+                Shape::Rect(rect) => {
+                    draw_stats.rects += 1;
+                    match draw_list.0.last_mut() {
+                        Some(DrawCommand::FillRects(run_color, run)) if *run_color == color => run.push(rect),
+                        _ => {
+                            let mut run = self.spare_batches.pop().unwrap_or_default();
+                            run.push(rect);
+                            draw_list.0.push(DrawCommand::FillRects(color, run));
+                            draw_stats.batches += 1;
+                        },
+                    }
+                },
+                Shape::Circle(center, radius) => draw_list.0.push(DrawCommand::FillCircle(color, center, radius)),
+            }
+        }
+
+        if let (Some(radius), true) = (approach_ring.radius, Scene::visible(None, *game_state)) {
+            let (x, y) = viewport.to_physical(approach_ring.x, approach_ring.y);
+            draw_list.0.push(DrawCommand::StrokeCircle(
+                theme.judgement_line,
+                (x.round() as i16, y.round() as i16),
+                (radius * scale).round() as i16,
+            ));
+        }
+
+        for (text, pos, color, scene) in (&text_storage, &position_storage, &color_storage, scene_storage.maybe()).join() {
+            if !Scene::visible(scene, *game_state) {
+                continue;
+            }
+            let (x, y) = viewport.to_physical(pos.x, pos.y);
+            let mut string = self.spare_texts.pop().unwrap_or_default();
+            string.push_str(&text.0);
+            draw_list.0.push(DrawCommand::Text(*color, (x.round() as i32, y.round() as i32), scale, string));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::render::{FLASH_LAYER, RECEPTOR_LAYER};
+
+    fn world() -> World {
+        let mut world = World::new();
+        System::setup(&mut RenderingSystem::default(), &mut world.res);
+        world.register::<Text>();
+        *world.write_resource::<GameState>() = GameState::Playing;
+        world
+    }
+
+    fn rect(world: &mut World, x: f64, color: Color, layer: Option<Layer>) {
+        let entity = world.create_entity()
+            .with(Rectangle { width: 10.0, height: 10.0 })
+            .with(Position { x, y: 100.0 })
+            .with(color);
+        match layer {
+            Some(layer) => entity.with(layer).build(),
+            None => entity.build(),
+        };
+    }
+
+    fn circle(world: &mut World, x: f64, color: Color, layer: Layer) {
+        world.create_entity()
+            .with(Circle { radius: 5.0 })
+            .with(Position { x, y: 100.0 })
+            .with(color)
+            .with(layer)
+            .build();
+    }
+
+    // The frame's shapes, past the clear and the background.
+    fn shapes(world: &World) -> Vec<DrawCommand> {
+        RenderingSystem::default().run_now(&world.res);
+        world.read_resource::<DrawList>().0.iter()
+            .filter(|command| matches!(command, DrawCommand::FillRects(..) | DrawCommand::FillCircle(..)))
+            .cloned()
+            .collect()
+    }
+
+    #[test]
+    fn circles_and_rects_are_drawn_in_one_layer_order() {
+        let red = Color::rgb(255, 0, 0);
+        let green = Color::rgb(0, 255, 0);
+        let blue = Color::rgb(0, 0, 255);
+        let mut world = world();
+        rect(&mut world, 100.0, green, Some(FLASH_LAYER));
+        rect(&mut world, 200.0, red, None);
+        circle(&mut world, 300.0, blue, RECEPTOR_LAYER);
+        circle(&mut world, 400.0, blue, FLASH_LAYER);
+
+        assert_eq!(shapes(&world), vec![
+            DrawCommand::FillCircle(blue, (300, 100), 5),
+            DrawCommand::FillRects(red, vec![DrawRect { x: 195, y: 95, width: 10, height: 10 }]),
+            DrawCommand::FillRects(green, vec![DrawRect { x: 95, y: 95, width: 10, height: 10 }]),
+            DrawCommand::FillCircle(blue, (400, 100), 5),
+        ]);
+    }
+
+    #[test]
+    fn recording_renderer_keeps_every_call_in_order() {
+        let red = Color::rgb(255, 0, 0);
+        let rect = DrawRect { x: 1, y: 2, width: 3, height: 4 };
+        let draw_list = DrawList(vec![
+            DrawCommand::Clear(Color::rgb(0, 0, 0)),
+            DrawCommand::FillRects(red, vec![rect, rect]),
+            DrawCommand::StrokeCircle(red, (5, 6), 7),
+            DrawCommand::Text(red, (8, 9), 1.5, "hi".to_string()),
+        ]);
+        let mut renderer = RecordingRenderer::default();
+        draw_list.draw(&mut renderer);
+        renderer.present();
+
+        assert_eq!(renderer.0, vec![
+            DrawCommand::Clear(Color::rgb(0, 0, 0)),
+            DrawCommand::FillRects(red, vec![rect, rect]),
+            DrawCommand::StrokeCircle(red, (5, 6), 7),
+            DrawCommand::Text(red, (8, 9), 1.5, "hi".to_string()),
+            DrawCommand::Present,
+        ]);
+        assert_eq!(renderer.snapshot(), concat!(
+            "clear #000000\n",
+            "fill_rects #ff0000 1,2 3x4; 1,2 3x4\n",
+            "stroke_circle #ff0000 5,6 r7\n",
+            "text #ff0000 8,9 x1.50 \"hi\"\n",
+            "present\n",
+        ));
+    }
+
+    // Takes the default fill_rects, so a batch is drawn as the rects it was made from.
+    #[derive(Default)]
+    struct RectRecorder(Vec<(Color, DrawRect)>);
+
+    impl Renderer for RectRecorder {
+        fn clear(&mut self, _: Color) {}
+        fn fill_rect(&mut self, color: Color, rect: DrawRect) -> Result<(), String> {
+            self.0.push((color, rect));
+            Ok(())
+        }
+        fn fill_circle(&mut self, _: Color, _: (i16, i16), _: i16) -> Result<(), String> { Ok(()) }
+        fn stroke_circle(&mut self, _: Color, _: (i16, i16), _: i16) -> Result<(), String> { Ok(()) }
+        fn draw_text(&mut self, _: Color, _: (i32, i32), _: f64, _: &str) -> Result<(), String> { Ok(()) }
+        fn draw_image(&mut self, _: &Path, _: DrawRect) -> Result<(), String> { Ok(()) }
+        fn present(&mut self) {}
+    }
+
+    #[test]
+    fn batching_draws_the_same_rects_in_fewer_calls() {
+        let red = Color::rgb(255, 0, 0);
+        let blue = Color::rgb(0, 0, 255);
+        let mut world = world();
+        for (x, color) in [(100.0, red), (200.0, red), (300.0, blue), (400.0, red)].iter().cloned() {
+            rect(&mut world, x, color, None);
+        }
+
+        let batched = shapes(&world);
+        // Only neighbours merge, the red after the blue starts a batch of its own.
+        assert_eq!(batched.len(), 3);
+        assert_eq!(world.read_resource::<DrawStats>().rects, 4);
+        assert_eq!(world.read_resource::<DrawStats>().batches, 3);
+
+        let mut unbatched = RectRecorder::default();
+        DrawList(batched).draw(&mut unbatched);
+        let at = |x: i32| DrawRect { x: x - 5, y: 95, width: 10, height: 10 };
+        assert_eq!(unbatched.0, vec![(red, at(100)), (red, at(200)), (blue, at(300)), (red, at(400))]);
+    }
+
+    #[test]
+    fn shapes_sharing_a_layer_keep_their_order() {
+        let colors: Vec<Color> = (0..6).map(|index| Color::rgb(index * 40, 0, 0)).collect();
+        let mut world = world();
+        // Every other rect is a flash, drawn after all the notes whatever order they were made in.
+        for (index, &color) in colors.iter().enumerate() {
+            let layer = if index % 2 == 0 { None } else { Some(FLASH_LAYER) };
+            rect(&mut world, 100.0 + index as f64 * 10.0, color, layer);
+        }
+        circle(&mut world, 500.0, colors[0], NOTE_LAYER);
+
+        let order: Vec<String> = shapes(&world).iter().map(DrawCommand::snapshot_line).collect();
+        assert_eq!(order, vec![
+            "fill_rects #000000 95,95 10x10",
+            "fill_rects #500000 115,95 10x10",
+            "fill_rects #a00000 135,95 10x10",
+            "fill_circle #000000 500,100 r5",
+            "fill_rects #280000 105,95 10x10",
+            "fill_rects #780000 125,95 10x10",
+            "fill_rects #c80000 145,95 10x10",
+        ]);
+    }
+}
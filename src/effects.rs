@@ -0,0 +1,464 @@
+use serde::{Deserialize, Serialize};
+
+use specs::{prelude::*, BitSet};
+
+use std::collections::VecDeque;
+
+use crate::{
+    AudioTime,
+    FrameTime,
+    TargetInput,
+    combo::{ComboEvent, ComboEvents},
+    lanes::{LaneLayout, NoteColor, ScrollDirection},
+    render::{Background, ClearColor, Color, JudgementLine, Position, Rectangle, Text},
+    rhythm::{AudioContext, BeatPhase, HitOffset, HitWindow, Judgement, JudgementHistory, LastHitKey, LastHitNote},
+    song::PlaybackRate,
+    theme::Theme,
+};
+
+pub const FLASH_SIZE: f64 = 40.0;
+pub const FLASH_DECAY_MILLIS: f64 = 300.0;
+pub const HIT_TICK_FADE_MILLIS: f64 = 300.0;
+// How far a tick sits from the judgement line per ms of error, and how many are shown at once.
+pub const HIT_TICK_PIXELS_PER_MILLI: f64 = 0.5;
+pub const MAX_HIT_TICKS: usize = 8;
+pub const HIT_TICK_LENGTH: f64 = 30.0;
+// Where a judgement label sits from its lane's receptor, clear of the notes coming in.
+pub const JUDGEMENT_LABEL_OFFSET: (f64, f64) = (-40.0, -40.0);
+
+#[derive(Debug)]
+#[derive(Default)]
+#[derive(Clone)]
+#[derive(Copy)]
+pub struct HitFlashMarker;
+
+impl Component for HitFlashMarker {
+    type Storage = NullStorage<Self>;
+}
+
+#[derive(Default)]
+pub struct HitFlash {
+    pub intensity: f64,
+    pub judgements_seen: usize,
+}
+
+pub fn perfect_streak(history: &[Judgement]) -> u32 {
+    history.iter().rev().take_while(|&&judgement| judgement == Judgement::Perfect).count() as u32
+}
+
+// A lone hit flashes at 40%, each consecutive Perfect adds 10% up to full brightness.
+pub fn flash_intensity(streak: u32) -> f64 {
+    (0.4 + 0.1 * streak.saturating_sub(1) as f64).min(1.0)
+}
+
+pub(crate) struct HitFlashSystem;
+
+impl<'a> System<'a> for HitFlashSystem {
+    type SystemData = (Read<'a, FrameTime>,
+                       Read<'a, JudgementHistory>,
+                       Read<'a, LastHitKey>,
+                       Read<'a, LastHitNote>,
+                       Read<'a, LaneLayout>,
+                       Read<'a, ScrollDirection>,
+                       Read<'a, JudgementLine>,
+                       Read<'a, Theme>,
+                       Read<'a, ComboEvents>,
+                       Write<'a, HitFlash>,
+                       ReadStorage<'a, HitFlashMarker>,
+                       ReadStorage<'a, NoteColor>,
+                       WriteStorage<'a, Position>,
+                       WriteStorage<'a, Rectangle>,
+                       WriteStorage<'a, Color>);
+
+    fn run(&mut self, data: Self::SystemData) {
+        let (
+            frame_time,
+            judgement_history,
+            last_hit_key,
+            last_hit_note,
+            lane_layout,
+            direction,
+            judgement_line,
+            theme,
+            combo_events,
+            mut hit_flash,
+            marker_storage,
+            note_color_storage,
+            mut position_storage,
+            mut rect_storage,
+            mut color_storage,
+        ) = data;
+
+        hit_flash.intensity = (hit_flash.intensity - frame_time.0 / FLASH_DECAY_MILLIS).max(0.0);
+
+        if judgement_history.0.len() > hit_flash.judgements_seen {
+            hit_flash.judgements_seen = judgement_history.0.len();
+            hit_flash.intensity = flash_intensity(perfect_streak(&judgement_history.0));
+        }
+        // Milestones and full combos flash at full brightness whatever the streak.
+        if combo_events.events.iter().any(|&event| event != ComboEvent::Broken) {
+            hit_flash.intensity = 1.0;
+        }
+
+        let point = last_hit_key.0.and_then(|key| lane_layout.point(*direction, &judgement_line, key, 0.0));
+        let intensity = hit_flash.intensity;
+        // A note the chart colored flashes in its own color, anything else in its judgement's.
+        let flash_color = last_hit_note.0
+            .and_then(|entity| note_color_storage.get(entity))
+            .map_or_else(
+                || theme.judgement(judgement_history.0.last().cloned().unwrap_or(Judgement::Perfect)),
+                |note_color| note_color.0,
+            );
+        for (_, position, rect, color) in (&marker_storage, &mut position_storage, &mut rect_storage, &mut color_storage).join() {
+            if let Some((x, y)) = point {
+                position.x = x;
+                position.y = y;
+            }
+            rect.width = FLASH_SIZE * intensity;
+            rect.height = FLASH_SIZE * intensity;
+            *color = Color::lerp(flash_color, theme.background, 1.0 - intensity);
+        }
+    }
+}
+
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(PartialEq)]
+#[derive(Default)]
+pub enum BeatFlashMode {
+    EveryBeat,
+    #[default]
+    Downbeat,
+}
+
+// Works out the BeatPhase once a frame, ahead of every effect that keeps time with it.
+pub(crate) struct BeatPhaseSystem;
+
+impl<'a> System<'a> for BeatPhaseSystem {
+    type SystemData = (Read<'a, AudioTime>,
+                       Read<'a, AudioContext>,
+                       Write<'a, BeatPhase>);
+
+    fn run(&mut self, data: Self::SystemData) {
+        let (audio_time, audio_context, mut beat_phase) = data;
+
+        *beat_phase = BeatPhase::at(audio_time.0, &audio_context);
+    }
+}
+
+// How far into the beat pulse the background is, 1.0 at the beat and 0.0 once it's faded out.
+#[derive(Default)]
+pub struct BeatPulse(pub f64);
+
+// Flashes the background to the theme's beat pulse each time the BeatPhase wraps into a new beat,
+// or only a new bar on Downbeat, fading back to the background over that beat or bar. The fade
+// runs on frame time scaled by the playback rate, so it lasts as long as the beat does in song
+// time. A Solid background gets it mixed into the ClearColor, any other has BeatPulse laid over it.
+#[derive(Default)]
+pub(crate) struct BeatFlashSystem {
+    intensity: f64,
+    last_phase: Option<f64>,
+}
+
+impl<'a> System<'a> for BeatFlashSystem {
+    type SystemData = (Read<'a, BeatPhase>,
+                       Read<'a, FrameTime>,
+                       Read<'a, AudioContext>,
+                       Read<'a, PlaybackRate>,
+                       Read<'a, BeatFlashMode>,
+                       Read<'a, Theme>,
+                       Read<'a, Background>,
+                       Write<'a, ClearColor>,
+                       Write<'a, BeatPulse>);
+
+    fn run(&mut self, data: Self::SystemData) {
+        let (
+            beat_phase,
+            frame_time,
+            audio_context,
+            playback_rate,
+            mode,
+            theme,
+            background,
+            mut clear_color,
+            mut beat_pulse,
+        ) = data;
+
+        let base = background.solid_color().unwrap_or(theme.background);
+        let (period_millis, phase) = match *mode {
+            BeatFlashMode::EveryBeat => (audio_context.beat_millis, beat_phase.beat),
+            BeatFlashMode::Downbeat => (audio_context.bar_millis, beat_phase.bar),
+        };
+        if period_millis == 0 {
+            clear_color.0 = base;
+            beat_pulse.0 = 0.0;
+            return;
+        }
+
+        self.intensity = (self.intensity - frame_time.0 * playback_rate.0 as f64 / period_millis as f64).max(0.0);
+        // A seek back wraps the phase too, and flashes just the same.
+        if self.last_phase.is_some_and(|last| phase < last) {
+            self.intensity = 1.0;
+        }
+        self.last_phase = Some(phase);
+
+        beat_pulse.0 = self.intensity.powi(2);
+        clear_color.0 = Color::lerp(base, theme.beat_pulse, beat_pulse.0);
+    }
+}
+
+struct HitTick {
+    entity: Entity,
+    color: Color,
+    brightness: f64,
+    age: f64,
+}
+
+// Drops a tick by the judgement line for every hit, off it toward the oncoming notes when early
+// and past it when late by HIT_TICK_PIXELS_PER_MILLI for each ms, in the theme's early or late
+// color. A bigger error draws a brighter tick, and each fades into the background over
+// HIT_TICK_FADE_MILLIS. Only the newest MAX_HIT_TICKS stay, so a fast run stays readable. A hit is
+// a note gaining a HitOffset, which catches every one of a frame's update steps, and a note a seek
+// re-arms ticks again when it's hit again.
+#[derive(Default)]
+pub(crate) struct HitTickSystem {
+    ticked: BitSet,
+    ticks: VecDeque<HitTick>,
+}
+
+impl<'a> System<'a> for HitTickSystem {
+    type SystemData = (Entities<'a>,
+                       Read<'a, FrameTime>,
+                       Read<'a, HitWindow>,
+                       Read<'a, LaneLayout>,
+                       Read<'a, ScrollDirection>,
+                       Read<'a, JudgementLine>,
+                       Read<'a, Theme>,
+                       ReadStorage<'a, HitOffset>,
+                       ReadStorage<'a, TargetInput>,
+                       WriteStorage<'a, Position>,
+                       WriteStorage<'a, Rectangle>,
+                       WriteStorage<'a, Color>);
+
+    fn run(&mut self, data: Self::SystemData) {
+        let (
+            entities,
+            frame_time,
+            hit_window,
+            lane_layout,
+            direction,
+            judgement_line,
+            theme,
+            hit_offset_storage,
+            target_input_storage,
+            mut position_storage,
+            mut rect_storage,
+            mut color_storage,
+        ) = data;
+
+        for tick in &mut self.ticks {
+            tick.age += frame_time.0;
+        }
+
+        let hits: Vec<_> = (&hit_offset_storage, &target_input_storage, !&self.ticked)
+            .join()
+            .map(|(hit_offset, target_input, _)| (hit_offset.0, target_input.lane_key()))
+            .collect();
+        self.ticked = hit_offset_storage.mask().clone();
+
+        let widest = hit_window.widest().max(1) as f64;
+        for (error, key) in hits {
+            let (x, y) = match lane_layout.point(*direction, &judgement_line, key, -error as f64 * HIT_TICK_PIXELS_PER_MILLI) {
+                Some(point) => point,
+                None => continue,
+            };
+            let rect = match *direction {
+                ScrollDirection::Horizontal => Rectangle { width: 2.0, height: HIT_TICK_LENGTH },
+                ScrollDirection::VerticalDown => Rectangle { width: HIT_TICK_LENGTH, height: 2.0 },
+            };
+            let color = if error < 0 { theme.early } else { theme.late };
+            let entity = entities.build_entity()
+                .with(Position { x, y }, &mut position_storage)
+                .with(rect, &mut rect_storage)
+                .with(color, &mut color_storage)
+                .build();
+            let brightness = 0.4 + 0.6 * (error.abs() as f64 / widest).min(1.0);
+            self.ticks.push_back(HitTick { entity, color, brightness, age: 0.0 });
+        }
+
+        while self.ticks.len() > MAX_HIT_TICKS || self.ticks.front().is_some_and(|tick| tick.age >= HIT_TICK_FADE_MILLIS) {
+            if let Some(tick) = self.ticks.pop_front() {
+                if let Err(err) = entities.delete(tick.entity) {
+                    log::warn!("Couldn't remove hit tick: {:?}", err);
+                }
+            }
+        }
+
+        for tick in &self.ticks {
+            let fade = 1.0 - tick.age / HIT_TICK_FADE_MILLIS;
+            if let Some(color) = color_storage.get_mut(tick.entity) {
+                *color = Color::lerp(tick.color, theme.background, 1.0 - tick.brightness * fade);
+            }
+        }
+    }
+}
+
+// What each judgement is called when ColorblindMode spells it out.
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(PartialEq)]
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct JudgementLabels {
+    pub perfect: String,
+    pub great: String,
+    pub good: String,
+    pub miss: String,
+}
+
+impl Default for JudgementLabels {
+    fn default() -> JudgementLabels {
+        JudgementLabels {
+            perfect: "PERFECT".to_string(),
+            great: "GREAT".to_string(),
+            good: "GOOD".to_string(),
+            miss: "MISS".to_string(),
+        }
+    }
+}
+
+impl JudgementLabels {
+    pub fn label(&self, judgement: Judgement) -> &str {
+        match judgement {
+            Judgement::Perfect => &self.perfect,
+            Judgement::Great => &self.great,
+            Judgement::Good => &self.good,
+            Judgement::Miss => &self.miss,
+        }
+    }
+}
+
+// Spells each judgement out by the lane it landed in, for players who can't tell the judgement
+// colors apart. See JudgementLabelSystem.
+#[derive(Debug)]
+#[derive(Default)]
+#[derive(Clone)]
+#[derive(PartialEq)]
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct ColorblindMode {
+    pub enabled: bool,
+    pub labels: JudgementLabels,
+}
+
+// Under ColorblindMode, shows the latest judgement's label by the receptor of the last hit, still
+// in its judgement's color, fading out over FLASH_DECAY_MILLIS like the hit flash. There's only
+// ever the one label, each judgement taking over from the last, so a dense run doesn't pile them
+// up. A miss has no lane of its own and shows where the last hit did.
+#[derive(Default)]
+pub(crate) struct JudgementLabelSystem {
+    label: Option<Entity>,
+    judgements_seen: usize,
+    intensity: f64,
+}
+
+impl<'a> System<'a> for JudgementLabelSystem {
+    type SystemData = (Entities<'a>,
+                       Read<'a, FrameTime>,
+                       Read<'a, ColorblindMode>,
+                       Read<'a, JudgementHistory>,
+                       Read<'a, LastHitKey>,
+                       Read<'a, LaneLayout>,
+                       Read<'a, ScrollDirection>,
+                       Read<'a, JudgementLine>,
+                       Read<'a, Theme>,
+                       WriteStorage<'a, Text>,
+                       WriteStorage<'a, Position>,
+                       WriteStorage<'a, Color>);
+
+    fn run(&mut self, data: Self::SystemData) {
+        let (
+            entities,
+            frame_time,
+            colorblind_mode,
+            judgement_history,
+            last_hit_key,
+            lane_layout,
+            direction,
+            judgement_line,
+            theme,
+            mut text_storage,
+            mut position_storage,
+            mut color_storage,
+        ) = data;
+
+        self.intensity = (self.intensity - frame_time.0 / FLASH_DECAY_MILLIS).max(0.0);
+        let fresh = judgement_history.0.len() > self.judgements_seen;
+        self.judgements_seen = judgement_history.0.len();
+        if fresh && colorblind_mode.enabled {
+            self.intensity = 1.0;
+        }
+
+        if self.intensity == 0.0 || !colorblind_mode.enabled {
+            if let Some(entity) = self.label.take() {
+                if let Err(err) = entities.delete(entity) {
+                    log::warn!("Couldn't remove judgement label: {:?}", err);
+                }
+            }
+            return;
+        }
+
+        let judgement = match judgement_history.0.last() {
+            Some(&judgement) => judgement,
+            None => return,
+        };
+        let entity = *self.label.get_or_insert_with(|| entities.create());
+        if fresh {
+            if let Err(err) = text_storage.insert(entity, Text(colorblind_mode.labels.label(judgement).to_string())) {
+                log::warn!("Couldn't label judgement: {:?}", err);
+            }
+        }
+        if let Some((x, y)) = last_hit_key.0.and_then(|key| lane_layout.point(*direction, &judgement_line, key, 0.0)) {
+            let (dx, dy) = JUDGEMENT_LABEL_OFFSET;
+            if let Err(err) = position_storage.insert(entity, Position { x: x + dx, y: y + dy }) {
+                log::warn!("Couldn't place judgement label: {:?}", err);
+            }
+        }
+        let color = Color::lerp(theme.judgement(judgement), theme.background, 1.0 - self.intensity);
+        if let Err(err) = color_storage.insert(entity, color) {
+            log::warn!("Couldn't color judgement label: {:?}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flash_brightens_with_each_consecutive_perfect() {
+        let mut history = Vec::new();
+        let mut last = 0.0;
+        for _ in 0..7 {
+            history.push(Judgement::Perfect);
+            let intensity = flash_intensity(perfect_streak(&history));
+            assert!(intensity > last);
+            last = intensity;
+        }
+        assert_eq!(last, 1.0);
+        history.push(Judgement::Perfect);
+        assert_eq!(flash_intensity(perfect_streak(&history)), 1.0);
+    }
+
+    #[test]
+    fn flash_resets_after_a_non_perfect() {
+        let history = vec![Judgement::Perfect, Judgement::Perfect, Judgement::Perfect, Judgement::Great];
+        assert_eq!(perfect_streak(&history), 0);
+        assert_eq!(flash_intensity(perfect_streak(&history)), flash_intensity(1));
+
+        let history = vec![Judgement::Perfect, Judgement::Good, Judgement::Perfect];
+        assert_eq!(perfect_streak(&history), 1);
+        assert!((flash_intensity(1) - 0.4).abs() < 1e-9);
+    }
+}
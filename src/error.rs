@@ -0,0 +1,38 @@
+use std::{
+    fmt,
+    io,
+};
+
+use crate::{
+    assets::AssetError,
+    chart::ChartError,
+    session::Divergence,
+    song::SongError,
+};
+
+#[derive(Debug)]
+pub enum GameError {
+    Sdl(String),
+    Io { path: String, err: io::Error },
+    Asset(AssetError),
+    Song(SongError),
+    Chart { path: String, err: ChartError },
+    Divergence(Divergence),
+}
+
+impl fmt::Display for GameError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GameError::Sdl(message) => write!(f, "SDL error: {}", message),
+            GameError::Io { path, err } => write!(f, "couldn't open {}: {}", path, err),
+            GameError::Asset(err) => write!(f, "{}", err),
+            GameError::Song(err) => write!(f, "{}", err),
+            GameError::Chart { path, err } => write!(f, "couldn't load {}: {}", path, err),
+            GameError::Divergence(divergence) => write!(f, "session diverged, {}", divergence),
+        }
+    }
+}
+
+pub fn sdl_error<E: fmt::Display>(err: E) -> GameError {
+    GameError::Sdl(err.to_string())
+}
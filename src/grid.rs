@@ -0,0 +1,121 @@
+use specs::prelude::*;
+
+use crate::{
+    InterpolatedAudioTime,
+    countdown::LeadIn,
+    lanes::{ScrollDirection, ScrollSpeed},
+    render::{Color, JudgementLine, Layer, Position, Rectangle, BACKGROUND_LAYER, LOGICAL_HEIGHT, LOGICAL_WIDTH},
+    rhythm::AudioContext,
+    theme::Theme,
+};
+
+pub const TICK_THICKNESS: f64 = 2.0;
+
+// Draws a line across the lanes at every upcoming beat, brighter at the start of each bar. Beats
+// are placed the way notes are, bar multiples of bar_millis plus beat multiples of beat_millis,
+// and with the same ScrollSpeed, so the grid stays lined up with the notes. The tick entities are
+// kept from frame to frame and ones without a beat to show are shrunk to nothing, and so is the
+// list of beats they're placed from.
+#[derive(Default)]
+pub(crate) struct BeatGridSystem {
+    ticks: Vec<Entity>,
+    lines: Vec<(f64, bool)>,
+}
+
+impl<'a> System<'a> for BeatGridSystem {
+    type SystemData = (Entities<'a>,
+                       Read<'a, InterpolatedAudioTime>,
+                       Read<'a, AudioContext>,
+                       Read<'a, JudgementLine>,
+                       Read<'a, ScrollSpeed>,
+                       Read<'a, ScrollDirection>,
+                       Read<'a, LeadIn>,
+                       Read<'a, Theme>,
+                       WriteStorage<'a, Layer>,
+                       WriteStorage<'a, Position>,
+                       WriteStorage<'a, Rectangle>,
+                       WriteStorage<'a, Color>);
+
+    fn run(&mut self, data: Self::SystemData) {
+        let (
+            entities,
+            audio_time,
+            audio_context,
+            judgement_line,
+            scroll_speed,
+            direction,
+            lead_in,
+            theme,
+            mut layer_storage,
+            mut position_storage,
+            mut rect_storage,
+            mut color_storage,
+        ) = data;
+
+        let beats_per_bar = audio_context.beats_per_bar.max(1) as u64;
+        let lines = &mut self.lines;
+        lines.clear();
+        if audio_context.beat_millis > 0 {
+            let track_length = match *direction {
+                ScrollDirection::Horizontal => LOGICAL_WIDTH,
+                ScrollDirection::VerticalDown => LOGICAL_HEIGHT,
+            };
+            let visible_millis = (track_length / scroll_speed.0) as u64;
+            let now = audio_time.0;
+
+            let mut beat = now / audio_context.bar_millis.max(1) * beats_per_bar;
+            loop {
+                let beat_time = beat / beats_per_bar * audio_context.bar_millis + beat % beats_per_bar * audio_context.beat_millis;
+                if beat_time + lead_in.0 > now + visible_millis {
+                    break;
+                }
+                if beat_time >= now {
+                    let distance = (beat_time - now + lead_in.0) as f64 * scroll_speed.0;
+                    lines.push((distance, beat.is_multiple_of(beats_per_bar)));
+                }
+                beat += 1;
+            }
+        }
+
+        while self.ticks.len() < lines.len() {
+            let entity = entities.build_entity()
+                .with(BACKGROUND_LAYER, &mut layer_storage)
+                .with(Position { x: 0.0, y: 0.0 }, &mut position_storage)
+                .with(Rectangle { width: 0.0, height: 0.0 }, &mut rect_storage)
+                .with(theme.beat_line, &mut color_storage)
+                .build();
+            self.ticks.push(entity);
+        }
+
+        for (index, &entity) in self.ticks.iter().enumerate() {
+            let (position, rect, color) = match lines.get(index) {
+                Some(&(distance, bar_line)) => {
+                    let color = if bar_line { theme.bar_line } else { theme.beat_line };
+                    match *direction {
+                        ScrollDirection::Horizontal => (
+                            Position { x: judgement_line.x + distance, y: LOGICAL_HEIGHT / 2.0 },
+                            Rectangle { width: TICK_THICKNESS, height: LOGICAL_HEIGHT },
+                            color,
+                        ),
+                        ScrollDirection::VerticalDown => (
+                            Position { x: LOGICAL_WIDTH / 2.0, y: LOGICAL_HEIGHT - judgement_line.x - distance },
+                            Rectangle { width: LOGICAL_WIDTH, height: TICK_THICKNESS },
+                            color,
+                        ),
+                    }
+                },
+                None => (Position { x: 0.0, y: 0.0 }, Rectangle { width: 0.0, height: 0.0 }, theme.beat_line),
+            };
+
+            if let Some(tick_position) = position_storage.get_mut(entity) {
+                *tick_position = position;
+            }
+            if let Some(tick_rect) = rect_storage.get_mut(entity) {
+                *tick_rect = rect;
+            }
+            if let Some(tick_color) = color_storage.get_mut(entity) {
+                *tick_color = color;
+            }
+        }
+    }
+}
@@ -0,0 +1,194 @@
+use specs::prelude::*;
+
+use crate::{
+    render::{Color, Position, Rectangle, FLASH_LAYER},
+    rhythm::{Judgement, JudgementHistory},
+    song::SongEnd,
+    state::{GameState, Scene},
+};
+
+pub const HEALTH_BAR_WIDTH: f64 = 300.0;
+pub const HEALTH_BAR_HEIGHT: f64 = 12.0;
+pub const HEALTH_BAR_X: f64 = 250.0;
+pub const HEALTH_BAR_Y: f64 = 570.0;
+
+// 0.0 is empty, 1.0 is full. Play starts at full.
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(Copy)]
+pub struct Health(pub f32);
+
+impl Default for Health {
+    fn default() -> Health { Health(1.0) }
+}
+
+impl Health {
+    pub fn apply(&mut self, judgement: Judgement) {
+        self.0 = (self.0 + health_delta(judgement)).clamp(0.0, 1.0);
+    }
+
+    pub fn depleted(&self) -> bool {
+        self.0 <= 0.0
+    }
+}
+
+pub fn health_delta(judgement: Judgement) -> f32 {
+    match judgement {
+        Judgement::Perfect => 0.02,
+        Judgement::Great => 0.01,
+        Judgement::Good => 0.0,
+        Judgement::Miss => -0.1,
+    }
+}
+
+// Whether running out of health ends the song. Cosmetic keeps the bar around but lets the song
+// play out regardless.
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(PartialEq)]
+#[derive(Default)]
+pub enum HealthDrainMode {
+    #[default]
+    Cosmetic,
+    Fail,
+}
+
+#[derive(Debug)]
+#[derive(Default)]
+#[derive(Clone)]
+#[derive(Copy)]
+pub struct HealthBar;
+
+impl Component for HealthBar {
+    type Storage = NullStorage<Self>;
+}
+
+pub fn spawn_health_bar(world: &mut World) {
+    world.create_entity()
+        .with(HealthBar)
+        .with(FLASH_LAYER)
+        .with(Scene(GameState::Playing))
+        .with(Rectangle { width: HEALTH_BAR_WIDTH, height: HEALTH_BAR_HEIGHT })
+        .with(Color::rgb(0, 180, 0))
+        .with(Position { x: HEALTH_BAR_X + HEALTH_BAR_WIDTH / 2.0, y: HEALTH_BAR_Y })
+        .build();
+}
+
+// Applies every new judgement to Health and, under HealthDrainMode::Fail, finishes the song the
+// moment it hits zero so the results screen comes up straight away. The bar stays left-anchored
+// and shrinks toward its left edge.
+#[derive(Default)]
+pub(crate) struct HealthSystem {
+    judgements_seen: usize,
+}
+
+impl<'a> System<'a> for HealthSystem {
+    type SystemData = (Read<'a, JudgementHistory>,
+                       Read<'a, HealthDrainMode>,
+                       Write<'a, Health>,
+                       Write<'a, SongEnd>,
+                       Write<'a, GameState>,
+                       ReadStorage<'a, HealthBar>,
+                       WriteStorage<'a, Position>,
+                       WriteStorage<'a, Rectangle>);
+
+    fn run(&mut self, data: Self::SystemData) {
+        let (
+            judgement_history,
+            drain_mode,
+            mut health,
+            mut song_end,
+            mut game_state,
+            bar_storage,
+            mut position_storage,
+            mut rect_storage,
+        ) = data;
+
+        let new_judgements = judgement_history.0.get(self.judgements_seen..).unwrap_or(&[]);
+        for &judgement in new_judgements {
+            health.apply(judgement);
+        }
+        self.judgements_seen = judgement_history.0.len();
+
+        if health.depleted()
+            && *drain_mode == HealthDrainMode::Fail
+            && *game_state == GameState::Playing
+            && !song_end.finished
+        {
+            song_end.ended = true;
+            song_end.finished = true;
+            *game_state = GameState::Results;
+        }
+
+        let width = HEALTH_BAR_WIDTH * health.0 as f64;
+        for (_, position, rect) in (&bar_storage, &mut position_storage, &mut rect_storage).join() {
+            rect.width = width;
+            position.x = HEALTH_BAR_X + width / 2.0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use specs::RunNow;
+
+    fn health_world(drain_mode: HealthDrainMode) -> World {
+        let mut world = World::new();
+        world.register::<HealthBar>();
+        world.register::<Position>();
+        world.register::<Rectangle>();
+        world.add_resource(JudgementHistory::default());
+        world.add_resource(drain_mode);
+        world.add_resource(Health::default());
+        world.add_resource(SongEnd::default());
+        world.add_resource(GameState::Playing);
+        world
+    }
+
+    fn judge(world: &mut World, health_system: &mut HealthSystem, judgements: &[Judgement]) {
+        world.write_resource::<JudgementHistory>().0.extend_from_slice(judgements);
+        health_system.run_now(&world.res);
+    }
+
+    #[test]
+    fn health_stays_within_empty_and_full() {
+        let mut health = Health::default();
+        health.apply(Judgement::Perfect);
+        assert_eq!(health.0, 1.0);
+
+        for _ in 0..20 {
+            health.apply(Judgement::Miss);
+        }
+        assert_eq!(health.0, 0.0);
+        assert!(health.depleted());
+    }
+
+    #[test]
+    fn draining_to_zero_fails_the_song() {
+        let mut world = health_world(HealthDrainMode::Fail);
+        let mut health_system = HealthSystem::default();
+        judge(&mut world, &mut health_system, &[Judgement::Miss; 9]);
+        assert_eq!(*world.read_resource::<GameState>(), GameState::Playing);
+
+        judge(&mut world, &mut health_system, &[Judgement::Miss]);
+        assert!(world.read_resource::<Health>().depleted());
+        assert!(world.read_resource::<SongEnd>().finished);
+        assert_eq!(*world.read_resource::<GameState>(), GameState::Results);
+    }
+
+    #[test]
+    fn cosmetic_health_lets_the_song_play_on() {
+        let mut world = health_world(HealthDrainMode::Cosmetic);
+        let mut health_system = HealthSystem::default();
+        judge(&mut world, &mut health_system, &[Judgement::Miss; 12]);
+        assert!(world.read_resource::<Health>().depleted());
+        assert_eq!(*world.read_resource::<GameState>(), GameState::Playing);
+
+        // Every judgement is applied once, so hits after the misses refill from zero.
+        judge(&mut world, &mut health_system, &[Judgement::Perfect; 5]);
+        assert!((world.read_resource::<Health>().0 - 0.1).abs() < 1e-6);
+    }
+}
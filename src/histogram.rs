@@ -0,0 +1,142 @@
+use specs::prelude::*;
+
+use crate::{
+    render::{Position, Rectangle, Text},
+    results::Results,
+    rhythm::{HitOffset, JudgementWindows},
+    state::{GameState, Scene},
+    theme::Theme,
+};
+
+pub const HISTOGRAM_RANGE_MILLIS: i64 = 100;
+pub const HISTOGRAM_BIN_MILLIS: i64 = 5;
+pub const HISTOGRAM_BINS: usize = (2 * HISTOGRAM_RANGE_MILLIS / HISTOGRAM_BIN_MILLIS) as usize;
+
+// Where the chart sits on the results screen, under the summary, in logical pixels.
+pub const HISTOGRAM_LEFT: f64 = 200.0;
+pub const HISTOGRAM_WIDTH: f64 = 400.0;
+pub const HISTOGRAM_BASELINE: f64 = 570.0;
+pub const HISTOGRAM_HEIGHT: f64 = 100.0;
+
+// Signed hit offsets in HISTOGRAM_BIN_MILLIS bins from -HISTOGRAM_RANGE_MILLIS, early, up to
+// +HISTOGRAM_RANGE_MILLIS, late. Offsets past either end count in the bin at that end. The mean and
+// standard deviation are over the offsets themselves, not the bins.
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(PartialEq)]
+pub struct OffsetHistogram {
+    pub bins: Vec<u32>,
+    pub count: u32,
+    sum: f64,
+    sum_of_squares: f64,
+}
+
+impl Default for OffsetHistogram {
+    fn default() -> OffsetHistogram {
+        OffsetHistogram { bins: vec![0; HISTOGRAM_BINS], count: 0, sum: 0.0, sum_of_squares: 0.0 }
+    }
+}
+
+impl OffsetHistogram {
+    pub fn from_offsets<I: IntoIterator<Item = i64>>(offsets: I) -> OffsetHistogram {
+        let mut histogram = OffsetHistogram::default();
+        for offset in offsets {
+            histogram.add(offset);
+        }
+        histogram
+    }
+
+    pub fn add(&mut self, offset: i64) {
+        let clamped = offset.clamp(-HISTOGRAM_RANGE_MILLIS, HISTOGRAM_RANGE_MILLIS - 1);
+        self.bins[((clamped + HISTOGRAM_RANGE_MILLIS) / HISTOGRAM_BIN_MILLIS) as usize] += 1;
+        self.count += 1;
+        self.sum += offset as f64;
+        self.sum_of_squares += (offset * offset) as f64;
+    }
+
+    // The offset in the middle of bin `index`.
+    pub fn bin_center(index: usize) -> i64 {
+        index as i64 * HISTOGRAM_BIN_MILLIS - HISTOGRAM_RANGE_MILLIS + HISTOGRAM_BIN_MILLIS / 2
+    }
+
+    pub fn mean(&self) -> Option<f64> {
+        if self.count == 0 {
+            return None;
+        }
+        Some(self.sum / self.count as f64)
+    }
+
+    // The population standard deviation, 0 for a single hit.
+    pub fn std_dev(&self) -> Option<f64> {
+        let mean = self.mean()?;
+        Some((self.sum_of_squares / self.count as f64 - mean * mean).max(0.0).sqrt())
+    }
+
+    pub fn summary_line(&self) -> String {
+        match (self.mean(), self.std_dev()) {
+            (Some(mean), Some(std_dev)) => format!("Offset {:+.1}ms  SD {:.1}ms", mean, std_dev),
+            _ => "Offset -  no hits".to_string(),
+        }
+    }
+}
+
+// Once the results are in, bins every hit note's offset into the OffsetHistogram and draws it on
+// the results screen: a bar a bin, tallest bin full height, in the color of the judgement an
+// offset in the middle of the bin would get, over a line marking zero. The mean and standard
+// deviation go in a line above it. With no hits there are no bars, just the line and the text.
+#[derive(Default)]
+pub(crate) struct HistogramRenderSystem {
+    drawn: bool,
+}
+
+impl<'a> System<'a> for HistogramRenderSystem {
+    type SystemData = (Entities<'a>,
+                       Read<'a, Results>,
+                       Read<'a, JudgementWindows>,
+                       Read<'a, Theme>,
+                       Read<'a, LazyUpdate>,
+                       Write<'a, OffsetHistogram>,
+                       ReadStorage<'a, HitOffset>);
+
+    fn run(&mut self, data: Self::SystemData) {
+        let (entities, results, judgement_windows, theme, lazy_update, mut histogram, hit_offset_storage) = data;
+
+        // Results go back to incomplete when a playlist starts its next song.
+        if !results.complete {
+            self.drawn = false;
+            return;
+        }
+        if self.drawn {
+            return;
+        }
+        self.drawn = true;
+
+        *histogram = OffsetHistogram::from_offsets(hit_offset_storage.join().map(|hit_offset| hit_offset.0));
+
+        lazy_update.create_entity(&entities)
+            .with(Scene(GameState::Results))
+            .with(Text(histogram.summary_line()))
+            .with(Position { x: 300.0, y: HISTOGRAM_BASELINE - HISTOGRAM_HEIGHT - 40.0 })
+            .with(theme.text)
+            .build();
+        lazy_update.create_entity(&entities)
+            .with(Scene(GameState::Results))
+            .with(Rectangle { width: 1.0, height: HISTOGRAM_HEIGHT })
+            .with(Position { x: HISTOGRAM_LEFT + HISTOGRAM_WIDTH / 2.0, y: HISTOGRAM_BASELINE - HISTOGRAM_HEIGHT / 2.0 })
+            .with(theme.judgement_line)
+            .build();
+
+        let tallest = histogram.bins.iter().cloned().max().unwrap_or(0).max(1);
+        let bin_width = HISTOGRAM_WIDTH / HISTOGRAM_BINS as f64;
+        for (index, &count) in histogram.bins.iter().enumerate().filter(|&(_, &count)| count > 0) {
+            let height = HISTOGRAM_HEIGHT * count as f64 / tallest as f64;
+            let judgement = judgement_windows.judge(OffsetHistogram::bin_center(index).unsigned_abs());
+            lazy_update.create_entity(&entities)
+                .with(Scene(GameState::Results))
+                .with(Rectangle { width: bin_width - 2.0, height })
+                .with(Position { x: HISTOGRAM_LEFT + (index as f64 + 0.5) * bin_width, y: HISTOGRAM_BASELINE - height / 2.0 })
+                .with(theme.judgement(judgement))
+                .build();
+        }
+    }
+}
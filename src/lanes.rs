@@ -0,0 +1,895 @@
+use sdl2::keyboard::Keycode;
+
+use serde::{Deserialize, Serialize};
+
+use specs::prelude::*;
+
+use std::collections::{HashMap, HashSet};
+
+use crate::{
+    InterpolatedAudioTime,
+    SongTime,
+    TargetInput,
+    countdown::LeadIn,
+    render::{
+        Circle,
+        Color,
+        JudgementLine,
+        Position,
+        Rectangle,
+        Scale,
+        Layer,
+        Text,
+        Viewport,
+        BACKGROUND_LAYER,
+        LOGICAL_HEIGHT,
+        LOGICAL_WIDTH,
+        RECEPTOR_LAYER,
+    },
+    rhythm::{AudioContext, BarIndex, BeatPhase, HoldDuration, HoldTicks, Judged, Judgement, TargetBarTime},
+    sdl::HeldKeys,
+    settings::KeyBindings,
+    state::GameState,
+    theme::Theme,
+};
+
+pub const NOTE_SIZE: f64 = 20.0;
+pub const RECEPTOR_SIZE: f64 = 26.0;
+pub const LANE_SPACING: f64 = 100.0;
+pub const COLUMN_SPACING: f64 = 80.0;
+pub const MAX_LANES: u8 = 8;
+pub const SCROLL_SPEED_STEP: f64 = 0.05;
+pub const MIN_SCROLL_SPEED: f64 = 0.1;
+pub const MAX_SCROLL_SPEED: f64 = 2.0;
+// How far a held lane's receptor is pushed toward white, 0.0 leaving it untouched.
+pub const RECEPTOR_HIGHLIGHT: f64 = 0.6;
+pub const NOTE_POP_MILLIS: u64 = 100;
+pub const NOTE_POP_SCALE: f64 = 1.3;
+pub const HOLD_TAIL_WIDTH: f64 = NOTE_SIZE / 2.0;
+// How far a broken hold's tail is faded toward the background.
+pub const HOLD_TAIL_DIM: f64 = 0.6;
+// The key legend shows through the lead-in and this long into the song.
+pub const KEY_LEGEND_MILLIS: i64 = 2000;
+// How much of the ApproachTime a note spends fading in.
+pub const APPROACH_FADE_FRACTION: f64 = 0.25;
+
+// Pixels a note travels per millisecond on its way to the judgement line. Only NoteRenderSystem
+// reads it, so it spaces notes out without moving when they can be hit.
+pub struct ScrollSpeed(pub f64);
+
+impl Default for ScrollSpeed {
+    fn default() -> ScrollSpeed { ScrollSpeed(0.4) }
+}
+
+impl ScrollSpeed {
+    pub fn nudge(&mut self, delta: f64) {
+        self.0 = (self.0 + delta).clamp(MIN_SCROLL_SPEED, MAX_SCROLL_SPEED);
+    }
+}
+
+// How long before its target a note shows up, in ms, fading in over the first
+// APPROACH_FADE_FRACTION of that. ScrollSpeed still decides where it's drawn, so a short approach
+// at a slow scroll has notes appear partway down the lane. 0 shows them as soon as they'd scroll
+// into the window.
+#[derive(Debug)]
+#[derive(Default)]
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(PartialEq)]
+#[derive(Serialize, Deserialize)]
+pub struct ApproachTime(pub u64);
+
+impl ApproachTime {
+    // How opaque a note `millis_to_hit` from its target is drawn, None before its approach starts.
+    pub fn visibility(&self, millis_to_hit: i64) -> Option<f64> {
+        if self.0 == 0 {
+            return Some(1.0);
+        }
+        let remaining = self.0 as i64 - millis_to_hit;
+        if remaining < 0 {
+            return None;
+        }
+        Some((remaining as f64 / (self.0 as f64 * APPROACH_FADE_FRACTION)).min(1.0))
+    }
+}
+
+// Fades `color` by `visibility`, leaving it alone when fully visible.
+fn faded(color: Color, visibility: f64) -> Color {
+    Color { a: (color.a as f64 * visibility).round() as u8, ..color }
+}
+
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(PartialEq)]
+#[derive(Eq)]
+#[derive(Default)]
+pub enum ScrollDirection {
+    #[default]
+    Horizontal,
+    VerticalDown,
+}
+
+// What notes and receptors are drawn as. A Circle fits inside the Square of the same size.
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(PartialEq)]
+#[derive(Eq)]
+#[derive(Default)]
+pub enum NoteShape {
+    #[default]
+    Square,
+    Circle,
+}
+
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(Copy)]
+pub struct LaneConfig {
+    pub y: f64,
+    pub color: Color,
+    pub key: Keycode,
+}
+
+// How many lanes the playfield has, from 1 to MAX_LANES.
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(PartialEq)]
+#[derive(Serialize, Deserialize)]
+pub struct LaneCount(pub u8);
+
+impl Default for LaneCount {
+    fn default() -> LaneCount { LaneCount(4) }
+}
+
+impl LaneCount {
+    pub fn new(count: u8) -> LaneCount {
+        LaneCount(count.clamp(1, MAX_LANES))
+    }
+
+    // The keys charts name the lanes by. Four lanes keep the arrow keys charts have always used,
+    // played on DFJK through default_key_bindings; other counts are named by the home row keys
+    // they're played on.
+    pub fn lane_keys(&self) -> Vec<Keycode> {
+        match self.0 {
+            4 => vec![Keycode::Left, Keycode::Down, Keycode::Up, Keycode::Right],
+            count => home_row_keys(count),
+        }
+    }
+
+    // Every count is played on the home row: DFJK onto the arrows for four lanes, and the lane
+    // keys themselves for the rest, bound explicitly so every count has a full set of bindings.
+    pub fn default_key_bindings(&self) -> HashMap<Keycode, Keycode> {
+        home_row_keys(self.0).into_iter().zip(self.lane_keys()).collect()
+    }
+}
+
+// Split evenly between the hands, with Space in the middle for odd counts.
+fn home_row_keys(count: u8) -> Vec<Keycode> {
+    let left = [Keycode::F, Keycode::D, Keycode::S, Keycode::A];
+    let right = [Keycode::J, Keycode::K, Keycode::L, Keycode::Semicolon];
+    let per_hand = (count / 2) as usize;
+    let mut keys: Vec<Keycode> = left[..per_hand].iter().rev().cloned().collect();
+    if count % 2 == 1 {
+        keys.push(Keycode::Space);
+    }
+    keys.extend(right[..per_hand].iter().cloned());
+    keys
+}
+
+pub struct LaneLayout(pub Vec<LaneConfig>);
+
+impl Default for LaneLayout {
+    fn default() -> LaneLayout { LaneLayout::for_count(LaneCount::default()) }
+}
+
+impl LaneLayout {
+    // Horizontal lanes are centered on the screen LANE_SPACING apart, closer if that many don't
+    // fit, so they always stay on screen. Colors go round the theme's lane colors.
+    pub fn for_count(count: LaneCount) -> LaneLayout {
+        let keys = count.lane_keys();
+        let spacing = LANE_SPACING.min(LOGICAL_HEIGHT / (keys.len() as f64 + 1.0));
+        let center = (keys.len() as f64 - 1.0) / 2.0;
+        let colors = Theme::default().lanes;
+        LaneLayout(keys.into_iter()
+            .enumerate()
+            .map(|(index, key)| LaneConfig {
+                y: LOGICAL_HEIGHT / 2.0 + (index as f64 - center) * spacing,
+                color: colors[index % colors.len()],
+                key,
+            })
+            .collect())
+    }
+
+    pub fn lane(&self, key: Keycode) -> Option<&LaneConfig> {
+        self.0.iter().find(|lane| lane.key == key)
+    }
+
+    // Where something `distance` pixels before the judgement point of `key`'s lane is drawn.
+    // Horizontal lanes sit at their configured y and scroll left onto the judgement line's x.
+    // VerticalDown lanes are columns COLUMN_SPACING apart centered on the screen, or spread evenly
+    // across its width when that many don't fit, falling onto receptors the judgement line's x up
+    // from the bottom edge.
+    pub fn point(&self, direction: ScrollDirection, judgement_line: &JudgementLine, key: Keycode, distance: f64) -> Option<(f64, f64)> {
+        let index = self.0.iter().position(|lane| lane.key == key)?;
+        Some(match direction {
+            ScrollDirection::Horizontal => (judgement_line.x + distance, self.0[index].y),
+            ScrollDirection::VerticalDown => {
+                let center = (self.0.len() as f64 - 1.0) / 2.0;
+                let spacing = COLUMN_SPACING.min(LOGICAL_WIDTH / self.0.len() as f64);
+                (
+                    LOGICAL_WIDTH / 2.0 + (index as f64 - center) * spacing,
+                    LOGICAL_HEIGHT - judgement_line.x - distance,
+                )
+            },
+        })
+    }
+
+    // Returns the number of distinct lanes the keys use when that's more than the layout has.
+    pub fn validate_count<I: IntoIterator<Item = Keycode>>(&self, keys: I) -> Result<(), usize> {
+        let used: HashSet<Keycode> = keys.into_iter().collect();
+        if used.len() > self.0.len() {
+            Err(used.len())
+        } else {
+            Ok(())
+        }
+    }
+
+    // Returns the first key that has no lane to be drawn in.
+    pub fn validate<I: IntoIterator<Item = Keycode>>(&self, keys: I) -> Result<(), Keycode> {
+        match keys.into_iter().find(|&key| self.lane(key).is_none()) {
+            Some(key) => Err(key),
+            None => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(Copy)]
+pub struct Receptor(pub Keycode);
+
+impl Component for Receptor {
+    type Storage = VecStorage<Self>;
+}
+
+// A chart's color for one note, drawn instead of its lane's color.
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(Copy)]
+pub struct NoteColor(pub Color);
+
+impl Component for NoteColor {
+    type Storage = VecStorage<Self>;
+}
+
+pub fn spawn_receptors(world: &mut World) {
+    let judgement_line = *world.read_resource::<JudgementLine>();
+    let direction = *world.read_resource::<ScrollDirection>();
+    let lane_layout = LaneLayout(world.read_resource::<LaneLayout>().0.clone());
+    let track_color = world.read_resource::<Theme>().judgement_line;
+    let shape = *world.read_resource::<NoteShape>();
+    for lane in &lane_layout.0 {
+        let (x, y) = match lane_layout.point(direction, &judgement_line, lane.key, 0.0) {
+            Some(point) => point,
+            None => continue,
+        };
+        let (track, track_position) = match direction {
+            ScrollDirection::Horizontal => (Rectangle { width: LOGICAL_WIDTH, height: 1.0 }, Position { x: LOGICAL_WIDTH / 2.0, y }),
+            ScrollDirection::VerticalDown => (Rectangle { width: 1.0, height: LOGICAL_HEIGHT }, Position { x, y: LOGICAL_HEIGHT / 2.0 }),
+        };
+
+        world.create_entity()
+            .with(track)
+            .with(BACKGROUND_LAYER)
+            .with(track_color)
+            .with(track_position)
+            .build();
+
+        let receptor = world.create_entity()
+            .with(Receptor(lane.key))
+            .with(Scale(1.0))
+            .with(RECEPTOR_LAYER)
+            .with(lane.color)
+            .with(Position { x, y });
+        match shape {
+            NoteShape::Square => receptor.with(Rectangle { width: RECEPTOR_SIZE, height: RECEPTOR_SIZE }).build(),
+            NoteShape::Circle => receptor.with(Circle { radius: RECEPTOR_SIZE / 2.0 }).build(),
+        };
+    }
+}
+
+// How far up the lane a note can be and still be in the window, with a note's width to spare. The
+// window can show more than the logical area, past its sides or above its top when letterboxed,
+// and notes are drawn there too.
+fn lookahead_distance(viewport: &Viewport, direction: ScrollDirection, judgement_line: &JudgementLine) -> f64 {
+    let (window_right, _) = viewport.to_logical(viewport.width as f64, 0.0);
+    let (_, window_top) = viewport.to_logical(0.0, 0.0);
+    let distance = match direction {
+        ScrollDirection::Horizontal => window_right - judgement_line.x,
+        ScrollDirection::VerticalDown => LOGICAL_HEIGHT - judgement_line.x - window_top,
+    };
+    distance + NOTE_SIZE
+}
+
+// One lane's notes in order of their target time, with a cursor at the first that hasn't reached
+// the judgement line yet and the span after it drawn last frame.
+#[derive(Default)]
+pub struct NoteLane {
+    notes: Vec<(u64, Entity)>,
+    cursor: usize,
+    shown_end: usize,
+}
+
+// Every note by lane for NoteRenderSystem, so it only walks the notes in view rather than the whole
+// chart each frame. Built on first use like LaneIndex; load_song and seek replace it with a default
+// one to have it built again.
+#[derive(Default)]
+pub struct NoteTrack {
+    lanes: HashMap<Keycode, NoteLane>,
+    built: bool,
+}
+
+// Places the unhit notes from the judgement line to the edge of the window along their lanes,
+// scrolling toward the line. A note only gets its shape as it comes into view, further from its
+// target than the ApproachTime or too far up the lane to reach the window's edge yet, with a
+// note's width to spare, and loses it once it's hit or reaches the line, so it's never drawn past
+// the line while it waits out the late side of the window.
+pub(crate) struct NoteRenderSystem;
+
+impl<'a> System<'a> for NoteRenderSystem {
+    type SystemData = (Entities<'a>,
+                       Read<'a, InterpolatedAudioTime>,
+                       Read<'a, JudgementLine>,
+                       Read<'a, LaneLayout>,
+                       Read<'a, LeadIn>,
+                       Read<'a, ApproachTime>,
+                       Read<'a, ScrollSpeed>,
+                       Read<'a, ScrollDirection>,
+                       Read<'a, NoteShape>,
+                       Read<'a, Viewport>,
+                       Write<'a, NoteTrack>,
+                       ReadStorage<'a, TargetInput>,
+                       ReadStorage<'a, TargetBarTime>,
+                       ReadStorage<'a, BarIndex>,
+                       ReadStorage<'a, NoteColor>,
+                       WriteStorage<'a, Position>,
+                       WriteStorage<'a, Rectangle>,
+                       WriteStorage<'a, Circle>,
+                       WriteStorage<'a, Color>);
+
+    fn run(&mut self, data: Self::SystemData) {
+        let (
+            entities,
+            audio_time,
+            judgement_line,
+            lane_layout,
+            lead_in,
+            approach_time,
+            scroll_speed,
+            direction,
+            shape,
+            viewport,
+            mut note_track,
+            target_input_storage,
+            target_bar_time_storage,
+            bar_index_storage,
+            note_color_storage,
+            mut position_storage,
+            mut rect_storage,
+            mut circle_storage,
+            mut color_storage,
+        ) = data;
+
+        if !note_track.built {
+            note_track.lanes.clear();
+            for (entity, input, target_bar_time) in (&*entities, &target_input_storage, &target_bar_time_storage).join() {
+                note_track.lanes.entry(input.lane_key()).or_insert_with(NoteLane::default).notes.push((target_bar_time.0, entity));
+                rect_storage.remove(entity);
+                circle_storage.remove(entity);
+            }
+            for lane in note_track.lanes.values_mut() {
+                lane.notes.sort_by_key(|&(time, _)| time);
+            }
+            note_track.built = true;
+        }
+
+        let lookahead_distance = lookahead_distance(&viewport, *direction, &judgement_line);
+        // During the lead-in audio_time sits at 0, so the remaining lead-in keeps notes scrolling in.
+        let millis_to_hit = |time: u64| time as i64 - audio_time.0 as i64 + lead_in.0 as i64;
+
+        for (&key, lane) in note_track.lanes.iter_mut() {
+            let lane_config = lane_layout.lane(key);
+            let shown_start = lane.cursor;
+            while lane.cursor < lane.notes.len() && millis_to_hit(lane.notes[lane.cursor].0) < 0 {
+                lane.cursor += 1;
+            }
+
+            let mut end = lane.cursor;
+            while let (Some(lane_config), Some(&(time, entity))) = (lane_config, lane.notes.get(end)) {
+                let millis_to_hit = millis_to_hit(time);
+                let distance = millis_to_hit as f64 * scroll_speed.0;
+                let visibility = match approach_time.visibility(millis_to_hit) {
+                    Some(visibility) if distance <= lookahead_distance => visibility,
+                    _ => break,
+                };
+                end += 1;
+
+                if bar_index_storage.get(entity).is_some() {
+                    rect_storage.remove(entity);
+                    circle_storage.remove(entity);
+                    continue;
+                }
+                let (x, y) = match lane_layout.point(*direction, &judgement_line, key, distance) {
+                    Some(point) => point,
+                    None => continue,
+                };
+                let shaped = match *shape {
+                    NoteShape::Square if rect_storage.get(entity).is_none() => {
+                        rect_storage.insert(entity, Rectangle { width: NOTE_SIZE, height: NOTE_SIZE }).map(|_| ())
+                    },
+                    NoteShape::Circle if circle_storage.get(entity).is_none() => {
+                        circle_storage.insert(entity, Circle { radius: NOTE_SIZE / 2.0 }).map(|_| ())
+                    },
+                    _ => Ok(()),
+                };
+                let placed = position_storage.insert(entity, Position { x, y }).map(|_| ())
+                    .and(shaped)
+                    .and_then(|()| {
+                        let color = note_color_storage.get(entity).map_or(lane_config.color, |note_color| note_color.0);
+                        color_storage.insert(entity, faded(color, visibility)).map(|_| ())
+                    });
+                if let Err(err) = placed {
+                    log::warn!("Couldn't place note: {:?}", err);
+                }
+            }
+
+            // Whatever was drawn last frame and isn't in view any more, at either end.
+            let hidden = (shown_start..lane.cursor.min(lane.shown_end)).chain(end..lane.shown_end);
+            for index in hidden {
+                let entity = lane.notes[index].1;
+                rect_storage.remove(entity);
+                circle_storage.remove(entity);
+            }
+            lane.shown_end = end;
+        }
+    }
+}
+
+// Draws a hold note's tail as a bar from its head up the lane to where it's released,
+// HoldDuration * ScrollSpeed long, scrolling with the head. Once the head is hit the tail is eaten
+// away at the judgement line as the hold goes on, and gone when it's over. A hold that's let go
+// early or missed keeps its tail, dimmed toward the background. Tails sit under the notes, and
+// appear and fade in along with their head within the ApproachTime.
+#[derive(Default)]
+pub(crate) struct HoldTailSystem {
+    tails: HashMap<Entity, Entity>,
+}
+
+impl<'a> System<'a> for HoldTailSystem {
+    type SystemData = (Entities<'a>,
+                       Read<'a, InterpolatedAudioTime>,
+                       Read<'a, AudioContext>,
+                       Read<'a, JudgementLine>,
+                       Read<'a, LaneLayout>,
+                       Read<'a, LeadIn>,
+                       Read<'a, ApproachTime>,
+                       Read<'a, ScrollSpeed>,
+                       Read<'a, ScrollDirection>,
+                       Read<'a, Viewport>,
+                       Read<'a, Theme>,
+                       ReadStorage<'a, TargetInput>,
+                       ReadStorage<'a, TargetBarTime>,
+                       ReadStorage<'a, HoldDuration>,
+                       ReadStorage<'a, BarIndex>,
+                       ReadStorage<'a, HoldTicks>,
+                       ReadStorage<'a, Judged>,
+                       ReadStorage<'a, NoteColor>,
+                       WriteStorage<'a, Position>,
+                       WriteStorage<'a, Rectangle>,
+                       WriteStorage<'a, Color>,
+                       WriteStorage<'a, Layer>);
+
+    fn run(&mut self, data: Self::SystemData) {
+        let (
+            entities,
+            audio_time,
+            audio_context,
+            judgement_line,
+            lane_layout,
+            lead_in,
+            approach_time,
+            scroll_speed,
+            direction,
+            viewport,
+            theme,
+            target_input_storage,
+            target_bar_time_storage,
+            hold_duration_storage,
+            bar_index_storage,
+            hold_ticks_storage,
+            judged_storage,
+            note_color_storage,
+            mut position_storage,
+            mut rect_storage,
+            mut color_storage,
+            mut layer_storage,
+        ) = data;
+
+        let lookahead_distance = lookahead_distance(&viewport, *direction, &judgement_line);
+        let mut drawn = HashSet::new();
+        for (head, input, target_bar_time, hold_duration) in (&*entities, &target_input_storage, &target_bar_time_storage, &hold_duration_storage).join() {
+            let lane = match lane_layout.lane(input.lane_key()) {
+                Some(lane) => lane,
+                None => continue,
+            };
+
+            // A hit head stays where it was due, its bar fixed by the hit, for the tail to run from.
+            let head_time = match bar_index_storage.get(head) {
+                Some(bar_index) => bar_index.0 * audio_context.bar_millis + target_bar_time.0,
+                None => audio_context.next_target_time(*target_bar_time, audio_time.0),
+            };
+            let head_millis = head_time as i64 - audio_time.0 as i64 + lead_in.0 as i64;
+            let start = head_millis.max(0) as f64 * scroll_speed.0;
+            let end = ((head_millis + hold_duration.0 as i64) as f64 * scroll_speed.0).min(lookahead_distance);
+            let visibility = match approach_time.visibility(head_millis) {
+                Some(visibility) if end > start => visibility,
+                _ => continue,
+            };
+
+            let (x, y) = match lane_layout.point(*direction, &judgement_line, input.lane_key(), (start + end) / 2.0) {
+                Some(point) => point,
+                None => continue,
+            };
+            let rect = match *direction {
+                ScrollDirection::Horizontal => Rectangle { width: end - start, height: HOLD_TAIL_WIDTH },
+                ScrollDirection::VerticalDown => Rectangle { width: HOLD_TAIL_WIDTH, height: end - start },
+            };
+            let broken = hold_ticks_storage.get(head).is_some_and(|hold_ticks| hold_ticks.released)
+                || judged_storage.get(head).is_some_and(|judged| judged.0 == Judgement::Miss);
+            let color = note_color_storage.get(head).map_or(lane.color, |note_color| note_color.0);
+            let color = if broken { Color::lerp(color, theme.background, HOLD_TAIL_DIM) } else { color };
+            let color = faded(color, visibility);
+
+            let tail = *self.tails.entry(head).or_insert_with(|| entities.create());
+            let placed = position_storage.insert(tail, Position { x, y }).map(|_| ())
+                .and_then(|()| rect_storage.insert(tail, rect).map(|_| ()))
+                .and_then(|()| color_storage.insert(tail, color).map(|_| ()))
+                .and_then(|()| layer_storage.insert(tail, RECEPTOR_LAYER).map(|_| ()));
+            if let Err(err) = placed {
+                log::warn!("Couldn't place hold tail: {:?}", err);
+            }
+            drawn.insert(head);
+        }
+
+        // Tails that are used up, off the screen or whose note is gone.
+        self.tails.retain(|head, &mut tail| {
+            if drawn.contains(head) {
+                return true;
+            }
+            if let Err(err) = entities.delete(tail) {
+                log::warn!("Couldn't remove hold tail: {:?}", err);
+            }
+            false
+        });
+    }
+}
+
+// Grows each unhit note from its normal size up to NOTE_POP_SCALE over the last NOTE_POP_MILLIS
+// before it's due. A note that's been hit, or has gone past and is waiting on its next bar, loses
+// its Scale again.
+pub(crate) struct NotePopSystem;
+
+impl<'a> System<'a> for NotePopSystem {
+    type SystemData = (Entities<'a>,
+                       Read<'a, InterpolatedAudioTime>,
+                       Read<'a, AudioContext>,
+                       Read<'a, LeadIn>,
+                       ReadStorage<'a, TargetBarTime>,
+                       ReadStorage<'a, BarIndex>,
+                       WriteStorage<'a, Scale>);
+
+    fn run(&mut self, data: Self::SystemData) {
+        let (entities, audio_time, audio_context, lead_in, target_bar_time_storage, bar_index_storage, mut scale_storage) = data;
+
+        for (entity, target_bar_time) in (&*entities, &target_bar_time_storage).join() {
+            let time_to_hit = audio_context.next_target_time(*target_bar_time, audio_time.0) - audio_time.0 + lead_in.0;
+            if bar_index_storage.get(entity).is_some() || time_to_hit >= NOTE_POP_MILLIS {
+                scale_storage.remove(entity);
+                continue;
+            }
+
+            let progress = 1.0 - time_to_hit as f64 / NOTE_POP_MILLIS as f64;
+            if let Err(err) = scale_storage.insert(entity, Scale(1.0 + (NOTE_POP_SCALE - 1.0) * progress)) {
+                log::warn!("Couldn't scale note: {:?}", err);
+            }
+        }
+    }
+}
+
+// Brightens the receptor of every held lane key and restores the lane color once it's released.
+// Purely cosmetic, hit detection never looks at receptors.
+pub(crate) struct ReceptorHighlightSystem;
+
+impl<'a> System<'a> for ReceptorHighlightSystem {
+    type SystemData = (Read<'a, HeldKeys>,
+                       Read<'a, LaneLayout>,
+                       ReadStorage<'a, Receptor>,
+                       WriteStorage<'a, Color>);
+
+    fn run(&mut self, data: Self::SystemData) {
+        let (held_keys, lane_layout, receptor_storage, mut color_storage) = data;
+
+        for (receptor, color) in (&receptor_storage, &mut color_storage).join() {
+            if let Some(lane) = lane_layout.lane(receptor.0) {
+                *color = if held_keys.0.contains(&receptor.0) {
+                    Color::lerp(lane.color, Color::rgb(255, 255, 255), RECEPTOR_HIGHLIGHT)
+                } else {
+                    lane.color
+                };
+            }
+        }
+    }
+}
+
+// How much bigger ReceptorPulseSystem draws the receptors at the start of each beat, 0.15 for 15%.
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(PartialEq)]
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct ReceptorPulse {
+    pub enabled: bool,
+    pub strength: f64,
+}
+
+impl Default for ReceptorPulse {
+    fn default() -> ReceptorPulse { ReceptorPulse { enabled: true, strength: 0.1 } }
+}
+
+// Swells every receptor at the start of each beat and lets it shrink back over the beat, straight
+// from the BeatPhase so it keeps time through seeks and rate changes. It only touches the Scale,
+// the held-key highlight has the Color to itself. Outside of a song, or turned off, the receptors
+// sit at their usual size.
+pub(crate) struct ReceptorPulseSystem;
+
+impl<'a> System<'a> for ReceptorPulseSystem {
+    type SystemData = (Read<'a, ReceptorPulse>,
+                       Read<'a, BeatPhase>,
+                       Read<'a, GameState>,
+                       ReadStorage<'a, Receptor>,
+                       WriteStorage<'a, Scale>);
+
+    fn run(&mut self, data: Self::SystemData) {
+        let (receptor_pulse, beat_phase, game_state, receptor_storage, mut scale_storage) = data;
+
+        let scale = if receptor_pulse.enabled && *game_state == GameState::Playing {
+            1.0 + receptor_pulse.strength.max(0.0) * (1.0 - beat_phase.beat)
+        } else {
+            1.0
+        };
+        for (_, receptor_scale) in (&receptor_storage, &mut scale_storage).join() {
+            receptor_scale.0 = scale;
+        }
+    }
+}
+
+// Whether KeyLegendSystem labels the receptors with their keys at the start of a song.
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(PartialEq)]
+#[derive(Serialize, Deserialize)]
+pub struct KeyLegend(pub bool);
+
+impl Default for KeyLegend {
+    fn default() -> KeyLegend { KeyLegend(true) }
+}
+
+// Every key that plays `lane`, sorted by name: those KeyBindings maps to it, and the lane key
+// itself unless it's been bound to another lane.
+pub fn legend_label(lane: Keycode, key_bindings: &KeyBindings) -> String {
+    let mut names: Vec<String> = key_bindings.0.iter()
+        .filter(|&(&pressed, &bound)| bound == lane && pressed != lane)
+        .map(|(pressed, _)| pressed.name())
+        .collect();
+    if key_bindings.lane_key(lane) == lane {
+        names.push(lane.name());
+    }
+    names.sort();
+    names.join(" / ")
+}
+
+// Labels each receptor with the keys that play its lane while the song starts, beside the receptor
+// on the side notes don't come from. The labels are rebuilt from KeyBindings every frame, so they
+// follow any change to the bindings, and removed once the legend's time is up or it's turned off.
+#[derive(Default)]
+pub(crate) struct KeyLegendSystem {
+    labels: HashMap<Keycode, Entity>,
+}
+
+impl<'a> System<'a> for KeyLegendSystem {
+    type SystemData = (Entities<'a>,
+                       Read<'a, SongTime>,
+                       Read<'a, GameState>,
+                       Read<'a, KeyLegend>,
+                       Read<'a, KeyBindings>,
+                       Read<'a, LaneLayout>,
+                       Read<'a, ScrollDirection>,
+                       Read<'a, JudgementLine>,
+                       Read<'a, Theme>,
+                       WriteStorage<'a, Text>,
+                       WriteStorage<'a, Position>,
+                       WriteStorage<'a, Color>);
+
+    fn run(&mut self, data: Self::SystemData) {
+        let (
+            entities,
+            song_time,
+            game_state,
+            key_legend,
+            key_bindings,
+            lane_layout,
+            direction,
+            judgement_line,
+            theme,
+            mut text_storage,
+            mut position_storage,
+            mut color_storage,
+        ) = data;
+
+        let in_song = *game_state == GameState::Playing || *game_state == GameState::Paused;
+        let shown = key_legend.0 && in_song && song_time.0 < KEY_LEGEND_MILLIS;
+        // A lane that's gone loses its label along with the rest when the legend is hidden.
+        self.labels.retain(|&key, &mut entity| {
+            let keep = shown && lane_layout.lane(key).is_some();
+            if !keep {
+                if let Err(err) = entities.delete(entity) {
+                    log::warn!("Couldn't remove key legend: {:?}", err);
+                }
+            }
+            keep
+        });
+        if !shown {
+            return;
+        }
+
+        for lane in &lane_layout.0 {
+            let (x, y) = match lane_layout.point(*direction, &judgement_line, lane.key, 0.0) {
+                Some(point) => point,
+                None => continue,
+            };
+            let position = match *direction {
+                ScrollDirection::Horizontal => Position { x: 10.0, y: y - NOTE_SIZE / 2.0 },
+                ScrollDirection::VerticalDown => Position { x: x - NOTE_SIZE, y: y + RECEPTOR_SIZE },
+            };
+            let entity = *self.labels.entry(lane.key).or_insert_with(|| entities.create());
+
+            if let Err(err) = position_storage.insert(entity, position) {
+                log::warn!("Couldn't place key legend: {:?}", err);
+            }
+            if let Err(err) = color_storage.insert(entity, theme.text) {
+                log::warn!("Couldn't color key legend: {:?}", err);
+            }
+            if let Err(err) = text_storage.insert(entity, Text(legend_label(lane.key, &key_bindings))) {
+                log::warn!("Couldn't label key legend: {:?}", err);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use specs::RunNow;
+
+    use std::{
+        alloc::{GlobalAlloc, Layout},
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Instant,
+    };
+
+    // Counts every allocation in the test binary, for the bench below to read around its frames.
+    struct CountingAllocator;
+
+    static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+    unsafe impl GlobalAlloc for CountingAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+            std::alloc::System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            std::alloc::System.dealloc(ptr, layout)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+    // Notes `gap` ms apart from `gap` on, going round the four lanes.
+    fn note_world(notes: u64, gap: u64) -> World {
+        let mut world = World::new();
+        System::setup(&mut NoteRenderSystem, &mut world.res);
+        let keys = LaneCount::default().lane_keys();
+        for note in 0..notes {
+            world.create_entity()
+                .with(TargetInput(vec![keys[note as usize % keys.len()]]))
+                .with(TargetBarTime((note + 1) * gap))
+                .build();
+        }
+        world
+    }
+
+    #[test]
+    fn every_lane_count_has_a_binding_for_each_lane() {
+        for count in 1..=MAX_LANES {
+            let lane_count = LaneCount::new(count);
+            let bindings = lane_count.default_key_bindings();
+            assert_eq!(bindings.len(), count as usize, "{} lanes", count);
+            let mut bound: Vec<Keycode> = bindings.values().cloned().collect();
+            let mut lanes = lane_count.lane_keys();
+            bound.sort_by_key(|key| *key as i32);
+            lanes.sort_by_key(|key| *key as i32);
+            assert_eq!(bound, lanes, "{} lanes", count);
+        }
+
+        let four = LaneCount(4).default_key_bindings();
+        let dfjk: Vec<Option<&Keycode>> = [Keycode::D, Keycode::F, Keycode::J, Keycode::K].iter().map(|key| four.get(key)).collect();
+        assert_eq!(dfjk, vec![Some(&Keycode::Left), Some(&Keycode::Down), Some(&Keycode::Up), Some(&Keycode::Right)]);
+        assert_eq!(LaneCount(7).default_key_bindings().get(&Keycode::Space), Some(&Keycode::Space));
+    }
+
+    #[test]
+    fn notes_are_never_drawn_past_the_line() {
+        let world = note_world(40, 125);
+        let mut drawn = 0;
+        // 7ms frames, so notes are caught a few ms either side of their line.
+        for frame in 0..(41 * 125 / 7) {
+            let now = frame * 7;
+            world.write_resource::<InterpolatedAudioTime>().0 = now;
+            NoteRenderSystem.run_now(&world.res);
+            let (target_bar_time_storage, rect_storage) = (world.read_storage::<TargetBarTime>(), world.read_storage::<Rectangle>());
+            for (target_bar_time, _) in (&target_bar_time_storage, &rect_storage).join() {
+                assert!(target_bar_time.0 >= now, "note at {}ms drawn at {}ms", target_bar_time.0, now);
+                drawn += 1;
+            }
+        }
+        assert!(drawn > 0);
+        assert_eq!((&world.read_storage::<Rectangle>()).join().count(), 0);
+    }
+
+    #[test]
+    #[ignore]
+    fn bench_note_rendering_over_a_long_chart() {
+        const NOTES: u64 = 2000;
+        const FRAME_MILLIS: u64 = 16;
+        let world = note_world(NOTES, 125);
+        let frames = (NOTES + 1) * 125 / FRAME_MILLIS;
+
+        let mut most_shown = 0;
+        let start = Instant::now();
+        let allocations_before = ALLOCATIONS.load(Ordering::Relaxed);
+        for frame in 0..frames {
+            world.write_resource::<InterpolatedAudioTime>().0 = frame * FRAME_MILLIS;
+            NoteRenderSystem.run_now(&world.res);
+            most_shown = most_shown.max((&world.read_storage::<Rectangle>()).join().count());
+        }
+        let allocations = ALLOCATIONS.load(Ordering::Relaxed) - allocations_before;
+        let elapsed = start.elapsed();
+
+        // Building the track and growing the storages toward the last note's id are all that
+        // allocate, nowhere near once a frame.
+        assert!(most_shown > 0 && most_shown < 50, "{} notes drawn at once", most_shown);
+        assert!(allocations < frames as usize / 100, "{} allocations over {} frames", allocations, frames);
+        println!(
+            "{} frames over {} notes: {:?} a frame, {} allocations, at most {} notes drawn",
+            frames, NOTES, elapsed / frames as u32, allocations, most_shown,
+        );
+    }
+}
@@ -1,46 +1,302 @@
 use rodio::{
-    Decoder,
     Device,
     Sink,
+    Source,
 };
 
-use sdl2::keyboard::Keycode;
+use sdl2::{
+    keyboard::Keycode,
+    render::BlendMode,
+};
 
 use specs::prelude::*;
 
 use std::{
     fs::File,
-    io::BufReader,
+    path::{Path, PathBuf},
     sync::atomic::Ordering,
+    thread,
+    time::{Duration, Instant},
     vec::Vec,
 };
 
+mod assets;
+use crate::assets::Assets;
+
+mod audio;
+use crate::audio::{
+    fade_out,
+    HitSoundSystem,
+    SfxOffset,
+    SfxPlayer,
+    SpatialSfx,
+    Volume,
+    VolumeSystem,
+    FADE_OUT_MILLIS,
+    SFX_POOL_SIZE,
+    VOLUME_STEP,
+};
+
+mod autoplay;
+use crate::autoplay::{
+    Autoplay,
+    AutoplaySystem,
+};
+
 mod render;
 use crate::render::{
-    Color,
+    ApproachRing,
+    Background,
+    Circle,
     ClearColor,
+    Color,
+    JudgementLine,
+    Layer,
     Position,
     Rectangle,
+    Scale,
+    Text,
+    Viewport,
+    FLASH_LAYER,
 };
 
 //mod rodio_ext;
 
+mod debug;
+use crate::debug::DebugOverlaySystem;
+
+mod combo;
+use crate::combo::{
+    ComboEventSystem,
+    ComboEvents,
+};
+
+mod countdown;
+use crate::countdown::{
+    CountdownSystem,
+    LeadIn,
+    DEFAULT_LEAD_IN_MILLIS,
+};
+
+mod error;
+use crate::error::{
+    sdl_error,
+    GameError,
+};
+
+mod draw;
+use crate::draw::{
+    DrawList,
+    DrawStats,
+    RenderingSystem,
+    snapshot_frame,
+};
+
+mod effects;
+use crate::effects::{
+    BeatFlashMode,
+    BeatFlashSystem,
+    BeatPhaseSystem,
+    BeatPulse,
+    ColorblindMode,
+    HitFlash,
+    HitFlashMarker,
+    HitFlashSystem,
+    HitTickSystem,
+    JudgementLabelSystem,
+};
+
+mod grid;
+use crate::grid::BeatGridSystem;
+
+mod health;
+use crate::health::{
+    spawn_health_bar,
+    Health,
+    HealthBar,
+    HealthDrainMode,
+    HealthSystem,
+};
+
+mod histogram;
+use crate::histogram::{HistogramRenderSystem, OffsetHistogram};
+
+mod lanes;
+use crate::lanes::{
+    spawn_receptors,
+    ApproachTime,
+    HoldTailSystem,
+    KeyLegend,
+    KeyLegendSystem,
+    LaneCount,
+    LaneLayout,
+    NoteColor,
+    NotePopSystem,
+    NoteRenderSystem,
+    NoteShape,
+    NoteTrack,
+    Receptor,
+    ReceptorHighlightSystem,
+    ReceptorPulse,
+    ReceptorPulseSystem,
+    ScrollDirection,
+    ScrollSpeed,
+    SCROLL_SPEED_STEP,
+};
+
+mod chart;
+use crate::chart::{
+    Chart,
+    ChartError,
+};
+
+mod calibration;
+use crate::calibration::{
+    GlobalOffset,
+    InputLatency,
+    LatencyCalibration,
+    LatencyCalibrationSystem,
+    GLOBAL_OFFSET_STEP,
+};
+
+mod results;
+use crate::results::{
+    Results,
+    ResultsExport,
+    ResultsSystem,
+    note_results,
+};
+
 mod rhythm;
 use crate::rhythm::{
+    ApproachRingSystem,
     AudioContext,
     BarIndex,
     BarIndexTaggingSystem,
+    BeatPhase,
+    ChordDiagnostics,
+    ChordGroup,
+    Combo,
+    ComboBreaks,
+    ComboGrace,
+    HitOffset,
+    HitWindow,
+    HoldDuration,
+    HoldTickCount,
+    HoldTickInterval,
+    HoldTickSystem,
+    HoldTicks,
+    InputTimeline,
+    Judged,
+    Judgement,
+    JudgementHistory,
+    JudgementWindows,
+    LaneIndex,
+    LastHitError,
+    LastHitKey,
+    LastHitNote,
+    NoteIndex,
+    PendingChords,
     RhythmCombo,
+    SingleKeyMode,
+    SongLength,
     TargetBarTime,
+    TimingFilter,
+    JUDGEMENT_WINDOW_STEP,
+};
+
+mod playlist;
+use crate::playlist::{
+    Playlist,
+    PlaylistSystem,
+};
+
+mod progress;
+use crate::progress::{
+    spawn_progress_bar,
+    ProgressBar,
+    ProgressBarSystem,
+};
+
+mod replay;
+use crate::replay::{
+    ReplayPlayer,
+    ReplayRecordSystem,
+    ReplayRecorder,
+    ReplaySystem,
+};
+
+mod score;
+use crate::score::{
+    Score,
+    ScoreWeights,
+    ScoringSystem,
+};
+
+mod session;
+use crate::session::{
+    verify_replay,
+    Divergence,
+    Session,
 };
 
+mod menu;
+use crate::menu::{
+    scan_charts,
+    MenuState,
+    MenuSystem,
+    CHARTS_DIR,
+};
+
+mod simulator;
+
+mod settings;
+use crate::settings::{
+    KeyBindings,
+    Settings,
+    SETTINGS_PATH,
+};
+
+mod stats;
+use crate::stats::{
+    Nps,
+    NpsSystem,
+};
+
+mod state;
+use crate::state::{
+    spawn_scene_text,
+    GameState,
+    Scene,
+    StateTransitionSystem,
+};
+
+mod song;
+use crate::song::{
+    analyze_offset,
+    open_audio,
+    PlaybackRate,
+    SeekBase,
+    SeekRequest,
+    SongConfig,
+    SongEnd,
+    SongEndSystem,
+};
+
+mod theme;
+
 mod sdl;
 use crate::sdl::{
+    window_position,
+    ClockOffset,
+    HeldKeys,
     InputEvent,
     InputEvents,
-    RenderingSystem,
-    SdlRects,
+    SdlInputSystem,
     SdlSystem,
+    WindowCommand,
+    WindowCommands,
+    WindowState,
 };
 
 #[derive(Default)]
@@ -52,167 +308,1202 @@ struct IsRunning(bool);
 #[derive(Default)]
 struct AudioTime(u64);
 
-struct OmniSystem;
+// audio_time without its floor at zero: negative through the lead-in, which it counts down in wall
+// clock milliseconds whatever the playback rate, and before the first beat. Presses are stamped and judged against this, so a note
+// at the very start of a chart can still be hit early.
+#[derive(Default)]
+struct SongTime(i64);
 
-impl<'a> System<'a> for OmniSystem {
-    type SystemData = (Read<'a, InputEvents>,
-                       Write<'a, IsRunning>,
-                       Write<'a, ClearColor>,
-                       Write<'a, AudioTime>,
-                       Write<'a, DebugFlag>,
+#[derive(Default)]
+struct InterpolatedAudioTime(u64);
+
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(PartialEq)]
+#[derive(Default)]
+enum TimeSource {
+    #[default]
+    Audio,
+    Wallclock,
+    Manual,
+}
+
+// Samples a second the output device takes across all its channels, read once at startup. The
+// sink's sample counter over this is how far into the song it's played.
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(Copy)]
+struct OutputRate(u32);
+
+// Milliseconds since startup, summed from frame times. Drives audio_time in silent mode.
+#[derive(Default)]
+struct WallClock(f64);
+
+// Milliseconds since the last update step, or since the last frame for the systems that run once
+// a frame, see the main loop.
+#[derive(Default)]
+struct FrameTime(f64);
+
+// How many milliseconds of wall time the current update step ends before now, see FixedTimestep.
+#[derive(Default)]
+struct StepLag(f64);
+
+const UPDATE_HZ: u32 = 240;
+const UPDATE_STEP_MILLIS: f64 = 1000.0 / UPDATE_HZ as f64;
+const MAX_UPDATE_STEPS: u32 = 8;
+
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(Copy)]
+struct UpdateStep {
+    millis: f64,
+    lag: f64,
+}
+
+// Cuts the time frames take into UPDATE_HZ update steps, carrying whatever is short of a whole step
+// over to the next frame. A frame always ends with the updates lagging it by that remainder, under
+// one step. Past MAX_UPDATE_STEPS in a frame, after a stall or on a machine that can't keep up, the
+// last step takes the rest of the time at once rather than dropping it, so no clock falls behind.
+#[derive(Default)]
+struct FixedTimestep {
+    accumulator: f64,
+}
+
+impl FixedTimestep {
+    // The steps a frame of `frame_millis` covers, oldest first.
+    fn advance(&mut self, frame_millis: f64) -> impl Iterator<Item = UpdateStep> {
+        self.accumulator += frame_millis;
+        let due = (self.accumulator / UPDATE_STEP_MILLIS).floor() as u32;
+        self.accumulator -= due as f64 * UPDATE_STEP_MILLIS;
+        let steps = due.min(MAX_UPDATE_STEPS);
+        let last_millis = (due + 1).saturating_sub(steps) as f64 * UPDATE_STEP_MILLIS;
+        let remainder = self.accumulator;
+        (0..steps).map(move |index| {
+            let later = steps - 1 - index;
+            UpdateStep {
+                millis: if later == 0 { last_millis } else { UPDATE_STEP_MILLIS },
+                lag: remainder + if later == 0 { 0.0 } else { (later - 1) as f64 * UPDATE_STEP_MILLIS + last_millis },
+            }
+        })
+    }
+
+    // How far behind now the last step to run is, whether or not any ran this frame.
+    fn lag(&self) -> f64 {
+        self.accumulator
+    }
+}
+
+fn duration_millis(duration: Duration) -> f64 {
+    duration.as_secs() as f64 * 1000.0 + duration.subsec_nanos() as f64 / 1_000_000.0
+}
+
+// The sink's sample counter only advances when the audio thread pulls a new buffer, so between
+// reads we extrapolate with wall-clock frame time. The extrapolation is capped at the last step
+// the real counter took so it can't run ahead of the next sample read by more than a frame.
+#[derive(Default)]
+struct AudioTimeInterpolator {
+    last_sample: u64,
+    last_step: u64,
+    since_sample: f64,
+}
+
+impl AudioTimeInterpolator {
+    fn update(&mut self, sampled: u64, frame_millis: f64) -> u64 {
+        if sampled != self.last_sample {
+            self.last_step = sampled.saturating_sub(self.last_sample);
+            self.last_sample = sampled;
+            self.since_sample = 0.0;
+        } else {
+            self.since_sample += frame_millis;
+        }
+
+        sampled + (self.since_sample as u64).min(self.last_step)
+    }
+}
+
+// What FrameLimiter holds the main loop to while vsync is off, 0 for as fast as it'll go. With
+// vsync on the display sets the pace instead, see VsyncEnabled.
+struct TargetFps(pub u32);
+
+impl Default for TargetFps {
+    fn default() -> TargetFps { TargetFps(60) }
+}
+
+struct FrameLimiter {
+    frame_start: Instant,
+}
+
+impl FrameLimiter {
+    fn new() -> FrameLimiter {
+        FrameLimiter { frame_start: Instant::now() }
+    }
+
+    // Sleeps out the rest of the frame. The next frame is scheduled from this frame's deadline
+    // rather than from when we woke up, so oversleeping doesn't accumulate into drift.
+    fn wait(&mut self, target_fps: u32) {
+        if target_fps == 0 {
+            self.frame_start = Instant::now();
+            return;
+        }
+
+        let frame_duration = Duration::from_secs(1) / target_fps;
+        let deadline = self.frame_start + frame_duration;
+        let now = Instant::now();
+        if now < deadline {
+            thread::sleep(deadline - now);
+        }
+
+        let now = Instant::now();
+        self.frame_start = if now > deadline + frame_duration { now } else { deadline };
+    }
+}
+
+// Set by tests and the headless tools to drive audio_time explicitly.
+#[derive(Default)]
+struct ManualClock(pub u64);
+
+// Reads whichever clock the TimeSource selects into audio_time, ahead of anything that judges or
+// draws against it. LeadIn is counted down in whole ms, with the fraction of a step left over
+// carried to the next, so a 240Hz step's 4.17ms doesn't round to 4 and stretch the lead-in.
+#[derive(Default)]
+struct AudioClockSystem {
+    lead_in_remainder: f64,
+}
+
+impl<'a> System<'a> for AudioClockSystem {
+    type SystemData = (Write<'a, AudioTime>,
+                       Write<'a, SongTime>,
+                       Write<'a, InterpolatedAudioTime>,
+                       Write<'a, AudioTimeInterpolator>,
+                       Write<'a, WallClock>,
+                       Write<'a, LeadIn>,
+                       Read<'a, GameState>,
+                       Read<'a, ManualClock>,
+                       Read<'a, TimeSource>,
+                       Read<'a, FrameTime>,
                        Read<'a, AudioContext>,
-                       Option<Read<'a, Device>>,
+                       Read<'a, PlaybackRate>,
+                       Read<'a, SeekBase>,
+                       Read<'a, StepLag>,
+                       Option<Read<'a, OutputRate>>,
                        Option<Read<'a, Sink>>);
 
     fn run(&mut self, data: Self::SystemData) {
         let (
-            input_events,
-            mut is_running,
-            mut clear_color,
             mut audio_time,
-            mut debug_flag,
+            mut song_time,
+            mut interpolated_audio_time,
+            mut audio_time_interpolator,
+            mut wall_clock,
+            mut lead_in,
+            game_state,
+            manual_clock,
+            time_source,
+            frame_time,
             audio_context,
-            maybe_device,
+            playback_rate,
+            seek_base,
+            step_lag,
+            maybe_output_rate,
             maybe_sink,
         ) = data;
 
-        if let (Some(device), Some(sink)) = (maybe_device, maybe_sink) {
-            let format = device.default_output_format().expect("Couldn't get default output format");
-            let samples_per_sec = format.channels as u32 * format.sample_rate.0;
-
-            let samples = sink.samples_written.load(Ordering::Relaxed);
-            let sample_time = samples as f64 / samples_per_sec as f64 - audio_context.first_beat_offset as f64 / 1000.0;
-            let beat_time = sample_time * (audio_context.milli_bpm / 1000) as f64 / 60.0;
+        // The sink only plays and the wall clock only runs while playing past the lead-in, so the
+        // two stay lined up across pauses.
+        let playing = *game_state == GameState::Playing;
+        if playing && lead_in.0 > 0 {
+            let elapsed = frame_time.0 + self.lead_in_remainder;
+            lead_in.0 = lead_in.0.saturating_sub(elapsed.floor() as u64);
+            self.lead_in_remainder = if lead_in.0 > 0 { elapsed.fract() } else { 0.0 };
+        } else if playing {
+            wall_clock.0 += frame_time.0;
+        }
+        if let Some(sink) = &maybe_sink {
+            let audible = playing && lead_in.0 == 0;
+            if audible && sink.is_paused() {
+                sink.play();
+            } else if !audible && !sink.is_paused() {
+                sink.pause();
+            }
+        }
 
-            let color = ((1.0 - beat_time.fract()).powi(2) * 128.0) as u8;
-            let color = 255 - color;
-            clear_color.0 = Color::rgb(color, color, color);
+        // The sink and the wall clock both count wall time since the last seek; the song moves
+        // through it at the playback rate. The manual clock is already in song time. The sink is
+        // read as it is now, so it's put back by StepLag to where this update step ends, while
+        // the wall clock only ever advances by whole steps and is there already.
+        let rate = playback_rate.0 as f64;
+        let seek_base = seek_base.0 as f64 / 1000.0;
+        let source_time = match (*time_source, maybe_output_rate, maybe_sink) {
+            (TimeSource::Audio, Some(output_rate), Some(sink)) => {
+                let samples = sink.samples_written.load(Ordering::Relaxed);
+                Some(seek_base + (samples as f64 / output_rate.0.max(1) as f64 - step_lag.0 / 1000.0) * rate)
+            },
+            (TimeSource::Wallclock, _, _) => Some(seek_base + wall_clock.0 / 1000.0 * rate),
+            (TimeSource::Manual, _, _) => {
+                audio_time.0 = manual_clock.0;
+                None
+            },
+            _ => None,
+        };
 
+        let mut unfloored = audio_time.0 as i64;
+        if let Some(source_time) = source_time {
+            let sample_time = source_time - audio_context.first_beat_offset as f64 / 1000.0;
             audio_time.0 = (sample_time * 1000.0) as u64;
+            unfloored = (sample_time * 1000.0) as i64;
         }
+        song_time.0 = unfloored - lead_in.0 as i64;
+        interpolated_audio_time.0 = audio_time_interpolator.update(audio_time.0, frame_time.0 * rate);
+    }
+}
+
+struct OmniSystem;
+
+impl<'a> System<'a> for OmniSystem {
+    type SystemData = (Read<'a, InputEvents>,
+                       Write<'a, DebugFlag>,
+                       Write<'a, WindowCommands>,
+                       Write<'a, Volume>,
+                       Write<'a, ScrollSpeed>,
+                       Write<'a, GlobalOffset>,
+                       Write<'a, SeekRequest>,
+                       Write<'a, ResultsExport>,
+                       Read<'a, HitWindow>,
+                       Write<'a, JudgementWindows>);
 
-        debug_flag.0 = false;
-        for event in &input_events.0 {
+    fn run(&mut self, data: Self::SystemData) {
+        let (
+            input_events,
+            mut debug_flag,
+            mut window_commands,
+            mut volume,
+            mut scroll_speed,
+            mut global_offset,
+            mut seek_request,
+            mut results_export,
+            hit_window,
+            mut judgement_windows,
+        ) = data;
+
+        for event in &input_events.events {
+            // With the debug overlay up the keypad tunes the judgement windows, the bottom row
+            // narrowing Perfect, Great and Good, the row above widening them.
+            if debug_flag.0 {
+                let nudge = match event.keycode {
+                    Some(Keycode::Kp1) => Some((Judgement::Perfect, -JUDGEMENT_WINDOW_STEP)),
+                    Some(Keycode::Kp2) => Some((Judgement::Great, -JUDGEMENT_WINDOW_STEP)),
+                    Some(Keycode::Kp3) => Some((Judgement::Good, -JUDGEMENT_WINDOW_STEP)),
+                    Some(Keycode::Kp4) => Some((Judgement::Perfect, JUDGEMENT_WINDOW_STEP)),
+                    Some(Keycode::Kp5) => Some((Judgement::Great, JUDGEMENT_WINDOW_STEP)),
+                    Some(Keycode::Kp6) => Some((Judgement::Good, JUDGEMENT_WINDOW_STEP)),
+                    _ => None,
+                };
+                if let Some((judgement, delta)) = nudge {
+                    judgement_windows.nudge(judgement, delta, hit_window.widest());
+                    continue;
+                }
+            }
             match *event {
-                InputEvent { keycode: Some(Keycode::Escape), .. } => {
-                    is_running.0 = false;
+                InputEvent { keycode: Some(Keycode::F11), .. } => {
+                    window_commands.0.push(WindowCommand::ToggleFullscreen);
+                },
+                InputEvent { keycode: Some(Keycode::Equals), .. } |
+                InputEvent { keycode: Some(Keycode::KpPlus), .. } => {
+                    volume.nudge(VOLUME_STEP);
+                },
+                InputEvent { keycode: Some(Keycode::Minus), .. } |
+                InputEvent { keycode: Some(Keycode::KpMinus), .. } => {
+                    volume.nudge(-VOLUME_STEP);
+                },
+                InputEvent { keycode: Some(Keycode::F3), .. } => {
+                    scroll_speed.nudge(-SCROLL_SPEED_STEP);
+                },
+                InputEvent { keycode: Some(Keycode::F4), .. } => {
+                    scroll_speed.nudge(SCROLL_SPEED_STEP);
+                },
+                InputEvent { keycode: Some(Keycode::LeftBracket), .. } => {
+                    global_offset.nudge(-GLOBAL_OFFSET_STEP);
                 },
-                InputEvent { keycode: Some(Keycode::Backquote), timestamp } => {
-                    debug_flag.0 = true;
-
-                    if audio_time.0 > timestamp as u64 {
-                        dbg!(audio_time.0 - timestamp as u64);
-                    } else {
-                        dbg!(("-", timestamp as u64 - audio_time.0));
-                    }
+                InputEvent { keycode: Some(Keycode::RightBracket), .. } => {
+                    global_offset.nudge(GLOBAL_OFFSET_STEP);
                 },
+                InputEvent { keycode: Some(Keycode::Backquote), .. } => {
+                    debug_flag.0 = !debug_flag.0;
+                },
+                // 1 to 4 jump to the start, a quarter, half and three quarters of the way in.
+                InputEvent { keycode: Some(Keycode::Num1), .. } => seek_request.0 = Some(0.0),
+                InputEvent { keycode: Some(Keycode::Num2), .. } => seek_request.0 = Some(0.25),
+                InputEvent { keycode: Some(Keycode::Num3), .. } => seek_request.0 = Some(0.5),
+                InputEvent { keycode: Some(Keycode::Num4), .. } => seek_request.0 = Some(0.75),
+                InputEvent { keycode: Some(Keycode::F5), .. } => results_export.requested = true,
                 _ => {},
             }
         }
     }
 }
 
+// The keys that hit a target, any one of them will do. Never empty: the first is the target's own
+// lane, where it's drawn and where its hits show up, the rest are alternates that needn't have a
+// lane at all.
 #[derive(Debug)]
 #[derive(Clone)]
-#[derive(Copy)]
-struct TargetTime(u64);
+struct TargetInput(Vec<Keycode>);
 
-impl Component for TargetTime {
-    type Storage = VecStorage<Self>;
+impl TargetInput {
+    fn lane_key(&self) -> Keycode {
+        self.0[0]
+    }
 }
 
-#[derive(Debug)]
-#[derive(Clone)]
-#[derive(Copy)]
-struct TargetInput(Keycode);
-
 impl Component for TargetInput {
     type Storage = VecStorage<Self>;
 }
 
+// Whether `flag` was passed on the command line.
+fn has_flag(flag: &str) -> bool {
+    std::env::args().any(|arg| arg == flag)
+}
+
+// The argument following `flag`, for options of the form `--flag <value>`.
+fn arg_value(flag: &str) -> Option<String> {
+    std::env::args().skip_while(|arg| arg != flag).nth(1)
+}
+
+fn import_session(path: &str) -> Result<(), GameError> {
+    match verify_replay(path) {
+        Ok(()) => println!("Session reproduced"),
+        Err(Divergence::Load(err)) => return Err(GameError::Chart { path: path.to_string(), err }),
+        Err(divergence) => return Err(GameError::Divergence(divergence)),
+    }
+    Ok(())
+}
+
+// Loads a chart and its notes into the world and queues its audio on the sink, which stays paused
+// until AudioClockSystem sees play start. Called again for each song of a playlist, after
+// clear_song has removed the last one.
+fn load_song(world: &mut World, chart_path: &Path) -> Result<Chart, GameError> {
+    let chart_error = |err| GameError::Chart { path: chart_path.display().to_string(), err };
+    let mut chart = Chart::load(chart_path).map_err(chart_error)?;
+
+    // `--quantize <n>` snaps notes to the nearest 1/n of a beat.
+    if let Some(division) = arg_value("--quantize").and_then(|division| division.parse().ok()) {
+        chart.quantize(division);
+    }
+
+    {
+        let lane_layout = world.read_resource::<LaneLayout>();
+        lane_layout.validate_count(chart.notes.iter().map(|note| note.keys[0]))
+            .map_err(|used| chart_error(ChartError::TooManyLanes { used, count: lane_layout.0.len() }))?;
+        lane_layout.validate(chart.notes.iter().map(|note| note.keys[0]))
+            .map_err(|key| chart_error(ChartError::UnmappedKey(key)))?;
+    }
+
+    let audio_path = chart.metadata.audio_path.clone();
+    let source = open_audio(&audio_path).map_err(GameError::Song)?;
+
+    let song_config = SongConfig {
+        auto_offset: chart.metadata.auto_offset,
+        offset_override: arg_value("--offset").and_then(|offset| offset.parse().ok()),
+    };
+    chart.metadata.first_beat_offset = song_config.first_beat_offset(chart.metadata.first_beat_offset, || {
+        // The playback decoder can't be rewound, so the analysis reads its own copy of the file.
+        let analyzed = open_audio(&audio_path).ok().map_or(0, analyze_offset);
+        log::info!("Detected first beat offset of {}ms", analyzed);
+        analyzed
+    });
+
+    let song_length = source.total_duration().map(|duration| duration_millis(duration) as u64).unwrap_or(0);
+    for warning in chart.validate(&chart.audio_context(), song_length) {
+        log::warn!("{}: {}", chart_path.display(), warning);
+    }
+    *world.write_resource::<SongLength>() = SongLength(song_length);
+    let playback_rate = *world.read_resource::<PlaybackRate>();
+    if let Some(sink) = world.res.try_fetch::<Sink>() {
+        sink.append(source.speed(playback_rate.0));
+    }
+
+    *world.write_resource::<AudioContext>() = chart.audio_context();
+    let hit_window = chart.hit_window(HitWindow::default());
+    *world.write_resource::<HitWindow>() = hit_window;
+    // Without windows of its own a chart's `good` is its whole HitWindow.
+    *world.write_resource::<JudgementWindows>() = chart.judgement_windows(JudgementWindows {
+        good: hit_window.widest(),
+        ..JudgementWindows::default()
+    });
+    // The tagging and note rendering systems index lanes on their first run, which may have been on
+    // an empty menu.
+    *world.write_resource::<LaneIndex>() = LaneIndex::default();
+    *world.write_resource::<NoteTrack>() = NoteTrack::default();
+    chart.spawn_notes(world);
+    Ok(chart)
+}
+
+// Clears what the last song left behind before a playlist loads the next one: its notes, its
+// results lines and the per-song state. The sink is replaced rather than reused, its sample
+// counter is the audio clock and has to start again from zero. JudgementHistory is kept, systems
+// track how far into it they've read.
+fn clear_song(world: &mut World, lead_in_millis: u64) {
+    let stale: Vec<Entity> = {
+        let entities = world.entities();
+        let target_storage = world.read_storage::<TargetBarTime>();
+        let scene_storage = world.read_storage::<Scene>();
+        (&entities, target_storage.maybe(), scene_storage.maybe()).join()
+            .filter(|&(_, target, scene)| target.is_some() || scene.is_some_and(|scene| scene.0 == GameState::Results))
+            .map(|(entity, _, _)| entity)
+            .collect()
+    };
+    if let Err(err) = world.delete_entities(&stale) {
+        log::warn!("Couldn't clear the last song: {:?}", err);
+    }
+    world.maintain();
+
+    let volume = *world.read_resource::<Volume>();
+    let sink = world.res.try_fetch::<Device>().map(|device| Sink::new(&device));
+    if let Some(sink) = sink {
+        sink.set_volume(volume.0);
+        sink.pause();
+        // Dropping the old sink stops whatever is left of the last song.
+        world.add_resource(sink);
+    }
+
+    *world.write_resource::<AudioTime>() = AudioTime(0);
+    *world.write_resource::<SongTime>() = SongTime(0);
+    *world.write_resource::<InterpolatedAudioTime>() = InterpolatedAudioTime(0);
+    *world.write_resource::<AudioTimeInterpolator>() = AudioTimeInterpolator::default();
+    *world.write_resource::<WallClock>() = WallClock::default();
+    *world.write_resource::<SeekBase>() = SeekBase::default();
+    *world.write_resource::<LeadIn>() = LeadIn(lead_in_millis);
+    *world.write_resource::<ClockOffset>() = ClockOffset::default();
+    *world.write_resource::<PendingChords>() = PendingChords::default();
+    *world.write_resource::<LastHitError>() = LastHitError::default();
+    *world.write_resource::<LastHitKey>() = LastHitKey::default();
+    *world.write_resource::<LastHitNote>() = LastHitNote::default();
+    *world.write_resource::<InputTimeline>() = InputTimeline::default();
+    *world.write_resource::<SongEnd>() = SongEnd::default();
+    *world.write_resource::<Results>() = Results::default();
+    world.write_resource::<ResultsExport>().written = false;
+    *world.write_resource::<OffsetHistogram>() = OffsetHistogram::default();
+    *world.write_resource::<Combo>() = Combo::default();
+    *world.write_resource::<Score>() = Score::default();
+    *world.write_resource::<Health>() = Health::default();
+}
+
+// A Sink can't seek, so a seek decodes the song again from the start and skips to the target on a
+// fresh sink. Skipping still decodes everything before the target, so a seek late into a long
+// song takes a moment, and the audio drops out from the old sink going away until AudioClockSystem
+// plays the new one. Notes judged at or after the target are cleared so they can be played again,
+// the ones before it keep their judgements, and so does the InputTimeline. Notes a seek skips over
+// unplayed are judged misses without draining health.
+//
+// Seeking is for practice and invalidates the score: Score, Combo and Health start over from the
+// seek, so at the end they only count what was played since the last one. The results still
+// judge every note, from whichever run it was last played in.
+fn seek(world: &mut World, chart: &Chart, fraction: f64) {
+    let song_length = world.read_resource::<SongLength>().0;
+    if song_length == 0 {
+        log::warn!("Can't seek without knowing how long the song is");
+        return;
+    }
+    let target = (song_length as f64 * fraction.clamp(0.0, 1.0)) as u64;
+    let source = match open_audio(&chart.metadata.audio_path) {
+        Ok(source) => source.skip_duration(Duration::from_millis(target)),
+        Err(err) => {
+            log::error!("Couldn't seek: {}", err);
+            return;
+        },
+    };
+
+    let volume = *world.read_resource::<Volume>();
+    let playback_rate = *world.read_resource::<PlaybackRate>();
+    let sink = world.res.try_fetch::<Device>().map(|device| Sink::new(&device));
+    if let Some(sink) = sink {
+        sink.set_volume(volume.0);
+        sink.pause();
+        sink.append(source.speed(playback_rate.0));
+        world.add_resource(sink);
+    }
+    *world.write_resource::<SeekBase>() = SeekBase(target);
+    *world.write_resource::<WallClock>() = WallClock::default();
+    *world.write_resource::<AudioTimeInterpolator>() = AudioTimeInterpolator::default();
+    *world.write_resource::<ClockOffset>() = ClockOffset::default();
+    *world.write_resource::<PendingChords>() = PendingChords::default();
+    *world.write_resource::<LaneIndex>() = LaneIndex::default();
+    *world.write_resource::<NoteTrack>() = NoteTrack::default();
+    *world.write_resource::<SongEnd>() = SongEnd::default();
+
+    let first_beat_offset = world.read_resource::<AudioContext>().first_beat_offset;
+    let bar_millis = world.read_resource::<AudioContext>().bar_millis;
+    let seek_time = target.saturating_sub(first_beat_offset);
+    *world.write_resource::<AudioTime>() = AudioTime(seek_time);
+    *world.write_resource::<InterpolatedAudioTime>() = InterpolatedAudioTime(seek_time);
+
+    let rearmed: Vec<Entity> = (&world.entities(), &world.read_storage::<TargetBarTime>(), &world.read_storage::<BarIndex>()).join()
+        .filter(|&(_, target_bar_time, bar_index)| bar_index.0 * bar_millis + target_bar_time.0 >= seek_time)
+        .map(|(entity, _, _)| entity)
+        .collect();
+    let skipped: Vec<Entity> = (&world.entities(), &world.read_storage::<TargetBarTime>(), !&world.read_storage::<BarIndex>()).join()
+        .filter(|&(_, target_bar_time, _)| target_bar_time.0 < seek_time)
+        .map(|(entity, _, _)| entity)
+        .collect();
+    world.write_resource::<InputTimeline>().0.retain(|entry| entry.audio_time < seek_time as i64);
+    {
+        let mut bar_index_storage = world.write_storage::<BarIndex>();
+        let mut judged_storage = world.write_storage::<Judged>();
+        let mut hit_offset_storage = world.write_storage::<HitOffset>();
+        let mut hold_ticks_storage = world.write_storage::<HoldTicks>();
+        for entity in rearmed {
+            bar_index_storage.remove(entity);
+            judged_storage.remove(entity);
+            hit_offset_storage.remove(entity);
+            hold_ticks_storage.remove(entity);
+        }
+        for entity in skipped {
+            if let Err(err) = bar_index_storage.insert(entity, BarIndex(0)) {
+                log::warn!("Couldn't tag skipped target: {:?}", err);
+            }
+            if let Err(err) = judged_storage.insert(entity, Judged(Judgement::Miss)) {
+                log::warn!("Couldn't judge skipped target: {:?}", err);
+            }
+        }
+    }
+    *world.write_resource::<Score>() = Score::default();
+    *world.write_resource::<Combo>() = Combo::default();
+    *world.write_resource::<Health>() = Health::default();
+    *world.write_resource::<OffsetHistogram>() = OffsetHistogram::default();
+    log::info!("Seeked to {}ms", target);
+}
+
+fn export_results(world: &World, chart: &Chart, chart_path: &Path) {
+    let due = {
+        let mut results_export = world.write_resource::<ResultsExport>();
+        let requested = std::mem::replace(&mut results_export.requested, false);
+        world.read_resource::<Results>().complete && (requested || (results_export.automatic && !results_export.written))
+    };
+    if !due {
+        return;
+    }
+    let score = world.read_resource::<Score>().0;
+    match world.read_resource::<Results>().export(chart_path, &chart.metadata, score, &note_results(world)) {
+        Ok(path) => log::info!("Wrote results to {}", path.display()),
+        Err(err) => log::error!("Couldn't write results: {}", err),
+    }
+    world.write_resource::<ResultsExport>().written = true;
+}
+
+// Whatever an update step asked of the main loop that systems can't do from inside a dispatch:
+// loading a chart picked from the menu or next in the playlist, seeking, and exporting results.
+fn after_update(world: &mut World, chart: &mut Option<Chart>, loaded_path: &mut Option<PathBuf>, lead_in_millis: u64) {
+    let chosen = world.write_resource::<MenuState>().chosen.take();
+    if let Some(chart_path) = chosen {
+        match load_song(world, &chart_path) {
+            Ok(loaded) => {
+                *chart = Some(loaded);
+                *loaded_path = Some(chart_path);
+                *world.write_resource::<GameState>() = GameState::Playing;
+            },
+            Err(err) => log::error!("{}", err),
+        }
+    }
+    let seek_request = world.write_resource::<SeekRequest>().0.take();
+    let seekable = matches!(*world.read_resource::<GameState>(), GameState::Playing | GameState::Paused);
+    if let (Some(fraction), Some(chart), true) = (seek_request, &*chart, seekable) {
+        seek(world, chart, fraction);
+    }
+    if let (Some(chart), Some(chart_path)) = (&*chart, &*loaded_path) {
+        export_results(world, chart, chart_path);
+    }
+    // A song that fails to load is skipped, the playlist moves on to the one after it.
+    let next_song = world.res.try_fetch_mut::<Playlist>().and_then(|mut playlist| playlist.next.take());
+    if let Some(chart_path) = next_song {
+        clear_song(world, lead_in_millis);
+        match load_song(world, &chart_path) {
+            Ok(loaded) => {
+                *chart = Some(loaded);
+                *loaded_path = Some(chart_path);
+                *world.write_resource::<GameState>() = GameState::Playing;
+            },
+            Err(err) => log::error!("{}", err),
+        }
+    }
+}
+
 fn main() {
+    env_logger::init();
+
+    if let Err(err) = run() {
+        eprintln!("Error: {}", err);
+        std::process::exit(1);
+    }
+}
+
+fn run() -> Result<(), GameError> {
+    if let Some(path) = arg_value("--import-session") {
+        return import_session(&path);
+    }
+
     let mut world = World::new();
 
+    let maybe_device = rodio::default_output_device();
+    if maybe_device.is_none() {
+        log::warn!("No audio device found, running silently on the wall clock");
+    }
 
-    let device  = rodio::default_output_device().expect("Couldn't get default audio device");
-    let sink = Sink::new(&device);
+    let lead_in = LeadIn(arg_value("--lead-in")
+        .and_then(|millis| millis.parse().ok())
+        .unwrap_or(DEFAULT_LEAD_IN_MILLIS));
+    let lead_in_millis = lead_in.0;
 
-    let file = File::open("top-fixed.ogg").expect("Couldn't open file");
-    let source = Decoder::new(BufReader::new(file)).expect("Couldn't decode file");
+    let settings = Settings::load_or_default(Path::new(SETTINGS_PATH));
+    let volume = settings.volume;
+    let time_source = match maybe_device {
+        Some(device) => {
+            let sink = Sink::new(&device);
+            sink.set_volume(volume.0);
+            // Held until AudioClockSystem sees play start.
+            sink.pause();
+            world.add_resource(sink);
 
-    sink.set_volume(0.05);
-    sink.append(source);
+            let mut sfx_player = SfxPlayer::new(&device, SFX_POOL_SIZE);
+            Assets::load_hit_sounds(&mut sfx_player);
+            world.add_resource(sfx_player);
+            let time_source = match device.default_output_format() {
+                Ok(format) => {
+                    world.add_resource(OutputRate(format.channels as u32 * format.sample_rate.0));
+                    TimeSource::Audio
+                },
+                Err(err) => {
+                    log::error!("Couldn't get the default output format, timing the song on the wall clock: {:?}", err);
+                    TimeSource::Wallclock
+                },
+            };
+            world.add_resource(device);
+            time_source
+        },
+        None => TimeSource::Wallclock,
+    };
 
-    let sdl = sdl2::init().unwrap();
-    let video_subsystem = sdl.video().unwrap();
+    let sdl = sdl2::init().map_err(sdl_error)?;
+    let video_subsystem = sdl.video().map_err(sdl_error)?;
 
-    let window = video_subsystem.window("Rhythm Game", 800, 600)
-        .position(2500, 300)
+    let window_config = settings.window.clone();
+    let mut window_builder = video_subsystem.window(&window_config.title, window_config.width.max(1), window_config.height.max(1));
+    match window_position(&video_subsystem, &window_config) {
+        Some((x, y)) => window_builder.position(x, y),
+        None => window_builder.position_centered(),
+    };
+    let window = window_builder
+        .resizable()
         .build()
-        .unwrap();
+        .map_err(sdl_error)?;
 
-    let clear_color = Color::rgb(255, 255, 255);
-    let mut canvas = window.into_canvas()
+    let theme_name = settings.theme;
+    let theme = theme_name.theme();
+    let clear_color = theme.background;
+    let mut canvas_builder = window.into_canvas();
+    if window_config.vsync.0 {
+        canvas_builder = canvas_builder.present_vsync();
+    }
+    let mut canvas = canvas_builder
         .build()
-        .unwrap();
+        .map_err(sdl_error)?;
+    // Colors carry alpha, which fills only respect when blending.
+    canvas.set_blend_mode(BlendMode::Blend);
     canvas.set_draw_color(clear_color);
     canvas.clear();
     canvas.present();
 
-    let event_pump = sdl.event_pump().unwrap();
+    let event_pump = sdl.event_pump().map_err(sdl_error)?;
+    let timer = sdl.timer().map_err(sdl_error)?;
+
+    let ttf_context = sdl2::ttf::init().map_err(sdl_error)?;
+    let assets = Assets::load(&ttf_context).map_err(GameError::Asset)?;
+
+    let timing_filter = if has_flag("--early-only") {
+        TimingFilter::EarlyOnly
+    } else if has_flag("--late-only") {
+        TimingFilter::LateOnly
+    } else {
+        TimingFilter::Both
+    };
 
     world.add_resource(IsRunning(true));
     world.add_resource(DebugFlag(false));
     world.add_resource(ClearColor(clear_color));
+    world.add_resource(BeatPulse::default());
+    world.add_resource(BeatPhase::default());
+    world.add_resource(if has_flag("--pulse-every-beat") {
+        BeatFlashMode::EveryBeat
+    } else {
+        BeatFlashMode::Downbeat
+    });
+    world.add_resource(theme.clone());
     world.add_resource(AudioTime(0));
-    world.add_resource(AudioContext::new(160_000 - 150, 110, 4));
-    world.add_resource(sink);
-    world.add_resource(device);
-    world.add_resource(InputEvents(Vec::new()));
-    world.add_resource(SdlRects::default());
+    world.add_resource(SongTime(0));
+    world.add_resource(InterpolatedAudioTime(0));
+    world.add_resource(AudioTimeInterpolator::default());
+    world.add_resource(FrameTime(0.0));
+    world.add_resource(StepLag::default());
+    world.add_resource(AudioContext::default());
+    world.add_resource(volume);
+    world.add_resource(time_source);
+    world.add_resource(WallClock::default());
+    world.add_resource(SeekBase::default());
+    world.add_resource(SeekRequest::default());
+    world.add_resource(ManualClock::default());
+    world.add_resource(lead_in);
+    world.add_resource(GameState::default());
+    world.add_resource(InputEvents::default());
+    world.add_resource(HeldKeys::default());
+    world.add_resource(ClockOffset::default());
+    world.add_resource(HoldTickInterval::default());
+    world.add_resource(HoldTickCount::default());
+    world.add_resource(HitWindow::default());
+    world.add_resource(JudgementWindows::default());
+    world.add_resource(JudgementHistory::default());
+    world.add_resource(InputTimeline::default());
+    world.add_resource(LaneIndex::default());
+    world.add_resource(NoteTrack::default());
+    world.add_resource(LastHitError::default());
+    world.add_resource(LastHitKey::default());
+    world.add_resource(LastHitNote::default());
+    world.add_resource(PendingChords::default());
+    world.add_resource(ChordDiagnostics::default());
+    world.add_resource(HitFlash::default());
+    world.add_resource(SongLength::default());
+    world.add_resource(SongEnd::default());
+    world.add_resource(Results::default());
+    // F5 on the results screen writes them to results/, `--export-results` does so for every song.
+    world.add_resource(ResultsExport {
+        automatic: has_flag("--export-results"),
+        ..ResultsExport::default()
+    });
+    world.add_resource(OffsetHistogram::default());
+    world.add_resource(Combo::default());
+    world.add_resource(ComboBreaks::default());
+    world.add_resource(Score::default());
+    world.add_resource(settings.score_weights);
+    world.add_resource(ComboEvents::default());
+    world.add_resource(Health::default());
+    world.add_resource(if has_flag("--fail-on-empty-health") {
+        HealthDrainMode::Fail
+    } else {
+        HealthDrainMode::Cosmetic
+    });
+    world.add_resource(ComboGrace(30));
+    world.add_resource(Autoplay(has_flag("--autoplay")));
+    world.add_resource(timing_filter);
+    // `--single-key` judges presses by time alone, whatever lane they're in.
+    world.add_resource(SingleKeyMode(has_flag("--single-key")));
+    world.add_resource(settings.input_latency);
+    world.add_resource(settings.global_offset);
+    world.add_resource(SfxOffset::new(settings.sfx_offset.0));
+
+    // `--record <path>` saves this run's inputs to <path> on exit, `--replay <path>` feeds a
+    // previously recorded run back in place of the keyboard.
+    let record_path = arg_value("--record");
+    if let Some(replay_path) = arg_value("--replay") {
+        let replay_player = ReplayPlayer::load(&replay_path)
+            .map_err(|err| GameError::Io { path: replay_path.clone(), err })?;
+        world.add_resource(replay_player);
+    }
+    let export_session_path = arg_value("--export-session");
+    // `--snapshot <path>` writes the first frame's draw commands to <path> as text and quits, to
+    // diff against a known good layout.
+    let snapshot_path = arg_value("--snapshot");
+    world.add_resource(ReplayRecorder {
+        recording: record_path.is_some() || export_session_path.is_some(),
+        entries: Vec::new(),
+    });
+    world.add_resource(LatencyCalibration {
+        enabled: has_flag("--calibrate"),
+        ..LatencyCalibration::default()
+    });
+    // `--rate <r>` practices at r times normal speed, e.g. 0.75.
+    world.add_resource(arg_value("--rate")
+        .and_then(|rate| rate.parse().ok())
+        .map_or(PlaybackRate::default(), PlaybackRate::new));
+    // `--background <spec>` replaces the theme's flat background, see Background::parse.
+    world.add_resource(match arg_value("--background").map(|spec| Background::parse(&spec)) {
+        Some(Ok(background)) => background,
+        Some(Err(err)) => {
+            log::warn!("Ignoring --background: {}", err);
+            Background::Solid(clear_color)
+        },
+        None => Background::Solid(clear_color),
+    });
+    world.add_resource(DrawList::default());
+    world.add_resource(DrawStats::default());
+    world.add_resource(Nps::default());
+    world.add_resource(TargetFps::default());
+    world.add_resource(Viewport { width: window_config.width.max(1), height: window_config.height.max(1) });
+    world.add_resource(JudgementLine::default());
+    // `--lanes <n>` overrides the settings' lane count for this run.
+    let saved_lane_count = LaneCount::new(settings.lane_count.0);
+    let lane_count = arg_value("--lanes")
+        .and_then(|count| count.parse().ok())
+        .map_or(saved_lane_count, LaneCount::new);
+    let mut lane_layout = LaneLayout::for_count(lane_count);
+    theme.apply_to_lanes(&mut lane_layout);
+    world.add_resource(lane_layout);
+    // Without bindings of its own the lane count's defaults apply, DFJK for the four arrow lanes.
+    // Only bindings the settings gave are saved back, so the defaults follow the lane count.
+    let key_bindings = settings.key_bindings;
+    world.add_resource(if key_bindings.0.is_empty() {
+        KeyBindings(lane_count.default_key_bindings())
+    } else {
+        key_bindings.clone()
+    });
+    world.add_resource(lane_count);
+    world.add_resource(settings.key_legend);
+    world.add_resource(settings.receptor_pulse);
+    world.add_resource(settings.approach_time);
+    world.add_resource(settings.colorblind_mode);
+    world.add_resource(ScrollSpeed::default());
+    world.add_resource(SpatialSfx(!has_flag("--no-spatial-sfx")));
+    world.add_resource(if has_flag("--vertical") {
+        ScrollDirection::VerticalDown
+    } else {
+        ScrollDirection::Horizontal
+    });
+    world.add_resource(if has_flag("--round-notes") {
+        NoteShape::Circle
+    } else {
+        NoteShape::Square
+    });
+    world.add_resource(ApproachRing::default());
+    world.add_resource(WindowCommands::default());
+    world.add_resource(WindowState::default());
 
     world.register::<Position>();
     world.register::<Color>();
     world.register::<Rectangle>();
+    world.register::<Circle>();
+    world.register::<Scale>();
     world.register::<TargetBarTime>();
     world.register::<TargetInput>();
     world.register::<RhythmCombo>();
+    world.register::<ChordGroup>();
+    world.register::<NoteIndex>();
+    world.register::<NoteColor>();
     world.register::<BarIndex>();
+    world.register::<HoldDuration>();
+    world.register::<HoldTicks>();
+    world.register::<Judged>();
+    world.register::<HitOffset>();
+    world.register::<Text>();
+    world.register::<HitFlashMarker>();
+    world.register::<Receptor>();
+    world.register::<Scene>();
+    world.register::<Layer>();
+    world.register::<HealthBar>();
+    world.register::<ProgressBar>();
 
-    world.create_entity()
-        .with(Rectangle { width: 3000.0, height: 1.0 })
-        .with(Color::rgb(0,0,0))
-        .with(Position { x: 0.0, y: 200.0 })
-        .build();
+    spawn_receptors(&mut world);
+    spawn_health_bar(&mut world);
+    spawn_progress_bar(&mut world);
 
+    let judgement_line = *world.read_resource::<JudgementLine>();
     world.create_entity()
-        .with(TargetBarTime(0))
-        .with(TargetInput(Keycode::Left))
-        .with(RhythmCombo)
+        .with(HitFlashMarker)
+        .with(FLASH_LAYER)
+        .with(Rectangle { width: 0.0, height: 0.0 })
+        .with(theme.perfect)
+        .with(Position { x: judgement_line.x, y: 0.0 })
         .build();
 
-    let target_bar_time = world.read_resource::<AudioContext>().make_bar_time(4, 3, 2);
-    world.create_entity()
-        .with(target_bar_time)
-        .with(TargetInput(Keycode::Right))
-        .with(RhythmCombo)
-        .build();
+    // `--chart <path>` skips the menu and goes straight to playing that chart, `--playlist a,b,...`
+    // does the same with the first of several charts and plays the rest after it.
+    let first_chart = match arg_value("--playlist") {
+        Some(songs) => {
+            let mut playlist = Playlist::new(songs.split(',').map(PathBuf::from));
+            let first = playlist.songs.pop_front();
+            world.add_resource(playlist);
+            first
+        },
+        None => arg_value("--chart").map(PathBuf::from),
+    };
+    let mut chart = None;
+    let mut loaded_path = None;
+    match first_chart {
+        Some(chart_path) => {
+            world.add_resource(MenuState::default());
+            chart = Some(load_song(&mut world, &chart_path)?);
+            loaded_path = Some(chart_path);
+            *world.write_resource::<GameState>() = GameState::Playing;
+        },
+        None => world.add_resource(MenuState { entries: scan_charts(Path::new(CHARTS_DIR)), ..MenuState::default() }),
+    }
 
-    let sdl_system = SdlSystem::new(sdl, canvas, event_pump);
+    spawn_scene_text(&mut world, GameState::Paused, &["Paused", "Enter to resume, Escape to quit"]);
 
-    let mut dispatcher = DispatcherBuilder::new()
+    let mut sdl_input_system = SdlInputSystem::new(event_pump, timer.clone());
+    // Outlives the dispatcher, which holds SdlSystem and with it the textures made from this.
+    let texture_creator = canvas.texture_creator();
+    let sdl_system = SdlSystem::new(sdl, canvas, &texture_creator, timer, assets.font);
+
+    // A frame polls input once, then runs the update dispatcher for every fixed update step the
+    // frame's time covers, UPDATE_HZ of them a second whatever the frame rate, and the render
+    // dispatcher once. Within each dispatcher the systems form a pipeline, each stage depending on
+    // the one before it by name:
+    //
+    //  1. poll input: SdlInputSystem, by hand before the update steps, see the main loop
+    //  2. update audio time: state_transition_system, then audio_clock_system
+    //  3. process input: replay_system swaps in recorded presses, then omni, menu, autoplay,
+    //     tagging and hold ticks act on them
+    //  4. scoring: everything reading JudgementHistory, Combo or Results after tagging
+    //  5. animate: the render dispatcher places, colors and shows entities from where the last
+    //     update step left the song
+    //  6. build the draw list: rendering_system, after every system that moves, colors or
+    //     shows entities, or sets the clear color
+    //  7. present: SdlSystem draws the list through its SdlRenderer, thread-local, so after all
+    //     of the above
+    //
+    // Each update step gets the presses timestamped within it, so a press polled in 1 is judged
+    // in 3 of the step it happened in and drawn in 7 of the same frame. Systems within a stage
+    // that don't share data still run in parallel.
+    //
+    // The audio clock is read afresh each update step, but the sink only moves on as the device
+    // pulls samples, so steps run back to back in one frame would all see much the same time.
+    // Each step instead puts the sink's time back by its StepLag, how long before now the step
+    // ends, so audio_time moves through the frame a step at a time. Presses are judged at their
+    // own timestamps either way; what the steps buy is chord expiry, misses, hold ticks and hit
+    // sounds landing within a step of when they're due rather than within a frame.
+    let mut update_dispatcher = DispatcherBuilder::new()
+        .with(StateTransitionSystem, "state_transition_system", &[])
+        .with(AudioClockSystem::default(), "audio_clock_system", &["state_transition_system"])
+        .with(ReplaySystem, "replay_system", &["audio_clock_system"])
+        .with(OmniSystem, "omni_system", &["replay_system"])
+        .with(MenuSystem::default(), "menu_system", &["replay_system"])
+        .with(AutoplaySystem, "autoplay_system", &["audio_clock_system"])
+        .with(BarIndexTaggingSystem, "bar_index_tagging_system", &["replay_system", "autoplay_system"])
+        .with(HoldTickSystem, "hold_tick_system", &["audio_clock_system"])
+        .with(VolumeSystem::default(), "volume_system", &["omni_system"])
+        .with(ReplayRecordSystem, "replay_record_system", &["audio_clock_system"])
+        .with(HitSoundSystem::default(), "hit_sound_system", &["bar_index_tagging_system"])
+        .with(NpsSystem::default(), "nps_system", &["bar_index_tagging_system"])
+        .with(ScoringSystem::default(), "scoring_system", &["bar_index_tagging_system", "hold_tick_system"])
+        .with(SongEndSystem, "song_end_system", &["audio_clock_system"])
+        .with(HealthSystem::default(), "health_system", &["bar_index_tagging_system"])
+        .with(ResultsSystem, "results_system", &["bar_index_tagging_system", "song_end_system", "health_system"])
+        .with(PlaylistSystem, "playlist_system", &["results_system"])
+        .with(LatencyCalibrationSystem, "latency_calibration_system", &["bar_index_tagging_system"])
+        .build();
+    // ComboEvents are handed over once a frame here, so hit_flash_system sees everything the
+    // frame's update steps did to the combo.
+    let mut render_dispatcher = DispatcherBuilder::new()
         .with_thread_local(sdl_system)
-        .with(OmniSystem, "omni_system", &[])
-        .with(RenderingSystem, "rendering_system", &[])
-        .with(BarIndexTaggingSystem, "bar_index_tagging_system", &[])
+        .with(ApproachRingSystem, "approach_ring_system", &[])
+        .with(NoteRenderSystem, "note_render_system", &[])
+        .with(NotePopSystem, "note_pop_system", &[])
+        .with(HoldTailSystem::default(), "hold_tail_system", &[])
+        .with(BeatGridSystem::default(), "beat_grid_system", &[])
+        .with(ReceptorHighlightSystem, "receptor_highlight_system", &[])
+        .with(KeyLegendSystem::default(), "key_legend_system", &[])
+        .with(DebugOverlaySystem::default(), "debug_overlay_system", &[])
+        .with(CountdownSystem::default(), "countdown_system", &[])
+        .with(ComboEventSystem::default(), "combo_event_system", &[])
+        .with(BeatPhaseSystem, "beat_phase_system", &[])
+        .with(BeatFlashSystem::default(), "beat_flash_system", &["beat_phase_system"])
+        .with(ReceptorPulseSystem, "receptor_pulse_system", &["beat_phase_system"])
+        .with(HitFlashSystem, "hit_flash_system", &["combo_event_system"])
+        .with(HitTickSystem::default(), "hit_tick_system", &[])
+        .with(JudgementLabelSystem::default(), "judgement_label_system", &[])
+        .with(ProgressBarSystem, "progress_bar_system", &[])
+        .with(HistogramRenderSystem::default(), "histogram_render_system", &[])
+        .with(RenderingSystem::default(), "rendering_system", &[
+            "approach_ring_system",
+            "note_render_system",
+            "note_pop_system",
+            "hold_tail_system",
+            "beat_grid_system",
+            "receptor_highlight_system",
+            "receptor_pulse_system",
+            "key_legend_system",
+            "debug_overlay_system",
+            "countdown_system",
+            "beat_flash_system",
+            "hit_flash_system",
+            "hit_tick_system",
+            "judgement_label_system",
+            "progress_bar_system",
+            "histogram_render_system",
+        ])
         .build();
 
+    let mut frame_limiter = FrameLimiter::new();
+    let mut fixed_timestep = FixedTimestep::default();
+    let mut last_frame = Instant::now();
+
     'main: loop {
-        dispatcher.dispatch(&mut world.res);
+        let now = Instant::now();
+        let frame_millis = duration_millis(now - last_frame);
+        last_frame = now;
+
+        // Polled here rather than as a thread-local, which would only run after the dispatch and
+        // leave this frame's presses for the next one.
+        sdl_input_system.run_now(&world.res);
+        for step in fixed_timestep.advance(frame_millis) {
+            world.write_resource::<FrameTime>().0 = step.millis;
+            world.write_resource::<StepLag>().0 = step.lag;
+            {
+                let mut input_events = world.write_resource::<InputEvents>();
+                let step_end = input_events.polled_at as f64 - step.lag;
+                input_events.release_until(step_end);
+            }
+            update_dispatcher.dispatch(&world.res);
+            world.maintain();
+            if !world.read_resource::<IsRunning>().0 {
+                break 'main;
+            }
+            after_update(&mut world, &mut chart, &mut loaded_path, lead_in_millis);
+        }
+
+        world.write_resource::<FrameTime>().0 = frame_millis;
+        world.write_resource::<StepLag>().0 = fixed_timestep.lag();
+        render_dispatcher.dispatch(&world.res);
         world.maintain();
-        if !world.read_resource::<IsRunning>().0 {
+        if let Some(snapshot_path) = &snapshot_path {
+            if let Err(err) = std::fs::write(snapshot_path, snapshot_frame(&world)) {
+                log::error!("Couldn't write snapshot to {}: {}", snapshot_path, err);
+            }
             break 'main;
         }
+        // With vsync on, presenting already waited for the display, and limiting as well would
+        // only add a frame of latency whenever the two drift apart.
+        let target_fps = if window_config.vsync.0 { 0 } else { world.read_resource::<TargetFps>().0 };
+        frame_limiter.wait(target_fps);
+    }
+
+    // Escape, Enter on the results screen and a playlist running out all end up here. The song
+    // fades out before anything is written, so quitting doesn't cut it off mid-note.
+    log::info!("Shutting down");
+    if let Some(sink) = world.res.try_fetch::<Sink>() {
+        fade_out(&sink, FADE_OUT_MILLIS);
+    }
+
+    let settings = Settings {
+        volume: *world.read_resource::<Volume>(),
+        key_bindings,
+        input_latency: *world.read_resource::<InputLatency>(),
+        global_offset: *world.read_resource::<GlobalOffset>(),
+        sfx_offset: *world.read_resource::<SfxOffset>(),
+        lane_count: saved_lane_count,
+        key_legend: *world.read_resource::<KeyLegend>(),
+        receptor_pulse: *world.read_resource::<ReceptorPulse>(),
+        approach_time: *world.read_resource::<ApproachTime>(),
+        colorblind_mode: world.read_resource::<ColorblindMode>().clone(),
+        score_weights: *world.read_resource::<ScoreWeights>(),
+        theme: theme_name,
+        window: window_config,
+    };
+    if let Err(err) = settings.save(Path::new(SETTINGS_PATH)) {
+        log::error!("Couldn't save settings to {}: {}", SETTINGS_PATH, err);
+    }
+    // Quitting from the results screen skips the step's after_update, which would have exported
+    // them under `--export-results`.
+    if let (Some(chart), Some(chart_path)) = (&chart, &loaded_path) {
+        export_results(&world, chart, chart_path);
+    }
+
+    if let Some(record_path) = record_path {
+        if let Err(err) = world.read_resource::<ReplayRecorder>().save_replay(&record_path) {
+            log::error!("Couldn't save replay to {}: {}", record_path, err);
+        }
+    }
+    if let (Some(export_session_path), Some(chart)) = (export_session_path, &chart) {
+        let session = Session::capture(&world, chart);
+        let result = File::create(&export_session_path)
+            .map_err(ChartError::from)
+            .and_then(|file| session.to_json(file));
+        if let Err(err) = result {
+            log::error!("Couldn't export session to {}: {}", export_session_path, err);
+        }
+    }
+    let results = world.read_resource::<Results>();
+    if results.complete {
+        log::info!("Final results: {}", results.summary_lines().join(", "));
+        for line in results.summary_lines() {
+            println!("{}", line);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interpolation_stays_within_a_frame_of_the_next_read() {
+        // The sink's counter moves in 25ms buffers, frames are 10ms apart.
+        let frame_millis = 10.0;
+        let reads: Vec<u64> = (0..50).map(|frame| frame * 10 / 25 * 25).collect();
+        let mut interpolator = AudioTimeInterpolator::default();
+        for (frame, pair) in reads.windows(2).enumerate() {
+            let interpolated = interpolator.update(pair[0], frame_millis);
+            assert!(interpolated >= pair[0], "frame {} behind its own read", frame);
+            assert!(interpolated <= pair[1] + frame_millis as u64, "frame {} ran ahead to {}", frame, interpolated);
+        }
+    }
+
+    #[test]
+    fn stalled_counter_extrapolates_no_further_than_its_last_step() {
+        let mut interpolator = AudioTimeInterpolator::default();
+        interpolator.update(75, 10.0);
+        interpolator.update(100, 10.0);
+        for _ in 0..20 {
+            assert!(interpolator.update(100, 10.0) <= 125);
+        }
+        assert_eq!(interpolator.update(300, 10.0), 300);
+    }
+
+    #[test]
+    fn lead_in_keeps_to_the_wall_clock_at_240hz() {
+        let mut world = World::new();
+        let mut audio_clock_system = AudioClockSystem::default();
+        System::setup(&mut audio_clock_system, &mut world.res);
+        *world.write_resource::<TimeSource>() = TimeSource::Manual;
+        *world.write_resource::<GameState>() = GameState::Playing;
+        *world.write_resource::<FrameTime>() = FrameTime(1000.0 / 240.0);
+        *world.write_resource::<LeadIn>() = LeadIn(3000);
+
+        let mut steps = 0;
+        while world.read_resource::<LeadIn>().0 > 0 {
+            audio_clock_system.run_now(&world.res);
+            steps += 1;
+        }
+        assert_eq!(steps, 720);
+    }
+
+    // The playfield as a chart starts: receptors, health and progress bars and the sample chart's
+    // first notes scrolling in half a second before it starts, at the logical window size.
+    fn playing_scene() -> World {
+        let mut world = World::new();
+        System::setup(&mut NoteRenderSystem, &mut world.res);
+        System::setup(&mut RenderingSystem::default(), &mut world.res);
+        world.register::<Receptor>();
+        world.register::<HealthBar>();
+        world.register::<ProgressBar>();
+        world.register::<Text>();
+        world.register::<RhythmCombo>();
+        world.register::<ChordGroup>();
+        world.register::<NoteIndex>();
+        spawn_receptors(&mut world);
+        spawn_health_bar(&mut world);
+        spawn_progress_bar(&mut world);
+
+        let chart = Chart::from_reader(include_str!("../charts/top-fixed.chart").as_bytes()).unwrap();
+        chart.spawn_notes(&mut world);
+        *world.write_resource::<LeadIn>() = LeadIn(500);
+        *world.write_resource::<GameState>() = GameState::Playing;
+        NoteRenderSystem.run_now(&world.res);
+        world
+    }
+
+    #[test]
+    fn playing_scene_matches_its_snapshot() {
+        let snapshot = snapshot_frame(&playing_scene());
+        assert_eq!(snapshot, include_str!("../tests/snapshots/playing-scene.txt"));
     }
-    println!("Hello, world!");
 }
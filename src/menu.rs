@@ -0,0 +1,144 @@
+use sdl2::keyboard::Keycode;
+
+use specs::prelude::*;
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    chart::Chart,
+    render::{Color, Position, Text},
+    sdl::{InputEvent, InputEvents},
+    state::{GameState, Scene},
+    theme::Theme,
+};
+
+pub const CHARTS_DIR: &str = "charts";
+
+#[derive(Debug)]
+#[derive(Clone)]
+pub struct ChartEntry {
+    pub path: PathBuf,
+    pub title: String,
+    pub milli_bpm: u64,
+}
+
+// Lists every `.chart`, `.json` and `.osu` chart in `dir` sorted by title. Files that don't parse are
+// logged and left out, and a missing or unreadable directory gives an empty list.
+pub fn scan_charts(dir: &Path) -> Vec<ChartEntry> {
+    let dir_entries = match fs::read_dir(dir) {
+        Ok(dir_entries) => dir_entries,
+        Err(err) => {
+            log::warn!("Couldn't read charts from {}: {}", dir.display(), err);
+            return Vec::new();
+        },
+    };
+
+    let mut entries: Vec<ChartEntry> = dir_entries
+        .filter_map(|dir_entry| dir_entry.ok().map(|dir_entry| dir_entry.path()))
+        .filter(|path| path.extension().is_some_and(|extension| extension == "chart" || extension == "json" || extension == "osu"))
+        .filter_map(|path| match Chart::load(&path) {
+            Ok(chart) => {
+                let title = chart.metadata.title.clone().unwrap_or_else(|| {
+                    path.file_stem().map_or_else(String::new, |stem| stem.to_string_lossy().into_owned())
+                });
+                Some(ChartEntry { path, title, milli_bpm: chart.metadata.milli_bpm })
+            },
+            Err(err) => {
+                log::warn!("Skipping chart {}: {}", path.display(), err);
+                None
+            },
+        })
+        .collect();
+    entries.sort_by(|a, b| a.title.cmp(&b.title));
+    entries
+}
+
+// `chosen` is set when a chart is picked and taken by the main loop, which owns loading it.
+#[derive(Default)]
+pub struct MenuState {
+    pub entries: Vec<ChartEntry>,
+    pub selected: usize,
+    pub chosen: Option<PathBuf>,
+}
+
+#[derive(Default)]
+pub(crate) struct MenuSystem {
+    lines: Vec<Entity>,
+}
+
+impl<'a> System<'a> for MenuSystem {
+    type SystemData = (Entities<'a>,
+                       Read<'a, InputEvents>,
+                       Read<'a, GameState>,
+                       Read<'a, Theme>,
+                       Write<'a, MenuState>,
+                       WriteStorage<'a, Text>,
+                       WriteStorage<'a, Position>,
+                       WriteStorage<'a, Color>,
+                       WriteStorage<'a, Scene>);
+
+    fn run(&mut self, data: Self::SystemData) {
+        let (
+            entities,
+            input_events,
+            game_state,
+            theme,
+            mut menu_state,
+            mut text_storage,
+            mut position_storage,
+            mut color_storage,
+            mut scene_storage,
+        ) = data;
+
+        if *game_state != GameState::Menu {
+            return;
+        }
+
+        for event in &input_events.events {
+            match *event {
+                InputEvent { keycode: Some(Keycode::Up), .. } => {
+                    menu_state.selected = menu_state.selected.saturating_sub(1);
+                },
+                InputEvent { keycode: Some(Keycode::Down), .. } => {
+                    menu_state.selected = (menu_state.selected + 1).min(menu_state.entries.len().saturating_sub(1));
+                },
+                InputEvent { keycode: Some(Keycode::Return), .. } => {
+                    menu_state.chosen = menu_state.entries.get(menu_state.selected).map(|entry| entry.path.clone());
+                },
+                _ => {},
+            }
+        }
+
+        let lines: Vec<String> = if menu_state.entries.is_empty() {
+            vec![format!("No charts found in {}/", CHARTS_DIR)]
+        } else {
+            menu_state.entries.iter()
+                .map(|entry| format!("{} ({:.1} bpm)", entry.title, entry.milli_bpm as f64 / 1000.0))
+                .collect()
+        };
+
+        while self.lines.len() < lines.len() {
+            let y = 100.0 + self.lines.len() as f64 * 30.0;
+            let entity = entities.build_entity()
+                .with(Scene(GameState::Menu), &mut scene_storage)
+                .with(Text(String::new()), &mut text_storage)
+                .with(Position { x: 100.0, y }, &mut position_storage)
+                .with(theme.text, &mut color_storage)
+                .build();
+            self.lines.push(entity);
+        }
+
+        for (index, (entity, line)) in self.lines.iter().zip(lines).enumerate() {
+            let selected = index == menu_state.selected && !menu_state.entries.is_empty();
+            if let Some(text) = text_storage.get_mut(*entity) {
+                text.0 = if selected { format!("> {}", line) } else { format!("  {}", line) };
+            }
+            if let Some(color) = color_storage.get_mut(*entity) {
+                *color = if selected { theme.selected_text } else { theme.text };
+            }
+        }
+    }
+}
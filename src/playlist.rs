@@ -0,0 +1,101 @@
+use specs::prelude::*;
+
+use std::{
+    collections::VecDeque,
+    path::PathBuf,
+};
+
+use crate::{
+    FrameTime,
+    IsRunning,
+    render::{Color, Position, Text},
+    state::{GameState, Scene},
+    theme::Theme,
+};
+
+// How long each song's results stay up before the next song starts loading.
+pub const PLAYLIST_RESULTS_MILLIS: f64 = 3000.0;
+
+// Charts still to play after the current one, in order. A chart carries its own audio path and
+// offsets, so it's all a song needs. Only present when the game was started with `--playlist`,
+// otherwise the results screen waits for Enter as usual.
+#[derive(Default)]
+pub struct Playlist {
+    pub songs: VecDeque<PathBuf>,
+    pub results_elapsed: f64,
+    // Popped and announced on the loading screen, but not loaded yet.
+    pub loading: Option<PathBuf>,
+    // Announced for at least a frame, for the main loop to load like a chart picked in the menu.
+    pub next: Option<PathBuf>,
+}
+
+impl Playlist {
+    pub fn new<I: IntoIterator<Item = PathBuf>>(songs: I) -> Playlist {
+        Playlist { songs: songs.into_iter().collect(), ..Playlist::default() }
+    }
+}
+
+// Holds each song's results for PLAYLIST_RESULTS_MILLIS, then puts up a loading line for a frame
+// before handing the next song to the main loop. That swaps in its AudioContext and SongLength
+// between dispatches, so no system ever sees one song's BPM with another's notes. Running out of
+// songs quits.
+pub(crate) struct PlaylistSystem;
+
+impl<'a> System<'a> for PlaylistSystem {
+    type SystemData = (Entities<'a>,
+                       Read<'a, FrameTime>,
+                       Read<'a, GameState>,
+                       Read<'a, Theme>,
+                       Write<'a, IsRunning>,
+                       Option<Write<'a, Playlist>>,
+                       WriteStorage<'a, Scene>,
+                       WriteStorage<'a, Text>,
+                       WriteStorage<'a, Position>,
+                       WriteStorage<'a, Color>);
+
+    fn run(&mut self, data: Self::SystemData) {
+        let (
+            entities,
+            frame_time,
+            game_state,
+            theme,
+            mut is_running,
+            maybe_playlist,
+            mut scene_storage,
+            mut text_storage,
+            mut position_storage,
+            mut color_storage,
+        ) = data;
+
+        let mut playlist = match maybe_playlist {
+            Some(playlist) => playlist,
+            None => return,
+        };
+        if *game_state != GameState::Results {
+            playlist.results_elapsed = 0.0;
+            return;
+        }
+        if let Some(path) = playlist.loading.take() {
+            playlist.next = Some(path);
+            return;
+        }
+
+        playlist.results_elapsed += frame_time.0;
+        if playlist.results_elapsed < PLAYLIST_RESULTS_MILLIS {
+            return;
+        }
+        match playlist.songs.pop_front() {
+            Some(path) => {
+                let name = path.file_stem().map_or_else(|| path.display().to_string(), |stem| stem.to_string_lossy().into_owned());
+                entities.build_entity()
+                    .with(Scene(GameState::Results), &mut scene_storage)
+                    .with(Text(format!("Loading {}...", name)), &mut text_storage)
+                    .with(Position { x: 300.0, y: 450.0 }, &mut position_storage)
+                    .with(theme.text, &mut color_storage)
+                    .build();
+                playlist.loading = Some(path);
+            },
+            None => is_running.0 = false,
+        }
+    }
+}
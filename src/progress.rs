@@ -0,0 +1,62 @@
+use specs::prelude::*;
+
+use crate::{
+    AudioTime,
+    render::{Position, Rectangle, FLASH_LAYER, LOGICAL_WIDTH},
+    rhythm::SongLength,
+    state::{GameState, Scene},
+    theme::Theme,
+};
+
+pub const PROGRESS_BAR_HEIGHT: f64 = 4.0;
+
+#[derive(Debug)]
+#[derive(Default)]
+#[derive(Clone)]
+#[derive(Copy)]
+pub struct ProgressBar;
+
+impl Component for ProgressBar {
+    type Storage = NullStorage<Self>;
+}
+
+// Starts empty, ProgressBarSystem grows it from the left edge.
+pub fn spawn_progress_bar(world: &mut World) {
+    let color = world.read_resource::<Theme>().progress;
+    world.create_entity()
+        .with(ProgressBar)
+        .with(FLASH_LAYER)
+        .with(Scene(GameState::Playing))
+        .with(Rectangle { width: 0.0, height: PROGRESS_BAR_HEIGHT })
+        .with(color)
+        .with(Position { x: 0.0, y: PROGRESS_BAR_HEIGHT / 2.0 })
+        .build();
+}
+
+// Fills the bar along the top edge by how far audio_time is through the song, measured the same
+// way SongEndSystem decides the song is over, so it's full just as the song ends. A song of
+// unknown length leaves it empty.
+pub(crate) struct ProgressBarSystem;
+
+impl<'a> System<'a> for ProgressBarSystem {
+    type SystemData = (Read<'a, AudioTime>,
+                       Read<'a, SongLength>,
+                       ReadStorage<'a, ProgressBar>,
+                       WriteStorage<'a, Position>,
+                       WriteStorage<'a, Rectangle>);
+
+    fn run(&mut self, data: Self::SystemData) {
+        let (audio_time, song_length, bar_storage, mut position_storage, mut rect_storage) = data;
+
+        let progress = if song_length.0 == 0 {
+            0.0
+        } else {
+            (audio_time.0 as f64 / song_length.0 as f64).min(1.0)
+        };
+        let width = LOGICAL_WIDTH * progress;
+        for (_, position, rect) in (&bar_storage, &mut position_storage, &mut rect_storage).join() {
+            rect.width = width;
+            position.x = width / 2.0;
+        }
+    }
+}
@@ -1,11 +1,157 @@
 use specs::prelude::*;
 
+use std::{
+    fmt,
+    path::PathBuf,
+};
+
+pub const LOGICAL_WIDTH: f64 = 800.0;
+pub const LOGICAL_HEIGHT: f64 = 600.0;
+
 pub struct ClearColor(pub Color);
 
 impl Default for ClearColor {
     fn default() -> ClearColor { ClearColor(Color::rgb(0,0,0)) }
 }
 
+pub const GRADIENT_BANDS: u32 = 32;
+
+// What's drawn behind the play area. A Solid background is the ClearColor, beat pulse and all.
+// The others are drawn as they are with the pulse laid over them, a Gradient as GRADIENT_BANDS
+// horizontal bands from its top color to its bottom one, an Image stretched over the play area.
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(PartialEq)]
+pub enum Background {
+    Solid(Color),
+    Gradient(Color, Color),
+    // A BMP file, the only format SDL reads without SDL_image.
+    Image(PathBuf),
+}
+
+impl Default for Background {
+    fn default() -> Background { Background::Solid(Color::rgb(0, 0, 0)) }
+}
+
+impl Background {
+    // `#rrggbb` is solid, `#rrggbb,#rrggbb` a gradient from top to bottom, anything else an image.
+    pub fn parse(spec: &str) -> Result<Background, ColorParseError> {
+        if !spec.starts_with('#') {
+            return Ok(Background::Image(PathBuf::from(spec)));
+        }
+        match spec.find(',') {
+            Some(comma) => Ok(Background::Gradient(Color::from_hex(&spec[..comma])?, Color::from_hex(&spec[comma + 1..])?)),
+            None => Ok(Background::Solid(Color::from_hex(spec)?)),
+        }
+    }
+
+    // What the beat pulse fades back to on a Solid background.
+    pub fn solid_color(&self) -> Option<Color> {
+        match self {
+            Background::Solid(color) => Some(*color),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(Copy)]
+pub struct Viewport {
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Default for Viewport {
+    fn default() -> Viewport {
+        Viewport { width: LOGICAL_WIDTH as u32, height: LOGICAL_HEIGHT as u32 }
+    }
+}
+
+impl Viewport {
+    // Takes on a new window size, after a resize or a change of fullscreen mode. Never 0 across,
+    // so scale() always has something to divide.
+    pub fn resize(&mut self, width: u32, height: u32) {
+        self.width = width.max(1);
+        self.height = height.max(1);
+    }
+
+    pub fn scale(&self) -> f64 {
+        (self.width as f64 / LOGICAL_WIDTH).min(self.height as f64 / LOGICAL_HEIGHT)
+    }
+
+    pub fn offset(&self) -> (f64, f64) {
+        let scale = self.scale();
+        (
+            (self.width as f64 - LOGICAL_WIDTH * scale) / 2.0,
+            (self.height as f64 - LOGICAL_HEIGHT * scale) / 2.0,
+        )
+    }
+
+    pub fn to_physical(self, x: f64, y: f64) -> (f64, f64) {
+        let scale = self.scale();
+        let (offset_x, offset_y) = self.offset();
+        (x * scale + offset_x, y * scale + offset_y)
+    }
+
+    pub fn to_logical(self, x: f64, y: f64) -> (f64, f64) {
+        let scale = self.scale();
+        let (offset_x, offset_y) = self.offset();
+        ((x - offset_x) / scale, (y - offset_y) / scale)
+    }
+}
+
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(Copy)]
+pub struct JudgementLine {
+    pub x: f64,
+}
+
+impl Default for JudgementLine {
+    fn default() -> JudgementLine { JudgementLine { x: 100.0 } }
+}
+
+pub const RING_MIN_RADIUS: f64 = 12.0;
+pub const RING_MAX_RADIUS: f64 = 60.0;
+pub const RING_LEAD_MILLIS: u64 = 600;
+
+// Ring around the receptor of the next note due, centered on that receptor.
+#[derive(Default)]
+pub struct ApproachRing {
+    pub radius: Option<f64>,
+    pub x: f64,
+    pub y: f64,
+}
+
+pub fn approach_ring_radius(time_to_hit: u64) -> Option<f64> {
+    if time_to_hit > RING_LEAD_MILLIS {
+        return None;
+    }
+    let t = time_to_hit as f64 / RING_LEAD_MILLIS as f64;
+    Some(RING_MIN_RADIUS + (RING_MAX_RADIUS - RING_MIN_RADIUS) * t)
+}
+
+// Draw order, higher layers on top. Entities without a Layer are on NOTE_LAYER, and text is always
+// drawn over every rect.
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(PartialEq)]
+#[derive(Eq)]
+#[derive(PartialOrd)]
+#[derive(Ord)]
+pub struct Layer(pub i32);
+
+impl Component for Layer {
+    type Storage = VecStorage<Self>;
+}
+
+pub const BACKGROUND_LAYER: Layer = Layer(-2);
+pub const RECEPTOR_LAYER: Layer = Layer(-1);
+pub const NOTE_LAYER: Layer = Layer(0);
+pub const FLASH_LAYER: Layer = Layer(1);
+
 #[derive(Debug)]
 pub struct Position {
     pub x: f64,
@@ -28,18 +174,112 @@ impl Component for Rectangle {
     type Storage = VecStorage<Self>;
 }
 
+// Multiplies the size a Rectangle or Circle is drawn at, around its center. Entities without one
+// are drawn at 1.0.
 #[derive(Debug)]
 #[derive(Clone)]
 #[derive(Copy)]
+pub struct Scale(pub f64);
+
+impl Component for Scale {
+    type Storage = VecStorage<Self>;
+}
+
+// Drawn filled, centered on the entity's Position like a Rectangle.
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(Copy)]
+pub struct Circle {
+    pub radius: f64,
+}
+
+impl Component for Circle {
+    type Storage = VecStorage<Self>;
+}
+
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(PartialEq)]
 pub struct Color {
     pub r: u8,
     pub g: u8,
     pub b: u8,
+    // 255 is opaque. The canvas blends, so anything less shows what's drawn underneath.
+    pub a: u8,
+}
+
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(PartialEq)]
+pub enum ColorParseError {
+    MissingHash,
+    BadLength(usize),
+    BadDigit(char),
+}
+
+impl fmt::Display for ColorParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ColorParseError::MissingHash => write!(f, "colors start with `#`"),
+            ColorParseError::BadLength(length) => write!(f, "expected 3, 6 or 8 hex digits, found {}", length),
+            ColorParseError::BadDigit(digit) => write!(f, "`{}` isn't a hex digit", digit),
+        }
+    }
 }
 
 impl Color {
     pub fn rgb(r: u8, g: u8, b: u8) -> Self {
-        Color {r, g, b}
+        Color::rgba(r, g, b, 255)
+    }
+
+    pub fn rgba(r: u8, g: u8, b: u8, a: u8) -> Self {
+        Color {r, g, b, a}
+    }
+
+    // `t` 0.0 is `a` and 1.0 is `b`, each channel rounded to the nearest value. `t` outside of
+    // 0..=1 extrapolates, saturating at the ends of the channel.
+    pub fn lerp(a: Color, b: Color, t: f64) -> Color {
+        let channel = |a: u8, b: u8| (a as f64 + (b as f64 - a as f64) * t).round() as u8;
+        Color::rgba(channel(a.r, b.r), channel(a.g, b.g), channel(a.b, b.b), channel(a.a, b.a))
+    }
+
+    // `#rgb`, `#rrggbb` or `#rrggbbaa`, either case. The short form doubles each digit, so `#f80`
+    // is `#ff8800`, and the forms without alpha are opaque.
+    pub fn from_hex(hex: &str) -> Result<Color, ColorParseError> {
+        if !hex.starts_with('#') {
+            return Err(ColorParseError::MissingHash);
+        }
+        let digits = &hex[1..];
+        if let Some(digit) = digits.chars().find(|c| !c.is_ascii_hexdigit()) {
+            return Err(ColorParseError::BadDigit(digit));
+        }
+        // All ASCII from here on, so byte indices are char indices.
+        let channel = |index: usize, width: usize| {
+            let value = u8::from_str_radix(&digits[index * width..(index + 1) * width], 16).unwrap_or(0);
+            if width == 1 { value * 17 } else { value }
+        };
+        match digits.len() {
+            3 => Ok(Color::rgb(channel(0, 1), channel(1, 1), channel(2, 1))),
+            6 => Ok(Color::rgb(channel(0, 2), channel(1, 2), channel(2, 2))),
+            8 => Ok(Color::rgba(channel(0, 2), channel(1, 2), channel(2, 2), channel(3, 2))),
+            length => Err(ColorParseError::BadLength(length)),
+        }
+    }
+
+    // `#rrggbb`, with the alpha appended only when it isn't opaque.
+    pub fn to_hex(self) -> String {
+        if self.a == 255 {
+            format!("#{:02x}{:02x}{:02x}", self.r, self.g, self.b)
+        } else {
+            format!("#{:02x}{:02x}{:02x}{:02x}", self.r, self.g, self.b, self.a)
+        }
+    }
+
+    // Multiplies every color channel by `factor`, saturating at 255. Alpha is left alone.
+    pub fn scale(self, factor: f64) -> Color {
+        let channel = |c: u8| (c as f64 * factor).round() as u8;
+        Color::rgba(channel(self.r), channel(self.g), channel(self.b), self.a)
     }
 }
 
@@ -48,3 +288,116 @@ impl Component for Color {
 }
 
 
+
+#[derive(Debug)]
+#[derive(Clone)]
+pub struct Text(pub String);
+
+impl Component for Text {
+    type Storage = VecStorage<Self>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wide_viewport_letterboxes_at_the_sides() {
+        let viewport = Viewport { width: 1600, height: 900 };
+        assert_eq!(viewport.scale(), 1.5);
+        assert_eq!(viewport.offset(), (200.0, 0.0));
+        assert_eq!(viewport.to_physical(0.0, 0.0), (200.0, 0.0));
+        assert_eq!(viewport.to_physical(LOGICAL_WIDTH, LOGICAL_HEIGHT), (1400.0, 900.0));
+    }
+
+    #[test]
+    fn tall_viewport_letterboxes_above_and_below() {
+        let viewport = Viewport { width: 400, height: 600 };
+        assert_eq!(viewport.scale(), 0.5);
+        assert_eq!(viewport.offset(), (0.0, 150.0));
+        assert_eq!(viewport.to_logical(200.0, 300.0), (LOGICAL_WIDTH / 2.0, LOGICAL_HEIGHT / 2.0));
+    }
+
+    #[test]
+    fn resize_after_a_mode_change_rescales() {
+        let mut viewport = Viewport::default();
+        assert_eq!(viewport.scale(), 1.0);
+        viewport.resize(1920, 1080);
+        assert_eq!((viewport.width, viewport.height), (1920, 1080));
+        assert_eq!(viewport.scale(), 1.8);
+        viewport.resize(0, 0);
+        assert_eq!((viewport.width, viewport.height), (1, 1));
+    }
+
+    #[test]
+    fn logical_coordinates_round_trip() {
+        let viewport = Viewport { width: 1280, height: 720 };
+        let (x, y) = viewport.to_physical(123.0, 456.0);
+        let (logical_x, logical_y) = viewport.to_logical(x, y);
+        assert!((logical_x - 123.0).abs() < 1e-9);
+        assert!((logical_y - 456.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn approach_ring_shrinks_toward_the_hit() {
+        assert_eq!(approach_ring_radius(RING_LEAD_MILLIS + 1), None);
+        assert_eq!(approach_ring_radius(RING_LEAD_MILLIS), Some(RING_MAX_RADIUS));
+        assert_eq!(approach_ring_radius(RING_LEAD_MILLIS / 2), Some((RING_MIN_RADIUS + RING_MAX_RADIUS) / 2.0));
+        assert_eq!(approach_ring_radius(0), Some(RING_MIN_RADIUS));
+    }
+
+    #[test]
+    fn lerp_ends_on_each_color_and_rounds_in_between() {
+        let black = Color::rgba(0, 0, 0, 255);
+        let odd = Color::rgba(255, 101, 3, 0);
+        assert_eq!(Color::lerp(black, odd, 0.0), black);
+        assert_eq!(Color::lerp(black, odd, 1.0), odd);
+        // 127.5, 50.5 and 1.5 round away from zero, 127.5 alpha on the way down too.
+        assert_eq!(Color::lerp(black, odd, 0.5), Color::rgba(128, 51, 2, 128));
+        assert_eq!(Color::lerp(odd, black, 0.5), Color::rgba(128, 51, 2, 128));
+        // A third of 255 is 85 exactly, of 101 33.67.
+        assert_eq!(Color::lerp(black, odd, 1.0 / 3.0), Color::rgba(85, 34, 1, 170));
+        assert_eq!(Color::lerp(black, odd, 2.0), Color::rgba(255, 202, 6, 0));
+    }
+
+    #[test]
+    fn scale_saturates_and_keeps_alpha() {
+        let color = Color::rgba(100, 200, 3, 50);
+        assert_eq!(color.scale(0.5), Color::rgba(50, 100, 2, 50));
+        assert_eq!(color.scale(2.0), Color::rgba(200, 255, 6, 50));
+        assert_eq!(color.scale(0.0), Color::rgba(0, 0, 0, 50));
+    }
+
+    #[test]
+    fn hex_colors_parse_in_each_form() {
+        assert_eq!(Color::from_hex("#f80"), Ok(Color::rgb(0xff, 0x88, 0x00)));
+        assert_eq!(Color::from_hex("#FF00aa"), Ok(Color::rgb(0xff, 0x00, 0xaa)));
+        assert_eq!(Color::from_hex("#ff00aa80"), Ok(Color::rgba(0xff, 0x00, 0xaa, 0x80)));
+        assert_eq!(Color::from_hex("#ff00aa").map(|color| color.to_hex()), Ok("#ff00aa".to_string()));
+        assert_eq!(Color::from_hex("#ff00aa80").map(|color| color.to_hex()), Ok("#ff00aa80".to_string()));
+    }
+
+    #[test]
+    fn bad_hex_colors_say_what_is_wrong() {
+        assert_eq!(Color::from_hex("ff00aa"), Err(ColorParseError::MissingHash));
+        assert_eq!(Color::from_hex(""), Err(ColorParseError::MissingHash));
+        assert_eq!(Color::from_hex("#ff00a"), Err(ColorParseError::BadLength(5)));
+        assert_eq!(Color::from_hex("#"), Err(ColorParseError::BadLength(0)));
+        assert_eq!(Color::from_hex("#ff00ag"), Err(ColorParseError::BadDigit('g')));
+        // A bad digit is reported ahead of the length, and non-ASCII doesn't split a char.
+        assert_eq!(Color::from_hex("#fé"), Err(ColorParseError::BadDigit('é')));
+    }
+
+    #[test]
+    fn backgrounds_parse_by_form() {
+        assert_eq!(Background::parse("#102030"), Ok(Background::Solid(Color::rgb(0x10, 0x20, 0x30))));
+        assert_eq!(
+            Background::parse("#000,#fff"),
+            Ok(Background::Gradient(Color::rgb(0, 0, 0), Color::rgb(255, 255, 255))),
+        );
+        assert_eq!(Background::parse("art/stage.bmp"), Ok(Background::Image(PathBuf::from("art/stage.bmp"))));
+        // Once it starts with `#` it's colors, both of them.
+        assert_eq!(Background::parse("#000,fff"), Err(ColorParseError::MissingHash));
+        assert_eq!(Background::parse("#00x"), Err(ColorParseError::BadDigit('x')));
+    }
+}
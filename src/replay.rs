@@ -0,0 +1,175 @@
+use sdl2::keyboard::Keycode;
+
+use specs::prelude::*;
+
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io::{self, BufRead, BufReader, BufWriter, Write as IoWrite},
+    path::Path,
+};
+
+use crate::{
+    SongTime,
+    sdl::{InputEvent, InputEvents},
+    state::GameState,
+};
+
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(Copy)]
+pub struct ReplayEntry {
+    pub audio_time: i64,
+    pub event: InputEvent,
+}
+
+#[derive(Default)]
+pub struct ReplayRecorder {
+    pub recording: bool,
+    pub entries: Vec<ReplayEntry>,
+}
+
+// One entry per line: `audio_time<TAB>timestamp<TAB>press time<TAB>key name`, with `-` for events
+// without a keycode. Key names come from SDL so they may contain spaces, hence the tabs. Replays
+// from before presses carried their own time have no press time field and were judged at
+// `audio_time`. Both times are SongTime, negative for presses during the lead-in.
+impl ReplayRecorder {
+    pub fn save_replay<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        for entry in &self.entries {
+            let key = entry.event.keycode.map(|keycode| keycode.name()).unwrap_or_else(|| "-".to_string());
+            writeln!(writer, "{}\t{}\t{}\t{}", entry.audio_time, entry.event.timestamp, entry.event.audio_time, key)?;
+        }
+        writer.flush()
+    }
+}
+
+pub struct ReplayPlayer {
+    pub entries: VecDeque<ReplayEntry>,
+}
+
+impl ReplayPlayer {
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<ReplayPlayer> {
+        let reader = BufReader::new(File::open(path)?);
+        let mut entries = VecDeque::new();
+        for line in reader.lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            entries.push_back(parse_entry(&line).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidData, format!("Bad replay line: {}", line))
+            })?);
+        }
+        Ok(ReplayPlayer { entries })
+    }
+}
+
+fn is_state_key(keycode: Option<Keycode>) -> bool {
+    keycode == Some(Keycode::Escape) || keycode == Some(Keycode::Return)
+}
+
+fn parse_entry(line: &str) -> Option<ReplayEntry> {
+    let fields: Vec<&str> = line.splitn(4, '\t').collect();
+    let (audio_time, timestamp, press_time, key) = match fields[..] {
+        [audio_time, timestamp, press_time, key] => (audio_time.parse().ok()?, timestamp, Some(press_time), key),
+        [audio_time, timestamp, key] => (audio_time.parse().ok()?, timestamp, None, key),
+        _ => return None,
+    };
+    let timestamp = timestamp.parse().ok()?;
+    let press_time = match press_time {
+        Some(press_time) => press_time.parse().ok()?,
+        None => audio_time,
+    };
+    let keycode = match key {
+        "-" => None,
+        name => Some(Keycode::from_name(name)?),
+    };
+    Some(ReplayEntry { audio_time, event: InputEvent { timestamp, keycode, audio_time: press_time } })
+}
+
+// Runs after SdlInputSystem has polled this frame's events and AudioClockSystem has advanced the
+// clock, and swaps them for the recorded events that are due at the current song time, lead-in
+// included. Escape and Enter are still passed through so a replay can be started, paused and
+// aborted.
+pub(crate) struct ReplaySystem;
+
+impl<'a> System<'a> for ReplaySystem {
+    type SystemData = (Read<'a, SongTime>,
+                       Read<'a, GameState>,
+                       Write<'a, InputEvents>,
+                       Option<Write<'a, ReplayPlayer>>);
+
+    fn run(&mut self, data: Self::SystemData) {
+        let (song_time, game_state, mut input_events, maybe_replay_player) = data;
+
+        if let Some(mut replay_player) = maybe_replay_player {
+            input_events.events.retain(|event| is_state_key(event.keycode));
+            let playing = *game_state == GameState::Playing;
+            while playing && replay_player.entries.front().is_some_and(|entry| entry.audio_time <= song_time.0) {
+                if let Some(entry) = replay_player.entries.pop_front() {
+                    input_events.push(entry.event);
+                }
+            }
+        }
+    }
+}
+
+// Records events alongside the song time of the frame that judges them. Each event also carries
+// its own press time, so feeding them back on those frames reproduces the same judgements.
+pub(crate) struct ReplayRecordSystem;
+
+impl<'a> System<'a> for ReplayRecordSystem {
+    type SystemData = (Read<'a, SongTime>,
+                       Read<'a, GameState>,
+                       Read<'a, InputEvents>,
+                       Write<'a, ReplayRecorder>);
+
+    fn run(&mut self, data: Self::SystemData) {
+        let (song_time, game_state, input_events, mut replay_recorder) = data;
+
+        // Nothing is judged outside of play, so there's nothing to reproduce, and state keys are
+        // left out so playback doesn't pause itself.
+        if replay_recorder.recording && *game_state == GameState::Playing {
+            for event in input_events.events.iter().filter(|event| !is_state_key(event.keycode)) {
+                replay_recorder.entries.push(ReplayEntry { audio_time: song_time.0, event: *event });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn saved_replay_loads_back_the_same_entries() {
+        let entry = |audio_time: i64, timestamp: u32, keycode: Option<Keycode>, press_time: i64| {
+            ReplayEntry { audio_time, event: InputEvent { timestamp, keycode, audio_time: press_time } }
+        };
+        // A lead-in press, a key name with a space and an event without a key.
+        let recorder = ReplayRecorder {
+            recording: true,
+            entries: vec![
+                entry(-120, 880, Some(Keycode::D), -124),
+                entry(500, 1500, Some(Keycode::KpPlus), 497),
+                entry(516, 1516, None, 512),
+            ],
+        };
+        let path = std::env::temp_dir().join(format!("rhythm-{}-replay.txt", std::process::id()));
+        recorder.save_replay(&path).unwrap();
+        let loaded = ReplayPlayer::load(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        let key = |entry: &ReplayEntry| (entry.audio_time, entry.event.timestamp, entry.event.keycode, entry.event.audio_time);
+        let loaded: Vec<_> = loaded.unwrap().entries.iter().map(key).collect();
+        assert_eq!(loaded, recorder.entries.iter().map(key).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn replays_without_press_times_are_judged_when_recorded() {
+        let entry = parse_entry("500\t1500\tD").unwrap();
+        assert_eq!((entry.audio_time, entry.event.audio_time, entry.event.keycode), (500, 500, Some(Keycode::D)));
+        assert!(parse_entry("500\t1500\tNot A Key").is_none());
+    }
+}
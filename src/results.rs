@@ -0,0 +1,254 @@
+use serde::{Deserialize, Serialize};
+
+use specs::prelude::*;
+
+use std::{
+    fs::{self, File},
+    io::{self, BufWriter},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    chart::ChartMetadata,
+    render::{Position, Text},
+    rhythm::{HitOffset, Judged, Judgement, NoteIndex, TargetBarTime},
+    song::SongEnd,
+    state::{GameState, Scene},
+    theme::Theme,
+};
+
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(PartialEq)]
+#[derive(Serialize, Deserialize)]
+pub enum Grade {
+    S,
+    A,
+    B,
+    C,
+    D,
+}
+
+impl Grade {
+    pub fn from_accuracy(accuracy: f64) -> Grade {
+        if accuracy >= 95.0 {
+            Grade::S
+        } else if accuracy >= 90.0 {
+            Grade::A
+        } else if accuracy >= 80.0 {
+            Grade::B
+        } else if accuracy >= 70.0 {
+            Grade::C
+        } else {
+            Grade::D
+        }
+    }
+}
+
+#[derive(Debug)]
+#[derive(Default)]
+#[derive(Clone)]
+#[derive(PartialEq)]
+#[derive(Serialize, Deserialize)]
+pub struct Results {
+    pub complete: bool,
+    pub total_notes: u32,
+    pub perfect: u32,
+    pub great: u32,
+    pub good: u32,
+    pub miss: u32,
+    pub accuracy: f64,
+    pub grade: Option<Grade>,
+}
+
+impl Results {
+    // Notes that were never judged by the end of the song count as misses. An empty chart has
+    // nothing to miss and comes out at 100%.
+    pub fn from_judgements<I: IntoIterator<Item = Option<Judgement>>>(judgements: I) -> Results {
+        let mut results = Results { complete: true, ..Results::default() };
+        let mut weighted = 0.0;
+
+        for judgement in judgements {
+            let judgement = judgement.unwrap_or(Judgement::Miss);
+            results.total_notes += 1;
+            weighted += judgement.weight();
+            match judgement {
+                Judgement::Perfect => results.perfect += 1,
+                Judgement::Great => results.great += 1,
+                Judgement::Good => results.good += 1,
+                Judgement::Miss => results.miss += 1,
+            }
+        }
+
+        results.accuracy = if results.total_notes == 0 {
+            100.0
+        } else {
+            weighted / results.total_notes as f64 * 100.0
+        };
+        results.grade = Some(Grade::from_accuracy(results.accuracy));
+        results
+    }
+
+    pub fn summary_lines(&self) -> Vec<String> {
+        vec![
+            format!("Grade {:?}  {:.2}%", self.grade.unwrap_or(Grade::D), self.accuracy),
+            format!("Notes {}", self.total_notes),
+            format!("Perfect {}", self.perfect),
+            format!("Great {}", self.great),
+            format!("Good {}", self.good),
+            format!("Miss {}", self.miss),
+        ]
+    }
+}
+
+pub const RESULTS_DIR: &str = "results";
+
+// How one note went, None for both when it was never judged.
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(PartialEq)]
+#[derive(Serialize, Deserialize)]
+pub struct NoteResult {
+    pub judgement: Option<Judgement>,
+    pub offset: Option<i64>,
+}
+
+// Each note's result, indexed by its place in the chart.
+pub fn note_results(world: &World) -> Vec<NoteResult> {
+    let index_storage = world.read_storage::<NoteIndex>();
+    let judged_storage = world.read_storage::<Judged>();
+    let hit_offset_storage = world.read_storage::<HitOffset>();
+    let mut notes = vec![NoteResult { judgement: None, offset: None }; index_storage.join().count()];
+    for (index, judged, hit_offset) in (&index_storage, judged_storage.maybe(), hit_offset_storage.maybe()).join() {
+        if let Some(note) = notes.get_mut(index.0) {
+            note.judgement = judged.map(|judged| judged.0);
+            note.offset = hit_offset.map(|hit_offset| hit_offset.0);
+        }
+    }
+    notes
+}
+
+// The longest run of notes in chart order hit without a miss between them.
+pub fn max_combo(notes: &[NoteResult]) -> u32 {
+    notes.iter()
+        .fold((0, 0), |(best, run), note| match note.judgement {
+            Some(Judgement::Miss) | None => (best, 0),
+            Some(_) => (best.max(run + 1), run + 1),
+        })
+        .0
+}
+
+// What gets written out for external tools. The chart and its metadata come along so a file
+// makes sense without the chart next to it.
+#[derive(Serialize)]
+struct ResultsReport<'a> {
+    chart: &'a str,
+    metadata: &'a ChartMetadata,
+    #[serde(flatten)]
+    results: &'a Results,
+    score: u64,
+    max_combo: u32,
+    notes: &'a [NoteResult],
+}
+
+impl Results {
+    pub fn to_json<W: io::Write>(&self, writer: W, chart: &str, metadata: &ChartMetadata, score: u64, notes: &[NoteResult]) -> io::Result<()> {
+        let report = ResultsReport { chart, metadata, results: self, score, max_combo: max_combo(notes), notes };
+        Ok(serde_json::to_writer_pretty(writer, &report)?)
+    }
+
+    // Writes to RESULTS_DIR, creating it if need be, as `<chart name>-<unix seconds>.json`.
+    pub fn export(&self, chart_path: &Path, metadata: &ChartMetadata, score: u64, notes: &[NoteResult]) -> io::Result<PathBuf> {
+        fs::create_dir_all(RESULTS_DIR)?;
+        let stem = chart_path.file_stem().map_or_else(|| "chart".into(), |stem| stem.to_string_lossy());
+        let seconds = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_secs());
+        let path = Path::new(RESULTS_DIR).join(format!("{}-{}.json", stem, seconds));
+        let file = File::create(&path)?;
+        self.to_json(BufWriter::new(file), &chart_path.display().to_string(), metadata, score, notes)?;
+        Ok(path)
+    }
+}
+
+// `requested` is set by the export hotkey and only counts once the results are in. `automatic`, set
+// by `--export-results`, writes every song's results as soon as they're in, `written` keeping that
+// to once a song.
+#[derive(Debug)]
+#[derive(Default)]
+pub struct ResultsExport {
+    pub automatic: bool,
+    pub requested: bool,
+    pub written: bool,
+}
+
+pub(crate) struct ResultsSystem;
+
+impl<'a> System<'a> for ResultsSystem {
+    type SystemData = (Entities<'a>,
+                       Read<'a, SongEnd>,
+                       Read<'a, Theme>,
+                       Read<'a, LazyUpdate>,
+                       Write<'a, Results>,
+                       ReadStorage<'a, TargetBarTime>,
+                       ReadStorage<'a, Judged>);
+
+    fn run(&mut self, data: Self::SystemData) {
+        let (entities, song_end, theme, lazy_update, mut results, target_bar_time_storage, judged_storage) = data;
+
+        if results.complete || !song_end.finished {
+            return;
+        }
+
+        *results = Results::from_judgements(
+            (&target_bar_time_storage, judged_storage.maybe())
+                .join()
+                .map(|(_, judged)| judged.map(|judged| judged.0))
+        );
+
+        for (line, text) in results.summary_lines().into_iter().enumerate() {
+            lazy_update.create_entity(&entities)
+                .with(Scene(GameState::Results))
+                .with(Text(text))
+                .with(Position { x: 300.0, y: 250.0 + line as f64 * 30.0 })
+                .with(theme.text)
+                .build();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_chart_scores_full_accuracy() {
+        let results = Results::from_judgements(Vec::new());
+        assert_eq!(results.total_notes, 0);
+        assert_eq!(results.accuracy, 100.0);
+        assert_eq!(results.grade, Some(Grade::S));
+    }
+
+    #[test]
+    fn unjudged_notes_count_as_misses() {
+        let results = Results::from_judgements(vec![
+            Some(Judgement::Perfect),
+            Some(Judgement::Great),
+            Some(Judgement::Good),
+            None,
+        ]);
+        assert_eq!((results.perfect, results.great, results.good, results.miss), (1, 1, 1, 1));
+        assert!((results.accuracy - 52.5).abs() < 1e-9);
+        assert_eq!(results.grade, Some(Grade::D));
+    }
+
+    #[test]
+    fn grades_start_at_their_thresholds() {
+        assert_eq!(Grade::from_accuracy(95.0), Grade::S);
+        assert_eq!(Grade::from_accuracy(94.99), Grade::A);
+        assert_eq!(Grade::from_accuracy(80.0), Grade::B);
+        assert_eq!(Grade::from_accuracy(70.0), Grade::C);
+        assert_eq!(Grade::from_accuracy(69.99), Grade::D);
+    }
+}
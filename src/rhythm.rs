@@ -1,9 +1,24 @@
+use sdl2::keyboard::Keycode;
+
+use serde::{Deserialize, Serialize};
+
 use specs::prelude::*;
 
+use std::collections::HashMap;
+
 use crate::{
     AudioTime,
+    DebugFlag,
+    InterpolatedAudioTime,
+    SongTime,
     TargetInput,
-    sdl::{InputEvent, InputEvents},
+    autoplay::Autoplay,
+    calibration::{GlobalOffset, InputLatency, LatencyCalibration},
+    countdown::LeadIn,
+    lanes::{LaneLayout, ScrollDirection},
+    render::{approach_ring_radius, ApproachRing, JudgementLine},
+    sdl::{HeldKeys, InputEvent, InputEvents},
+    state::GameState,
 };
 
 #[derive(Debug)]
@@ -37,8 +52,62 @@ impl AudioContext {
         }
     }
 
+    // Works from milli_bpm rather than beat_millis, which is already truncated, and divides once at
+    // the end, rounding to the nearest ms. Triplets and quintuplets then land where they should
+    // instead of drifting a little earlier with every beat. u128 keeps the product from
+    // overflowing however far into the song the index goes.
     pub fn make_bar_time(&self, multiple: u64, division: u64, index: u64) -> TargetBarTime {
-        TargetBarTime((self.beat_millis * index * multiple) / division)
+        let numerator = 60_000_000 * index as u128 * multiple as u128;
+        let denominator = self.milli_bpm as u128 * division as u128;
+        if denominator == 0 {
+            return TargetBarTime(0);
+        }
+        TargetBarTime(((numerator + denominator / 2) / denominator) as u64)
+    }
+
+    pub fn next_target_time(&self, target_bar_time: TargetBarTime, time: u64) -> u64 {
+        let bar = time.saturating_sub(target_bar_time.0).div_ceil(self.bar_millis);
+        bar * self.bar_millis + target_bar_time.0
+    }
+}
+
+// The bar audio_time falls in and the beat within it, both counted from 0. audio_time already
+// starts at the first beat, so bar 0 beat 0 is the first downbeat and anything before that
+// counts as it too.
+pub fn musical_position(audio_time: u64, ctx: &AudioContext) -> (u64, u8) {
+    if ctx.bar_millis == 0 || ctx.beat_millis == 0 {
+        return (0, 0);
+    }
+    let bar = audio_time / ctx.bar_millis;
+    let beat = (audio_time % ctx.bar_millis) / ctx.beat_millis;
+    // bar_millis is rounded on its own, so the last beat can come out one past the bar's end.
+    (bar, (beat as u8).min(ctx.beats_per_bar.saturating_sub(1)))
+}
+
+// How far audio_time is through its beat and its bar, each from 0.0 at the start up to 1.0. Like
+// musical_position a beat can't run past the bar, the last one takes up whatever bar_millis's
+// rounding leaves over.
+#[derive(Debug)]
+#[derive(Default)]
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(PartialEq)]
+pub struct BeatPhase {
+    pub beat: f64,
+    pub bar: f64,
+}
+
+impl BeatPhase {
+    pub fn at(audio_time: u64, ctx: &AudioContext) -> BeatPhase {
+        if ctx.bar_millis == 0 || ctx.beat_millis == 0 {
+            return BeatPhase::default();
+        }
+        let within_bar = audio_time % ctx.bar_millis;
+        let beat = (within_bar / ctx.beat_millis).min(ctx.beats_per_bar.saturating_sub(1) as u64);
+        BeatPhase {
+            beat: ((within_bar - beat * ctx.beat_millis) as f64 / ctx.beat_millis as f64).min(1.0),
+            bar: within_bar as f64 / ctx.bar_millis as f64,
+        }
     }
 }
 
@@ -61,61 +130,1242 @@ impl Component for BarIndex {
     type Storage = VecStorage<Self>;
 }
 
+// How far in ms a press may land from its note and still hit it, on either side. A press exactly
+// at a bound misses.
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(PartialEq)]
+pub struct HitWindow {
+    pub early: u64,
+    pub late: u64,
+}
+
+impl Default for HitWindow {
+    fn default() -> HitWindow { HitWindow::symmetric(100) }
+}
+
+impl HitWindow {
+    pub fn symmetric(millis: u64) -> HitWindow {
+        HitWindow { early: millis, late: millis }
+    }
+
+    pub fn accepts(&self, milli_error: u64, early: bool) -> bool {
+        milli_error < if early { self.early } else { self.late }
+    }
+
+    pub fn widest(&self) -> u64 {
+        self.early.max(self.late)
+    }
+}
+
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(PartialEq)]
+#[derive(Default)]
+#[derive(Serialize, Deserialize)]
+pub enum TimingFilter {
+    #[default]
+    Both,
+    EarlyOnly,
+    LateOnly,
+}
+
+impl TimingFilter {
+    pub fn accepts(&self, early: bool) -> bool {
+        match *self {
+            TimingFilter::Both => true,
+            TimingFilter::EarlyOnly => early,
+            TimingFilter::LateOnly => !early,
+        }
+    }
+}
+
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(PartialEq)]
+#[derive(Eq)]
+#[derive(Hash)]
+#[derive(Serialize, Deserialize)]
+pub enum Judgement {
+    Perfect,
+    Great,
+    Good,
+    Miss,
+}
+
+impl Judgement {
+    pub fn weight(&self) -> f64 {
+        match *self {
+            Judgement::Perfect => 1.0,
+            Judgement::Great => 0.7,
+            Judgement::Good => 0.4,
+            Judgement::Miss => 0.0,
+        }
+    }
+}
+
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(Copy)]
+pub struct Judged(pub Judgement);
+
+impl Component for Judged {
+    type Storage = VecStorage<Self>;
+}
+
+// Signed error a note was hit with in ms, negative for early. Misses have none.
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(Copy)]
+pub struct HitOffset(pub i64);
+
+impl Component for HitOffset {
+    type Storage = VecStorage<Self>;
+}
+
+// How far a debug hotkey moves a judgement window, in ms.
+pub const JUDGEMENT_WINDOW_STEP: i64 = 5;
+
+// Anything outside `great` is judged Good. A hit has to land inside `good` as well as the
+// HitWindow to count at all, so `good` can narrow the HitWindow but not widen it. Always
+// perfect <= great <= good.
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(PartialEq)]
+pub struct JudgementWindows {
+    pub perfect: u64,
+    pub great: u64,
+    pub good: u64,
+}
+
+impl Default for JudgementWindows {
+    fn default() -> JudgementWindows {
+        JudgementWindows { perfect: 25, great: 50, good: HitWindow::default().widest() }
+    }
+}
+
+impl JudgementWindows {
+    // Moves one window by `delta` ms, stopping at the windows either side of it, and `good` at
+    // `ceiling`, past which it would make no difference. A Miss has no window and is left alone.
+    pub fn nudge(&mut self, judgement: Judgement, delta: i64, ceiling: u64) {
+        let nudged = |window: u64, floor: u64, ceiling: u64| ((window as i64 + delta).max(floor as i64) as u64).min(ceiling);
+        match judgement {
+            Judgement::Perfect => self.perfect = nudged(self.perfect, 0, self.great),
+            Judgement::Great => self.great = nudged(self.great, self.perfect, self.good),
+            Judgement::Good => self.good = nudged(self.good, self.great, ceiling.max(self.great)),
+            Judgement::Miss => {},
+        }
+    }
+
+    pub fn judge(&self, milli_error: u64) -> Judgement {
+        if milli_error <= self.perfect {
+            Judgement::Perfect
+        } else if milli_error <= self.great {
+            Judgement::Great
+        } else {
+            Judgement::Good
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct JudgementHistory(pub Vec<Judgement>);
+
+#[derive(Default)]
+pub struct SongLength(pub u64);
+
+// Hits in a row, hold ticks included. Only ScoringSystem changes it during play, from
+// JudgementHistory, HoldTickCount and ComboBreaks, so it's also the streak the multiplier reads.
+#[derive(Default)]
+pub struct Combo(pub u32);
+
+// Where presses that hit nothing broke the combo, as the length JudgementHistory had then, kept
+// across songs like it so ScoringSystem can place each break among the judgements around it.
+#[derive(Default)]
+pub struct ComboBreaks(pub Vec<usize>);
+
+// Signed error of the most recent hit in ms, negative for early.
+#[derive(Default)]
+pub struct LastHitError(pub Option<i64>);
+
+// Key of the most recent hit, so effects can find the lane it landed in.
+#[derive(Default)]
+pub struct LastHitKey(pub Option<Keycode>);
+
+// The note the most recent hit judged.
+#[derive(Default)]
+pub struct LastHitNote(pub Option<Entity>);
+
+// A judged note: the song time of the press that judged it, latency corrected, the lane it's in and
+// what it got. A chord member missed for its chord not arriving in time is at the time that was
+// called, or at its own press if it had one.
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(PartialEq)]
+#[derive(Serialize, Deserialize)]
+pub struct TimelineEntry {
+    pub audio_time: i64,
+    #[serde(with = "lane_name")]
+    pub lane: Keycode,
+    pub judgement: Judgement,
+}
+
+mod lane_name {
+    use sdl2::keyboard::Keycode;
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(lane: &Keycode, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&lane.name())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Keycode, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        Keycode::from_name(&name).ok_or_else(|| D::Error::custom(format!("unknown key `{}`", name)))
+    }
+}
+
+// Every judgement of the song in the order it was made, alongside JudgementHistory, with enough
+// to replay the play state somewhere else. Cleared with the rest of the per-song state.
+#[derive(Debug)]
+#[derive(Default)]
+#[derive(Clone)]
+#[derive(PartialEq)]
+#[derive(Serialize, Deserialize)]
+pub struct InputTimeline(pub Vec<TimelineEntry>);
+
+impl InputTimeline {
+    pub fn record(&mut self, audio_time: i64, lane: Keycode, judgement: Judgement) {
+        self.0.push(TimelineEntry { audio_time, lane, judgement });
+    }
+
+    // The last `count` entries, oldest first, or all of them if there are fewer.
+    pub fn recent(&self, count: usize) -> &[TimelineEntry] {
+        &self.0[self.0.len().saturating_sub(count)..]
+    }
+}
+
+// Notes sharing a group are a chord and only score once every member of it has been hit.
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(PartialEq)]
+#[derive(Eq)]
+#[derive(Hash)]
+pub struct ChordGroup(pub u32);
+
+impl Component for ChordGroup {
+    type Storage = VecStorage<Self>;
+}
+
+// Where a note sits in its chart's note list, so two runs of a chart can be compared note for
+// note whatever order their entities were created in.
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(PartialEq)]
+pub struct NoteIndex(pub usize);
+
+impl Component for NoteIndex {
+    type Storage = VecStorage<Self>;
+}
+
+// The keys of a chord that did arrive landing this close together, in ms, looks like a clean press
+// of the whole chord rather than a fumble.
+pub const GHOSTING_SPREAD_MILLIS: i64 = 15;
+
+// Incomplete chords, counted by why they probably failed. Keyboards without full rollover drop
+// some keys of a chord pressed together, so when the keys that got through arrived almost at once
+// the rest likely never left the keyboard. Anything else is put down to the player.
+#[derive(Debug)]
+#[derive(Default)]
+pub struct ChordDiagnostics {
+    pub missed: u32,
+    pub possible_ghosting: u32,
+}
+
+impl ChordDiagnostics {
+    pub fn record(&mut self, chord_size: usize, errors: &[i64]) {
+        let spread = match (errors.iter().min(), errors.iter().max()) {
+            (Some(earliest), Some(latest)) => latest - earliest,
+            _ => i64::MAX,
+        };
+        if errors.len() >= 2 && spread <= GHOSTING_SPREAD_MILLIS {
+            self.possible_ghosting += 1;
+            log::warn!(
+                "Only {} of {} chord keys arrived, {}ms apart; the keyboard may be dropping simultaneous presses",
+                errors.len(), chord_size, spread,
+            );
+        } else {
+            self.missed += 1;
+            log::info!("Chord missed, {} of {} keys arrived", errors.len(), chord_size);
+        }
+    }
+}
+
+struct ScoredHit {
+    entity: Entity,
+    key: Keycode,
+    judgement: Judgement,
+    error: i64,
+    press_time: i64,
+}
+
+struct PendingChord {
+    first_press: i64,
+    hits: Vec<ScoredHit>,
+}
+
+// Chord members hit so far, held back from scoring until the rest of their chord arrives. A chord
+// still incomplete a full hit window's width after its first hit scores a miss for every member.
+#[derive(Default)]
+pub struct PendingChords(HashMap<ChordGroup, PendingChord>);
+
+// Judges every press by time alone: a press of any key the chart uses takes the nearest note in
+// any lane, as if there were only the one. For charts that are pure timing practice.
+#[derive(Default)]
+pub struct SingleKeyMode(pub bool);
+
+// Presses this far past the edge of the hit window score nothing but leave the combo intact.
+#[derive(Default)]
+pub struct ComboGrace(pub u64);
+
+// Unhit targets bucketed by key, each lane sorted by the time the target is due. Each lane keeps a
+// cursor at the first target that could still be in range of the song time, advanced as it moves,
+// so a press only ever walks the few targets near it.
+#[derive(Default)]
+pub struct Lane {
+    pub targets: Vec<(u64, Entity)>,
+    pub cursor: usize,
+}
+
+// Alongside the lanes, every unhit target in order of its time, with a cursor at the first one
+// that hasn't gone past the judgement line yet.
+#[derive(Default)]
+pub struct LaneIndex {
+    pub lanes: HashMap<Keycode, Lane>,
+    pub built: bool,
+    last_time: i64,
+    by_time: Vec<(u64, Entity)>,
+    passed: usize,
+}
+
+impl LaneIndex {
+    pub fn rebuild<'a>(
+        &mut self,
+        entities: &Entities<'a>,
+        target_input_storage: &ReadStorage<'a, TargetInput>,
+        target_bar_time_storage: &ReadStorage<'a, TargetBarTime>,
+        bar_index_storage: &WriteStorage<'a, BarIndex>,
+    ) {
+        self.lanes.clear();
+        self.by_time.clear();
+        for (entity, input, target_bar_time, _) in (&**entities, target_input_storage, target_bar_time_storage, !bar_index_storage).join() {
+            for &key in &input.0 {
+                self.lanes.entry(key).or_default().targets.push((target_bar_time.0, entity));
+            }
+            self.by_time.push((target_bar_time.0, entity));
+        }
+        for lane in self.lanes.values_mut() {
+            lane.targets.sort_by_key(|&(offset, _)| offset);
+        }
+        self.by_time.sort_by_key(|&(offset, _)| offset);
+        self.last_time = 0;
+        self.passed = 0;
+        self.built = true;
+    }
+
+    // Every target due at or before `time` that an earlier call didn't already return, whether or
+    // not it's been hit since.
+    pub fn take_passed(&mut self, time: i64) -> Vec<Entity> {
+        let start = self.passed;
+        while self.passed < self.by_time.len() && self.by_time[self.passed].0 as i64 <= time {
+            self.passed += 1;
+        }
+        self.by_time[start..self.passed].iter().map(|&(_, entity)| entity).collect()
+    }
+
+    pub fn lane_is_empty(&self, keycode: Keycode) -> bool {
+        self.lanes.get(&keycode).is_none_or(|lane| lane.targets.is_empty())
+    }
+
+    // Moves every cursor up to the first target still within `radius` of `time`. Going back, after
+    // a seek, starts the cursors over from the beginning.
+    pub fn advance(&mut self, time: i64, radius: u64) {
+        let rewound = time < self.last_time;
+        self.last_time = time;
+        for lane in self.lanes.values_mut() {
+            if rewound {
+                lane.cursor = 0;
+            }
+            while lane.cursor < lane.targets.len() && lane.targets[lane.cursor].0 as i64 + (radius as i64) < time {
+                lane.cursor += 1;
+            }
+        }
+    }
+
+    // Every target due within `radius` of `time`, which mustn't be before the time last advanced to.
+    pub fn candidates(&self, keycode: Keycode, time: i64, radius: u64) -> Vec<Entity> {
+        let lane = match self.lanes.get(&keycode) {
+            Some(lane) => lane,
+            None => return Vec::new(),
+        };
+
+        lane.targets[lane.cursor.min(lane.targets.len())..].iter()
+            .skip_while(|&&(target_time, _)| target_time as i64 + (radius as i64) < time)
+            .take_while(|&&(target_time, _)| target_time as i64 <= time + radius as i64)
+            .map(|&(_, entity)| entity)
+            .collect()
+    }
+
+    // Takes the target out of the lane of every key that could hit it.
+    pub fn remove(&mut self, keys: &[Keycode], entity: Entity) {
+        for keycode in keys {
+            if let Some(lane) = self.lanes.get_mut(keycode) {
+                if let Some(index) = lane.targets.iter().position(|&(_, lane_entity)| lane_entity == entity) {
+                    lane.targets.remove(index);
+                    if index < lane.cursor {
+                        lane.cursor -= 1;
+                    }
+                }
+            }
+        }
+    }
+}
+
 pub(crate) struct BarIndexTaggingSystem;
 
 impl<'a> System<'a> for BarIndexTaggingSystem {
     type SystemData = (Entities<'a>,
-                       Read<'a, AudioTime>,
-                       Read<'a, AudioContext>,
+                       Read<'a, SongTime>,
+                       // Grouped since specs only takes tuples of up to 26.
+                       (Read<'a, HitWindow>,
+                        Read<'a, TimingFilter>,
+                        Read<'a, InputLatency>,
+                        Read<'a, GlobalOffset>,
+                        Read<'a, ComboGrace>,
+                        Read<'a, JudgementWindows>,
+                        Read<'a, SingleKeyMode>),
+                       Read<'a, DebugFlag>,
                        Read<'a, InputEvents>,
+                       Read<'a, GameState>,
+                       Read<'a, Autoplay>,
+                       Write<'a, LatencyCalibration>,
+                       Write<'a, ComboBreaks>,
+                       Write<'a, JudgementHistory>,
+                       Write<'a, InputTimeline>,
+                       Write<'a, LaneIndex>,
+                       Write<'a, LastHitError>,
+                       Write<'a, LastHitKey>,
+                       Write<'a, LastHitNote>,
+                       Write<'a, PendingChords>,
+                       Write<'a, ChordDiagnostics>,
                        ReadStorage<'a, TargetInput>,
                        ReadStorage<'a, TargetBarTime>,
-                       WriteStorage<'a, BarIndex>);
+                       ReadStorage<'a, ChordGroup>,
+                       WriteStorage<'a, BarIndex>,
+                       WriteStorage<'a, Judged>,
+                       WriteStorage<'a, HitOffset>);
 
     fn run(&mut self, data: Self::SystemData) {
         let (
             entities,
-            audio_time,
-            audio_context,
+            song_time,
+            (
+                hit_window,
+                timing_filter,
+                input_latency,
+                global_offset,
+                combo_grace,
+                judgement_windows,
+                single_key_mode,
+            ),
+            debug_flag,
             input_events,
+            game_state,
+            autoplay,
+            mut latency_calibration,
+            mut combo_breaks,
+            mut judgement_history,
+            mut input_timeline,
+            mut lane_index,
+            mut last_hit_error,
+            mut last_hit_key,
+            mut last_hit_note,
+            mut pending_chords,
+            mut chord_diagnostics,
             target_input_storage,
             target_bar_time_storage,
-            mut bar_index_storage
+            chord_group_storage,
+            mut bar_index_storage,
+            mut judged_storage,
+            mut hit_offset_storage,
         ) = data;
 
-        for event in &input_events.0 {
+        if !lane_index.built {
+            lane_index.rebuild(&entities, &target_input_storage, &target_bar_time_storage, &bar_index_storage);
+        }
+
+        // Under autoplay AutoplaySystem does all the judging and presses are ignored.
+        if *game_state != GameState::Playing || autoplay.0 {
+            return;
+        }
+
+        // Each press is judged at the song time SdlInputSystem stamped it with, which can be up to a
+        // frame before this one, so the lane cursors only advance as far as the earliest of them.
+        // Song time runs negative through the lead-in, where a press can still be early for a note
+        // at the start of the chart, so everything here is signed.
+        let latency_corrected = |time: i64| time - input_latency.0 - global_offset.0;
+        let now = latency_corrected(song_time.0);
+        let earliest = input_events.events.iter()
+            .map(|event| latency_corrected(event.audio_time))
+            .fold(now, i64::min);
+        let search_radius = hit_window.widest() + combo_grace.0;
+        lane_index.advance(earliest, search_radius);
+
+        let window_width = (hit_window.early + hit_window.late) as i64;
+        let expired: Vec<_> = pending_chords.0.iter()
+            .filter(|(_, pending)| now >= pending.first_press + window_width)
+            .map(|(&group, _)| group)
+            .collect();
+        for group in expired {
+            let pending = match pending_chords.0.remove(&group) {
+                Some(pending) => pending,
+                None => continue,
+            };
+            let unhit: Vec<_> = (&*entities, &chord_group_storage, &target_input_storage)
+                .join()
+                .filter(|&(entity, &member, _)| member == group && bar_index_storage.get(entity).is_none())
+                .map(|(entity, _, input)| (entity, input.lane_key(), input.0.clone()))
+                .collect();
+            let errors: Vec<i64> = pending.hits.iter().map(|hit| hit.error).collect();
+            chord_diagnostics.record(unhit.len() + errors.len(), &errors);
+            for (entity, lane, keys) in unhit {
+                if let Err(err) = bar_index_storage.insert(entity, BarIndex(0)) {
+                    log::warn!("Couldn't tag chord member: {:?}", err);
+                }
+                lane_index.remove(&keys, entity);
+                if let Err(err) = judged_storage.insert(entity, Judged(Judgement::Miss)) {
+                    log::warn!("Couldn't judge chord member: {:?}", err);
+                }
+                judgement_history.0.push(Judgement::Miss);
+                input_timeline.record(now, lane, Judgement::Miss);
+            }
+            for hit in pending.hits {
+                if let Err(err) = judged_storage.insert(hit.entity, Judged(Judgement::Miss)) {
+                    log::warn!("Couldn't judge chord member: {:?}", err);
+                }
+                judgement_history.0.push(Judgement::Miss);
+                input_timeline.record(hit.press_time, hit.key, Judgement::Miss);
+            }
+        }
+
+        for event in &input_events.events {
             match *event {
-                InputEvent { keycode: Some(keycode), timestamp } => {
-                    let targets_hit: Vec<_> = (&*entities, &target_input_storage, &target_bar_time_storage, !&bar_index_storage)
-                        .join()
-                        .filter(|(_, input, _, _)| input.0 == keycode)
-                        .filter_map(|(entity, _, target_bar_time, _)| {
-
-                            let nearest_bar = (audio_time.0.saturating_sub(target_bar_time.0) + audio_context.bar_millis / 2) / audio_context.bar_millis;
-                            let target_time = nearest_bar * audio_context.bar_millis + target_bar_time.0;
-                            let milli_error = if audio_time.0 > target_time {
-                                audio_time.0 - target_time
-                            } else {
-                                target_time - audio_time.0
-                            };
-
-                            dbg!((target_bar_time, nearest_bar, target_time, milli_error));
-
-                            if milli_error < 100 {
-                                Some((entity, nearest_bar, milli_error))
-                            } else {
-                                None
+                InputEvent { keycode: Some(keycode), audio_time: event_time, .. } => {
+                    let press_time = latency_corrected(event_time);
+                    let lanes: Vec<Keycode> = if single_key_mode.0 && lane_index.lanes.contains_key(&keycode) {
+                        lane_index.lanes.keys().cloned().collect()
+                    } else {
+                        vec![keycode]
+                    };
+                    // A note playable from several keys is in several lanes.
+                    let mut in_range: Vec<Entity> = lanes.iter()
+                        .flat_map(|&lane| lane_index.candidates(lane, press_time, search_radius))
+                        .collect();
+                    in_range.sort();
+                    in_range.dedup();
+                    let candidates: Vec<_> = in_range
+                        .into_iter()
+                        .filter(|&entity| bar_index_storage.get(entity).is_none())
+                        .filter_map(|entity| target_bar_time_storage.get(entity).map(|target_bar_time| (entity, target_bar_time)))
+                        .map(|(entity, target_bar_time)| {
+                            let target_time = target_bar_time.0 as i64;
+                            let early = press_time < target_time;
+                            let milli_error = (press_time - target_time).unsigned_abs();
+
+                            if debug_flag.0 {
+                                log::debug!("{:?} error {}ms", target_bar_time, milli_error);
                             }
+
+                            (entity, press_time - target_time, milli_error, early)
                         }).collect();
 
-                    for hit in targets_hit {
-                        if let Err(err) = bar_index_storage.insert(hit.0, BarIndex(hit.1)) {
-                            dbg!(err);
+                    // A press only ever takes the nearest note in its lane, stacked notes each need their own.
+                    let target_hit = candidates.iter()
+                        .filter(|&&(_, _, milli_error, early)| {
+                            hit_window.accepts(milli_error, early) && milli_error <= judgement_windows.good && timing_filter.accepts(early)
+                        })
+                        .min_by_key(|&&(_, _, milli_error, _)| milli_error);
+
+                    if target_hit.is_none() && !lanes.iter().all(|&lane| lane_index.lane_is_empty(lane)) {
+                        let nearest_error = candidates.iter().map(|&(_, _, milli_error, _)| milli_error).min();
+                        if nearest_error.is_none_or(|nearest_error| nearest_error >= search_radius) {
+                            combo_breaks.0.push(judgement_history.0.len());
+                        }
+                    }
+
+                    if let Some(hit) = target_hit {
+                        if let Err(err) = bar_index_storage.insert(hit.0, BarIndex(0)) {
+                            log::warn!("Couldn't tag hit target: {:?}", err);
+                        }
+                        let target_input = target_input_storage.get(hit.0);
+                        lane_index.remove(target_input.map_or(&[keycode][..], |input| &input.0[..]), hit.0);
+
+                        // Reported in the target's own lane even when an alternate key hit it.
+                        let key = target_input.map_or(keycode, TargetInput::lane_key);
+                        let scored_hit = ScoredHit { entity: hit.0, key, judgement: judgement_windows.judge(hit.2), error: hit.1, press_time };
+                        let ready = match chord_group_storage.get(hit.0) {
+                            Some(&group) => {
+                                let chord_size = chord_group_storage.join().filter(|&&member| member == group).count();
+                                let complete = {
+                                    let pending = pending_chords.0.entry(group)
+                                        .or_insert_with(|| PendingChord { first_press: press_time, hits: Vec::new() });
+                                    pending.hits.push(scored_hit);
+                                    pending.hits.len() >= chord_size
+                                };
+                                if complete {
+                                    pending_chords.0.remove(&group).map_or_else(Vec::new, |pending| pending.hits)
+                                } else {
+                                    Vec::new()
+                                }
+                            },
+                            None => vec![scored_hit],
+                        };
+
+                        for scored_hit in ready {
+                            if let Err(err) = judged_storage.insert(scored_hit.entity, Judged(scored_hit.judgement)) {
+                                log::warn!("Couldn't judge hit target: {:?}", err);
+                            }
+                            if let Err(err) = hit_offset_storage.insert(scored_hit.entity, HitOffset(scored_hit.error)) {
+                                log::warn!("Couldn't record hit offset: {:?}", err);
+                            }
+                            judgement_history.0.push(scored_hit.judgement);
+                            input_timeline.record(scored_hit.press_time, scored_hit.key, scored_hit.judgement);
+                            latency_calibration.samples.push(scored_hit.error);
+                            last_hit_error.0 = Some(scored_hit.error);
+                            last_hit_key.0 = Some(scored_hit.key);
+                            last_hit_note.0 = Some(scored_hit.entity);
                         }
                     }
                 },
                 _ => {},
             }
         }
+
+        // A note nobody pressed is missed once it's too late for any press to hit it. Chord members
+        // still waiting on the rest of a chord are left to its expiry above.
+        let late_edge = hit_window.late.min(judgement_windows.good + 1) as i64;
+        for entity in lane_index.take_passed(now - late_edge) {
+            if bar_index_storage.get(entity).is_some() {
+                continue;
+            }
+            if chord_group_storage.get(entity).is_some_and(|group| pending_chords.0.contains_key(group)) {
+                continue;
+            }
+            let keys = match target_input_storage.get(entity) {
+                Some(input) => input.0.clone(),
+                None => continue,
+            };
+            if let Err(err) = bar_index_storage.insert(entity, BarIndex(0)) {
+                log::warn!("Couldn't tag missed target: {:?}", err);
+            }
+            lane_index.remove(&keys, entity);
+            if let Err(err) = judged_storage.insert(entity, Judged(Judgement::Miss)) {
+                log::warn!("Couldn't judge missed target: {:?}", err);
+            }
+            judgement_history.0.push(Judgement::Miss);
+            input_timeline.record(now, keys[0], Judgement::Miss);
+        }
+    }
+}
+
+
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(Copy)]
+pub struct HoldDuration(pub u64);
+
+impl Component for HoldDuration {
+    type Storage = VecStorage<Self>;
+}
+
+#[derive(Debug)]
+#[derive(Default)]
+#[derive(Clone)]
+#[derive(Copy)]
+pub struct HoldTicks {
+    pub ticks: u32,
+    pub released: bool,
+}
+
+impl Component for HoldTicks {
+    type Storage = VecStorage<Self>;
+}
+
+pub struct HoldTickInterval(pub u64);
+
+// Every hold tick awarded so far, counted on across songs like JudgementHistory so ScoringSystem
+// can score the ones it hasn't seen yet.
+#[derive(Default)]
+pub struct HoldTickCount(pub u64);
+
+impl Default for HoldTickInterval {
+    fn default() -> HoldTickInterval { HoldTickInterval(100) }
+}
+
+pub(crate) struct HoldTickSystem;
+
+impl<'a> System<'a> for HoldTickSystem {
+    type SystemData = (Entities<'a>,
+                       Read<'a, AudioTime>,
+                       Read<'a, AudioContext>,
+                       Read<'a, HoldTickInterval>,
+                       Read<'a, HeldKeys>,
+                       Read<'a, GameState>,
+                       Write<'a, HoldTickCount>,
+                       ReadStorage<'a, TargetInput>,
+                       ReadStorage<'a, TargetBarTime>,
+                       ReadStorage<'a, BarIndex>,
+                       ReadStorage<'a, HoldDuration>,
+                       WriteStorage<'a, HoldTicks>);
+
+    fn run(&mut self, data: Self::SystemData) {
+        let (
+            entities,
+            audio_time,
+            audio_context,
+            hold_tick_interval,
+            held_keys,
+            game_state,
+            mut hold_tick_count,
+            target_input_storage,
+            target_bar_time_storage,
+            bar_index_storage,
+            hold_duration_storage,
+            mut hold_ticks_storage,
+        ) = data;
+
+        if *game_state != GameState::Playing {
+            return;
+        }
+
+        let interval = hold_tick_interval.0.max(1);
+
+        for (entity, input, target_bar_time, bar_index, hold_duration) in (&*entities, &target_input_storage, &target_bar_time_storage, &bar_index_storage, &hold_duration_storage).join() {
+            let hold_start = bar_index.0 * audio_context.bar_millis + target_bar_time.0;
+            let hold_end = hold_start + hold_duration.0;
+            if audio_time.0 < hold_start {
+                continue;
+            }
+
+            if hold_ticks_storage.get(entity).is_none() {
+                if let Err(err) = hold_ticks_storage.insert(entity, HoldTicks::default()) {
+                    log::warn!("Couldn't start hold ticks: {:?}", err);
+                    continue;
+                }
+            }
+
+            if let Some(hold_ticks) = hold_ticks_storage.get_mut(entity) {
+                if hold_ticks.released {
+                    continue;
+                }
+
+                if input.0.iter().any(|key| held_keys.0.contains(key)) {
+                    let held_until = audio_time.0.min(hold_end);
+                    let ticks = ((held_until - hold_start) / interval) as u32;
+                    hold_tick_count.0 += ticks.saturating_sub(hold_ticks.ticks) as u64;
+                    hold_ticks.ticks = ticks;
+                } else if audio_time.0 < hold_end {
+                    hold_ticks.released = true;
+                }
+            }
+        }
+    }
+}
+
+pub(crate) struct ApproachRingSystem;
+
+impl<'a> System<'a> for ApproachRingSystem {
+    type SystemData = (Read<'a, InterpolatedAudioTime>,
+                       Read<'a, AudioContext>,
+                       Read<'a, LaneLayout>,
+                       Read<'a, ScrollDirection>,
+                       Read<'a, JudgementLine>,
+                       Read<'a, LeadIn>,
+                       Write<'a, ApproachRing>,
+                       ReadStorage<'a, TargetInput>,
+                       ReadStorage<'a, TargetBarTime>,
+                       ReadStorage<'a, BarIndex>);
+
+    fn run(&mut self, data: Self::SystemData) {
+        let (
+            audio_time,
+            audio_context,
+            lane_layout,
+            direction,
+            judgement_line,
+            lead_in,
+            mut approach_ring,
+            target_input_storage,
+            target_bar_time_storage,
+            bar_index_storage,
+        ) = data;
+
+        let nearest = (&target_input_storage, &target_bar_time_storage, !&bar_index_storage)
+            .join()
+            .map(|(input, target_bar_time, _)| (audio_context.next_target_time(*target_bar_time, audio_time.0) - audio_time.0 + lead_in.0, input.lane_key()))
+            .min_by_key(|&(time_to_hit, _)| time_to_hit);
+
+        approach_ring.radius = None;
+        if let Some((time_to_hit, key)) = nearest {
+            if let Some((x, y)) = lane_layout.point(*direction, &judgement_line, key, 0.0) {
+                *approach_ring = ApproachRing { radius: approach_ring_radius(time_to_hit), x, y };
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use specs::RunNow;
+
+    use std::time::Instant;
+
+    use crate::{
+        chart::Chart,
+        session::SessionSettings,
+        simulator::Simulator,
+    };
+
+    // 120 BPM in 4/4 with no offset, so beats are 500ms apart and bars 2000ms.
+    fn chart(notes: &str) -> Chart {
+        Chart::from_reader(format!("bpm 120000\naudio song.ogg\n{}", notes).as_bytes()).unwrap()
+    }
+
+    fn hold_world(duration: u64) -> World {
+        let mut world = World::new();
+        world.register::<TargetInput>();
+        world.register::<TargetBarTime>();
+        world.register::<BarIndex>();
+        world.register::<HoldDuration>();
+        world.register::<HoldTicks>();
+        world.add_resource(AudioTime(0));
+        world.add_resource(AudioContext::new(120_000, 0, 4));
+        world.add_resource(HoldTickInterval(100));
+        world.add_resource(HeldKeys::default());
+        world.add_resource(GameState::Playing);
+        world.add_resource(HoldTickCount::default());
+        world.create_entity()
+            .with(TargetInput(vec![Keycode::D]))
+            .with(TargetBarTime(500))
+            .with(BarIndex(0))
+            .with(HoldDuration(duration))
+            .build();
+        world
+    }
+
+    fn hold_step(world: &mut World, time: u64, held: bool) -> Option<HoldTicks> {
+        world.write_resource::<AudioTime>().0 = time;
+        {
+            let mut held_keys = world.write_resource::<HeldKeys>();
+            held_keys.0.clear();
+            if held {
+                held_keys.0.insert(Keycode::D);
+            }
+        }
+        HoldTickSystem.run_now(&world.res);
+        world.maintain();
+        let hold_ticks_storage = world.read_storage::<HoldTicks>();
+        hold_ticks_storage.join().next().cloned()
+    }
+
+    #[test]
+    fn tuplets_land_on_their_exact_times() {
+        for &milli_bpm in &[120_000, 140_000, 159_850, 97_000] {
+            let ctx = AudioContext::new(milli_bpm, 0, 4);
+            let beat = 60_000_000.0 / milli_bpm as f64;
+            for &division in &[3, 5, 6] {
+                // Four bars' worth, far enough for truncating the beat to have drifted.
+                for index in 0..=16 * division {
+                    let exact = (beat * index as f64 / division as f64).round() as u64;
+                    assert_eq!(ctx.make_bar_time(1, division, index).0, exact, "{} BPM, {}/{}", milli_bpm, index, division);
+                }
+            }
+        }
+
+        // The last triplet of a 140 BPM bar is 1714.29ms in; 428ms beats would put it at 1712.
+        assert_eq!(AudioContext::new(140_000, 0, 4).make_bar_time(1, 3, 12).0, 1714);
+        assert_eq!(AudioContext::new(120_000, 0, 4).make_bar_time(2, 3, 1).0, 333);
+    }
+
+    #[test]
+    fn musical_position_turns_over_on_bar_and_beat_boundaries() {
+        let ctx = AudioContext::new(120_000, 0, 4);
+        let positions: Vec<_> = [0, 499, 500, 1250, 1999, 2000, 2750, 9999].iter()
+            .map(|&audio_time| musical_position(audio_time, &ctx))
+            .collect();
+        assert_eq!(positions, vec![(0, 0), (0, 0), (0, 1), (0, 2), (0, 3), (1, 0), (1, 1), (4, 3)]);
+
+        // audio_time already counts from the first beat, so the offset doesn't move anything.
+        assert_eq!(musical_position(500, &AudioContext::new(120_000, 300, 4)), (0, 1));
+        assert_eq!(musical_position(1499, &AudioContext::new(120_000, 0, 3)), (0, 2));
+        assert_eq!(musical_position(1500, &AudioContext::new(120_000, 0, 3)), (1, 0));
+    }
+
+    #[test]
+    fn musical_position_keeps_a_rounded_last_beat_in_its_bar() {
+        // 375ms beats but 1501ms bars, so the last ms of the bar would be a fifth beat.
+        let ctx = AudioContext::new(159_850, 110, 4);
+        assert_eq!((ctx.beat_millis, ctx.bar_millis), (375, 1501));
+        assert_eq!(musical_position(1499, &ctx), (0, 3));
+        assert_eq!(musical_position(1500, &ctx), (0, 3));
+        assert_eq!(musical_position(1501, &ctx), (1, 0));
+        assert_eq!(musical_position(1000, &AudioContext::default()), (0, 0));
+    }
+
+    #[test]
+    fn beat_phase_starts_over_on_each_beat_and_bar() {
+        let ctx = AudioContext::new(120_000, 0, 4);
+        let phase = |audio_time| {
+            let phase = BeatPhase::at(audio_time, &ctx);
+            (phase.beat, phase.bar)
+        };
+        assert_eq!(phase(0), (0.0, 0.0));
+        assert_eq!(phase(250), (0.5, 0.125));
+        assert_eq!(phase(499), (0.998, 0.2495));
+        assert_eq!(phase(500), (0.0, 0.25));
+        assert_eq!(phase(1999), (0.998, 0.9995));
+        assert_eq!(phase(2000), (0.0, 0.0));
+        assert_eq!(phase(3000), (0.0, 0.5));
+    }
+
+    #[test]
+    fn beat_phase_stretches_the_last_beat_over_a_rounded_bar() {
+        // 375ms beats in a 1501ms bar leave the last beat 376ms long, so it reaches 1.0 a ms
+        // before the bar ends rather than wrapping.
+        let ctx = AudioContext::new(159_850, 0, 4);
+        assert_eq!(BeatPhase::at(1125, &ctx).beat, 0.0);
+        assert_eq!(BeatPhase::at(1500, &ctx), BeatPhase { beat: 1.0, bar: 1500.0 / 1501.0 });
+        assert_eq!(BeatPhase::at(1501, &ctx), BeatPhase { beat: 0.0, bar: 0.0 });
+        assert_eq!(BeatPhase::at(700, &AudioContext::default()), BeatPhase::default());
+    }
+
+    #[test]
+    fn held_note_ticks_every_interval_until_its_end() {
+        let mut world = hold_world(500);
+        assert_eq!(hold_step(&mut world, 400, true).map(|hold| hold.ticks), None);
+        assert_eq!(hold_step(&mut world, 550, true).map(|hold| hold.ticks), Some(0));
+        assert_eq!(hold_step(&mut world, 750, true).map(|hold| hold.ticks), Some(2));
+        assert_eq!(hold_step(&mut world, 999, true).map(|hold| hold.ticks), Some(4));
+        assert_eq!(hold_step(&mut world, 1400, true).map(|hold| hold.ticks), Some(5));
+        assert_eq!(world.read_resource::<HoldTickCount>().0, 5);
+    }
+
+    #[test]
+    fn released_hold_stops_ticking() {
+        let mut world = hold_world(500);
+        hold_step(&mut world, 720, true);
+        let released = hold_step(&mut world, 800, false).unwrap();
+        assert!(released.released);
+        assert_eq!(released.ticks, 2);
+        assert_eq!(hold_step(&mut world, 950, true).map(|hold| hold.ticks), Some(2));
+        assert_eq!(world.read_resource::<HoldTickCount>().0, 2);
+    }
+
+    #[test]
+    fn early_only_filter_scores_early_hits_and_ignores_late_ones() {
+        let chart = chart("note 1 1 1 D\nnote 1 1 2 D\n");
+        let settings = SessionSettings { timing_filter: TimingFilter::EarlyOnly, ..SessionSettings::default() };
+        let mut simulator = Simulator::new(&chart, &settings);
+        simulator.press(Keycode::D, 530);
+        assert_eq!(simulator.note_judgements(), vec![None, None]);
+        // The late press never counted, so the first note goes by unhit.
+        simulator.press(Keycode::D, 970);
+        assert_eq!(simulator.note_judgements(), vec![Some(Judgement::Miss), Some(Judgement::Great)]);
+    }
+
+    #[test]
+    fn presses_past_the_window_keep_the_combo_only_inside_the_grace() {
+        let chart = chart("note 1 1 1 D\nnote 1 1 2 D\nnote 1 1 3 D\n");
+        let settings = SessionSettings { combo_grace: 30, ..SessionSettings::default() };
+        let mut simulator = Simulator::new(&chart, &settings);
+        simulator.press(Keycode::D, 500);
+        assert_eq!(simulator.combo(), 1);
+
+        // 115ms early for the second note: outside the 100ms window, inside the 30ms grace.
+        simulator.press(Keycode::D, 885);
+        assert_eq!(simulator.combo(), 1);
+        assert_eq!(simulator.note_judgements(), vec![Some(Judgement::Perfect), None, None]);
+
+        simulator.press(Keycode::D, 1000);
+        assert_eq!(simulator.combo(), 2);
+
+        // 150ms early for the third, past the grace.
+        simulator.press(Keycode::D, 1350);
+        assert_eq!(simulator.combo(), 0);
+    }
+
+    #[test]
+    fn unpressed_note_is_missed_once_past_the_late_window() {
+        let chart = chart("note 1 1 1 D\nnote 1 1 2 D\n");
+        let mut simulator = Simulator::new(&chart, &SessionSettings::default());
+        simulator.press(Keycode::D, 500);
+        simulator.advance_to(1099);
+        assert_eq!(simulator.note_judgements(), vec![Some(Judgement::Perfect), None]);
+        assert_eq!(simulator.combo(), 1);
+
+        // A press exactly at the 100ms bound would miss, so that's when the note goes.
+        simulator.advance_to(1100);
+        assert_eq!(simulator.note_judgements(), vec![Some(Judgement::Perfect), Some(Judgement::Miss)]);
+        assert_eq!(simulator.judgements(), vec![Judgement::Perfect, Judgement::Miss]);
+        assert_eq!(simulator.combo(), 0);
+
+        // Missed once, not again on every step after.
+        simulator.advance_to(1500);
+        assert_eq!(simulator.judgements().len(), 2);
+    }
+
+    #[test]
+    fn one_press_hits_only_the_nearest_of_two_close_notes() {
+        // 500ms and 530ms, both in the window of a press at 520.
+        let chart = chart("note 1 1000 1000 D\nnote 1 1000 1060 D\n");
+        let mut simulator = Simulator::new(&chart, &SessionSettings::default());
+        simulator.press(Keycode::D, 520);
+        assert_eq!(simulator.note_judgements(), vec![None, Some(Judgement::Perfect)]);
+        assert_eq!(simulator.judgements().len(), 1);
+
+        simulator.press(Keycode::D, 560);
+        assert_eq!(simulator.note_judgements(), vec![Some(Judgement::Good), Some(Judgement::Perfect)]);
+    }
+
+    #[test]
+    fn either_key_hits_a_multi_key_note() {
+        let chart = chart("note 1 1 1 A+;\nnote 1 1 2 A+;\n");
+        assert_eq!(chart.notes[0].keys, vec![Keycode::A, Keycode::Semicolon]);
+        let mut simulator = Simulator::new(&chart, &SessionSettings::default());
+        simulator.press(Keycode::Semicolon, 500);
+        simulator.press(Keycode::A, 1000);
+        assert_eq!(simulator.note_judgements(), vec![Some(Judgement::Perfect), Some(Judgement::Perfect)]);
+
+        // Keys outside the set hit nothing.
+        let mut simulator = Simulator::new(&chart, &SessionSettings::default());
+        simulator.press(Keycode::S, 500);
+        assert_eq!(simulator.note_judgements(), vec![None, None]);
+    }
+
+    #[test]
+    fn wider_early_bound_takes_a_press_the_late_one_would_not() {
+        let chart = chart("note 1 1 1 D\nnote 1 1 2 D\n");
+        let settings = SessionSettings { hit_window: 150, hit_window_late: Some(50), good_window: Some(150), ..SessionSettings::default() };
+        let mut simulator = Simulator::new(&chart, &settings);
+        // 120ms early for the first note, inside the 150ms early bound.
+        simulator.press(Keycode::D, 380);
+        assert_eq!(simulator.note_judgements(), vec![Some(Judgement::Good), None]);
+
+        // 120ms late for the second, outside the 50ms late bound, so it hits nothing.
+        simulator.press(Keycode::D, 1120);
+        assert_eq!(simulator.note_judgements(), vec![Some(Judgement::Good), Some(Judgement::Miss)]);
+        assert_eq!(simulator.judgements(), vec![Judgement::Good, Judgement::Miss]);
+    }
+
+    #[test]
+    fn single_key_mode_plays_a_rapid_run_on_one_key() {
+        // Sixteenths 125ms apart, going round the four lanes twice.
+        let notes: String = [0, 1, 2, 3, 4, 5, 6, 7].iter().zip(["D", "F", "J", "K"].iter().cycle())
+            .map(|(index, key)| format!("note 1 4 {} {}\n", index, key))
+            .collect();
+        let chart = chart(&notes);
+        let settings = SessionSettings { single_key_mode: true, ..SessionSettings::default() };
+        let mut simulator = Simulator::new(&chart, &settings);
+        for (index, &error) in [0i64, 10, -20, 30, -40, 60, 0, -5].iter().enumerate() {
+            simulator.press(Keycode::D, (index as i64 * 125 + error) as u64);
+        }
+        assert_eq!(simulator.judgements(), vec![
+            Judgement::Perfect,
+            Judgement::Perfect,
+            Judgement::Perfect,
+            Judgement::Great,
+            Judgement::Great,
+            Judgement::Good,
+            Judgement::Perfect,
+            Judgement::Perfect,
+        ]);
+        assert_eq!(simulator.combo(), 8);
+
+        // Without it the same presses only reach the D lane, and the notes in between go by.
+        let mut simulator = Simulator::new(&chart, &SessionSettings::default());
+        for index in 0..8 {
+            simulator.press(Keycode::D, index * 125);
+        }
+        let hit = simulator.note_judgements().iter().map(|judgement| *judgement == Some(Judgement::Perfect)).collect::<Vec<_>>();
+        assert_eq!(hit, vec![true, false, false, false, true, false, false, false]);
+    }
+
+    #[test]
+    fn timeline_keeps_every_judgement_in_order() {
+        let chart = chart("note 1 1 1 D\nnote 1 1 2 F\nnote 1 1 3 D\nnote 1 1 3 J\n");
+        let mut simulator = Simulator::new(&chart, &SessionSettings::default());
+        simulator.press(Keycode::D, 510);
+        simulator.press(Keycode::F, 955);
+        // J is pressed for the last pair, D is left to go by.
+        simulator.press(Keycode::J, 1580);
+        simulator.advance_to(1600);
+
+        let entry = |audio_time, lane, judgement| TimelineEntry { audio_time, lane, judgement };
+        let timeline = simulator.timeline();
+        assert_eq!(timeline.0, vec![
+            entry(510, Keycode::D, Judgement::Perfect),
+            entry(955, Keycode::F, Judgement::Great),
+            entry(1580, Keycode::J, Judgement::Good),
+            entry(1600, Keycode::D, Judgement::Miss),
+        ]);
+        assert_eq!(timeline.recent(2), &timeline.0[2..]);
+        assert_eq!(timeline.recent(10), &timeline.0[..]);
+
+        let json = serde_json::to_string(&timeline).unwrap();
+        assert_eq!(serde_json::from_str::<InputTimeline>(&json).unwrap(), timeline);
+    }
+
+    #[test]
+    fn full_chord_judges_every_member() {
+        let chart = chart("chord 1\nnote 1 1 1 D\nnote 1 1 1 F\nchord none\n");
+        let mut simulator = Simulator::new(&chart, &SessionSettings::default());
+        simulator.press(Keycode::D, 500);
+        // Held back until the rest of the chord is in.
+        assert_eq!(simulator.note_judgements(), vec![None, None]);
+        simulator.press(Keycode::F, 530);
+        assert_eq!(simulator.note_judgements(), vec![Some(Judgement::Perfect), Some(Judgement::Great)]);
+        assert_eq!(simulator.combo(), 2);
+    }
+
+    #[test]
+    fn partial_chord_misses_every_member_once_it_expires() {
+        let chart = chart("chord 1\nnote 1 1 1 D\nnote 1 1 1 F\nchord none\nnote 1 1 3 D\n");
+        let mut simulator = Simulator::new(&chart, &SessionSettings::default());
+        simulator.press(Keycode::D, 500);
+        simulator.advance_to(699);
+        assert_eq!(simulator.note_judgements(), vec![None, None, None]);
+
+        // The whole hit window after the first press, both members go, the pressed one too.
+        simulator.advance_to(700);
+        assert_eq!(simulator.note_judgements(), vec![Some(Judgement::Miss), Some(Judgement::Miss), None]);
+        assert_eq!(simulator.judgements(), vec![Judgement::Miss, Judgement::Miss]);
+        assert_eq!(simulator.combo(), 0);
+
+        simulator.press(Keycode::D, 1500);
+        assert_eq!(simulator.combo(), 1);
+    }
+
+    // A timing comparison more than a check, though the two have to agree on every press. Run it
+    // with `cargo test --release lane_index_against -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn bench_lane_index_against_a_linear_scan() {
+        const NOTES: u64 = 1000;
+        const PRESSES: u64 = 10_000;
+        let keys = [Keycode::D, Keycode::F, Keycode::J, Keycode::K];
+        // The thousand notes a 16th apart run on over 250 bars.
+        let audio_context = AudioContext::new(120_000, 0, 4);
+        let radius = HitWindow::default().widest();
+
+        let mut world = World::new();
+        world.register::<TargetInput>();
+        world.register::<TargetBarTime>();
+        world.register::<BarIndex>();
+        for note in 0..NOTES {
+            world.create_entity()
+                .with(TargetInput(vec![keys[note as usize % keys.len()]]))
+                .with(audio_context.make_bar_time(1, 4, note))
+                .build();
+        }
+
+        let entities = world.entities();
+        let target_input_storage = world.read_storage::<TargetInput>();
+        let target_bar_time_storage = world.read_storage::<TargetBarTime>();
+        let mut lane_index = LaneIndex::default();
+        lane_index.rebuild(&entities, &target_input_storage, &target_bar_time_storage, &world.write_storage::<BarIndex>());
+
+        let song_millis = audio_context.make_bar_time(1, 4, NOTES).0;
+        let presses: Vec<(Keycode, i64)> = (0..PRESSES)
+            .map(|press| (keys[press as usize % keys.len()], (press * song_millis / PRESSES) as i64))
+            .collect();
+        let error = |entity: Entity, time: i64| {
+            let target_time = target_bar_time_storage.get(entity).map_or(0, |target_bar_time| target_bar_time.0);
+            (time - target_time as i64).unsigned_abs()
+        };
+
+        let start = Instant::now();
+        let linear: Vec<Option<Entity>> = presses.iter()
+            .map(|&(key, time)| {
+                (&*entities, &target_input_storage).join()
+                    .filter(|&(_, input)| input.0.contains(&key))
+                    .map(|(entity, _)| (entity, error(entity, time)))
+                    .filter(|&(_, milli_error)| milli_error < radius)
+                    .min_by_key(|&(_, milli_error)| milli_error)
+                    .map(|(entity, _)| entity)
+            })
+            .collect();
+        let linear_time = start.elapsed();
+
+        let start = Instant::now();
+        let indexed: Vec<Option<Entity>> = presses.iter()
+            .map(|&(key, time)| {
+                lane_index.advance(time, radius);
+                lane_index.candidates(key, time, radius).into_iter()
+                    .map(|entity| (entity, error(entity, time)))
+                    .filter(|&(_, milli_error)| milli_error < radius)
+                    .min_by_key(|&(_, milli_error)| milli_error)
+                    .map(|(entity, _)| entity)
+            })
+            .collect();
+        let indexed_time = start.elapsed();
+
+        assert_eq!(indexed, linear);
+        println!("{} presses over {} notes: linear scan {:?}, LaneIndex {:?}", PRESSES, NOTES, linear_time, indexed_time);
+    }
+
+    #[test]
+    fn press_only_reaches_the_notes_near_it() {
+        // A hundred notes a 16th apart over 25 bars.
+        let audio_context = AudioContext::new(120_000, 0, 4);
+        let mut world = World::new();
+        world.register::<TargetInput>();
+        world.register::<TargetBarTime>();
+        world.register::<BarIndex>();
+        let notes: Vec<Entity> = (0..100)
+            .map(|note| world.create_entity()
+                .with(TargetInput(vec![Keycode::D]))
+                .with(audio_context.make_bar_time(1, 4, note))
+                .build())
+            .collect();
+
+        let mut lane_index = LaneIndex::default();
+        lane_index.rebuild(&world.entities(), &world.read_storage(), &world.read_storage(), &world.write_storage());
+        let radius = HitWindow::default().widest();
+
+        lane_index.advance(5000, radius);
+        assert_eq!(lane_index.candidates(Keycode::D, 5000, radius), vec![notes[40]]);
+        lane_index.advance(5060, radius);
+        assert_eq!(lane_index.candidates(Keycode::D, 5060, radius), vec![notes[40], notes[41]]);
+        assert!(lane_index.candidates(Keycode::F, 5000, radius).is_empty());
+
+        // Later bars' notes are only in reach at their own time, never a bar earlier or later.
+        lane_index.advance(12400, radius);
+        assert_eq!(lane_index.candidates(Keycode::D, 12400, radius), vec![notes[99]]);
+        lane_index.advance(12480, radius);
+        assert!(lane_index.candidates(Keycode::D, 12480, radius).is_empty());
+
+        // Seeking back across bars brings the earlier notes back into reach.
+        lane_index.advance(1990, radius);
+        assert_eq!(lane_index.candidates(Keycode::D, 1990, radius), vec![notes[16]]);
+        lane_index.advance(2060, radius);
+        assert_eq!(lane_index.candidates(Keycode::D, 2060, radius), vec![notes[16], notes[17]]);
     }
 }
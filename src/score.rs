@@ -0,0 +1,179 @@
+use serde::{Deserialize, Serialize};
+
+use specs::prelude::*;
+
+use crate::{
+    combo::{count_combo, ComboEvents},
+    rhythm::{Combo, ComboBreaks, HoldTickCount, Judgement, JudgementHistory},
+};
+
+// Points per judgement, multiplied by the combo: 1x to start with, one more for every
+// `multiplier_step` hits in a row before this one, up to `max_multiplier`. A miss scores its own
+// points, normally none, and starts the multiplier over. Each tick of a held note scores
+// `hold_tick` points and counts toward the multiplier like a hit.
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(PartialEq)]
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct ScoreWeights {
+    pub perfect: u64,
+    pub great: u64,
+    pub good: u64,
+    pub miss: u64,
+    pub hold_tick: u64,
+    pub multiplier_step: u32,
+    pub max_multiplier: u32,
+}
+
+impl Default for ScoreWeights {
+    fn default() -> ScoreWeights {
+        ScoreWeights { perfect: 300, great: 100, good: 50, miss: 0, hold_tick: 10, multiplier_step: 10, max_multiplier: 4 }
+    }
+}
+
+impl ScoreWeights {
+    pub fn points(&self, judgement: Judgement) -> u64 {
+        match judgement {
+            Judgement::Perfect => self.perfect,
+            Judgement::Great => self.great,
+            Judgement::Good => self.good,
+            Judgement::Miss => self.miss,
+        }
+    }
+
+    // A step of 0 never raises the multiplier, and a cap of 0 is treated as 1.
+    pub fn multiplier(&self, streak: u32) -> u32 {
+        let raised = match self.multiplier_step {
+            0 => 0,
+            step => streak / step,
+        };
+        (1 + raised).min(self.max_multiplier.max(1))
+    }
+}
+
+#[derive(Debug)]
+#[derive(Default)]
+pub struct Score(pub u64);
+
+// Keeps Combo as well as the score, walking JudgementHistory with the ComboBreaks among it, so
+// each judgement is multiplied by the combo it was made on even when several land in one step,
+// and every change to the combo makes its ComboEvents. Hold ticks are scored after the step's
+// judgements and add to Combo too.
+#[derive(Default)]
+pub struct ScoringSystem {
+    judgements_seen: usize,
+    breaks_seen: usize,
+    ticks_seen: u64,
+}
+
+impl<'a> System<'a> for ScoringSystem {
+    type SystemData = (Read<'a, ScoreWeights>,
+                       Read<'a, JudgementHistory>,
+                       Read<'a, ComboBreaks>,
+                       Read<'a, HoldTickCount>,
+                       Write<'a, Score>,
+                       Write<'a, Combo>,
+                       Write<'a, ComboEvents>);
+
+    fn run(&mut self, data: Self::SystemData) {
+        let (score_weights, judgement_history, combo_breaks, hold_tick_count, mut score, mut combo, mut combo_events) = data;
+
+        let mut breaks = combo_breaks.0.get(self.breaks_seen..).unwrap_or(&[]).iter().peekable();
+        for (index, &judgement) in judgement_history.0.iter().enumerate().skip(self.judgements_seen) {
+            while breaks.peek().is_some_and(|&&at| at <= index) {
+                breaks.next();
+                count_combo(&mut combo.0, false, &mut combo_events.pending);
+            }
+            score.0 += score_weights.points(judgement) * score_weights.multiplier(combo.0) as u64;
+            count_combo(&mut combo.0, judgement != Judgement::Miss, &mut combo_events.pending);
+        }
+        // Whatever breaks are left came after every judgement so far.
+        if breaks.next().is_some() {
+            count_combo(&mut combo.0, false, &mut combo_events.pending);
+        }
+        self.judgements_seen = judgement_history.0.len();
+        self.breaks_seen = combo_breaks.0.len();
+
+        for _ in self.ticks_seen..hold_tick_count.0 {
+            score.0 += score_weights.hold_tick * score_weights.multiplier(combo.0) as u64;
+            count_combo(&mut combo.0, true, &mut combo_events.pending);
+        }
+        self.ticks_seen = self.ticks_seen.max(hold_tick_count.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use specs::RunNow;
+
+    fn scoring_world(score_weights: ScoreWeights) -> World {
+        let mut world = World::new();
+        world.add_resource(score_weights);
+        world.add_resource(JudgementHistory::default());
+        world.add_resource(ComboBreaks::default());
+        world.add_resource(HoldTickCount::default());
+        world.add_resource(Score::default());
+        world.add_resource(Combo::default());
+        world.add_resource(ComboEvents::default());
+        world
+    }
+
+    #[test]
+    fn hold_ticks_score_and_count_toward_the_combo() {
+        let world = scoring_world(ScoreWeights { hold_tick: 10, multiplier_step: 2, ..ScoreWeights::default() });
+        let mut scoring_system = ScoringSystem::default();
+        world.write_resource::<HoldTickCount>().0 = 3;
+        scoring_system.run_now(&world.res);
+        // 1x, 1x, then 2x once two ticks are in.
+        assert_eq!(world.read_resource::<Score>().0, 40);
+        assert_eq!(world.read_resource::<Combo>().0, 3);
+
+        scoring_system.run_now(&world.res);
+        assert_eq!(world.read_resource::<Score>().0, 40);
+        assert_eq!(world.read_resource::<Combo>().0, 3);
+    }
+
+    #[test]
+    fn judgements_score_their_weights() {
+        let world = scoring_world(ScoreWeights { multiplier_step: 0, ..ScoreWeights::default() });
+        world.write_resource::<JudgementHistory>().0 = vec![Judgement::Perfect, Judgement::Great, Judgement::Good, Judgement::Miss];
+        ScoringSystem::default().run_now(&world.res);
+        assert_eq!(world.read_resource::<Score>().0, 300 + 100 + 50);
+        assert_eq!(world.read_resource::<Combo>().0, 0);
+    }
+
+    #[test]
+    fn multiplier_stops_at_its_cap() {
+        let weights = ScoreWeights { perfect: 1, multiplier_step: 1, max_multiplier: 3, ..ScoreWeights::default() };
+        let world = scoring_world(weights);
+        world.write_resource::<JudgementHistory>().0 = vec![Judgement::Perfect; 5];
+        ScoringSystem::default().run_now(&world.res);
+        // 1x, 2x, then 3x for the rest.
+        assert_eq!(world.read_resource::<Score>().0, 1 + 2 + 3 + 3 + 3);
+        assert_eq!(world.read_resource::<Combo>().0, 5);
+        assert_eq!(weights.multiplier(1000), 3);
+        assert_eq!(ScoreWeights { max_multiplier: 0, ..weights }.multiplier(1000), 1);
+    }
+
+    #[test]
+    fn multiplier_follows_the_combo() {
+        let world = scoring_world(ScoreWeights { perfect: 1, multiplier_step: 2, ..ScoreWeights::default() });
+        let mut scoring_system = ScoringSystem::default();
+        world.write_resource::<Combo>().0 = 4;
+        world.write_resource::<JudgementHistory>().0 = vec![Judgement::Perfect];
+        scoring_system.run_now(&world.res);
+        assert_eq!(world.read_resource::<Score>().0, 3);
+
+        // A stray press between the next two hits starts the multiplier over.
+        world.write_resource::<JudgementHistory>().0.push(Judgement::Perfect);
+        world.write_resource::<ComboBreaks>().0.push(2);
+        world.write_resource::<JudgementHistory>().0.push(Judgement::Perfect);
+        scoring_system.run_now(&world.res);
+        assert_eq!(world.read_resource::<Score>().0, 3 + 3 + 1);
+        assert_eq!(world.read_resource::<Combo>().0, 1);
+    }
+}
@@ -1,102 +1,618 @@
 use sdl2::{
-    event::Event,
+    event::{Event, WindowEvent},
+    gfx::primitives::DrawRenderer,
     keyboard::Keycode,
+    mouse::MouseButton,
     pixels::Color as SdlColor,
-    render::Canvas,
-    video::Window,
+    rect::Rect,
+    render::{Canvas, Texture, TextureCreator},
+    surface::Surface,
+    ttf::Font,
+    video::{FullscreenType, Window, WindowContext},
     EventPump,
     Sdl,
+    TimerSubsystem,
+    VideoSubsystem,
 };
 
 use specs::prelude::*;
 
-use crate::render::{
-    ClearColor,
-    Color,
-    Position,
-    Rectangle,
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
 };
 
-impl Into<SdlColor> for Color {
-    fn into(self) -> SdlColor {
-        SdlColor::RGB(self.r, self.g, self.b)
+use crate::{
+    InterpolatedAudioTime,
+    SongTime,
+    StepLag,
+    countdown::LeadIn,
+    draw::{DrawList, DrawRect, Renderer},
+    lanes::Receptor,
+    render::{
+        Circle,
+        Color,
+        Position,
+        Rectangle,
+        Viewport,
+    },
+    settings::{KeyBindings, WindowConfig},
+    song::PlaybackRate,
+    state::GameState,
+};
+
+impl From<Color> for SdlColor {
+    fn from(color: Color) -> SdlColor {
+        SdlColor::RGBA(color.r, color.g, color.b, color.a)
+    }
+}
+
+impl From<DrawRect> for Rect {
+    fn from(rect: DrawRect) -> Rect {
+        Rect::new(rect.x, rect.y, rect.width, rect.height)
     }
 }
 
 #[derive(Debug)]
+#[derive(Clone)]
+#[derive(Copy)]
 pub struct InputEvent {
     pub timestamp: u32,
     pub keycode: Option<Keycode>,
+    // When the event happened in SongTime, not when it was polled, so negative during the lead-in.
+    // See SdlInputSystem.
+    pub audio_time: i64,
+}
+
+pub const INPUT_EVENT_CAPACITY: usize = 64;
+
+// `events` are this update step's, at most INPUT_EVENT_CAPACITY of them. When a step floods past
+// that, the oldest events are dropped in favour of the newest ones and counted in `dropped`, which
+// is never reset so an overlay can show that flooding happened at all. `pending` holds what's been
+// polled but not yet reached by an update step, in the order it was polled, and `polled_at` the
+// SDL ticks it was last polled at.
+pub struct InputEvents {
+    pub events: Vec<InputEvent>,
+    pub pending: Vec<InputEvent>,
+    pub polled_at: u32,
+    pub dropped: u64,
+}
+
+impl Default for InputEvents {
+    fn default() -> InputEvents {
+        InputEvents {
+            events: Vec::with_capacity(INPUT_EVENT_CAPACITY),
+            pending: Vec::with_capacity(INPUT_EVENT_CAPACITY),
+            polled_at: 0,
+            dropped: 0,
+        }
+    }
+}
+
+impl InputEvents {
+    pub fn push(&mut self, event: InputEvent) {
+        self.events.push(event);
+        self.trim();
+    }
+
+    // Starts an update step that ends at `ticks`, handing it every pending event up to then.
+    pub fn release_until(&mut self, ticks: f64) {
+        self.events.clear();
+        let due = self.pending.iter().take_while(|event| event.timestamp as f64 <= ticks).count();
+        self.events.extend(self.pending.drain(..due));
+        self.trim();
+    }
+
+    // Drops the oldest events past INPUT_EVENT_CAPACITY in one go.
+    fn trim(&mut self) {
+        let excess = self.events.len().saturating_sub(INPUT_EVENT_CAPACITY);
+        self.events.drain(..excess);
+        self.dropped += excess as u64;
+    }
 }
 
 #[derive(Default)]
-pub struct InputEvents(pub Vec<InputEvent>);
+pub struct HeldKeys(pub HashSet<Keycode>);
 
+pub const CLOCK_OFFSET_SMOOTHING: f64 = 0.05;
+
+// Event timestamps are SDL ticks, milliseconds since SDL was initialised, a clock unrelated to
+// audio_time. While the song plays the two advance together, so audio_time = ticks * rate + offset
+// for a constant offset in song milliseconds. It is averaged over frames, smoothing out the steps
+// the sample counter moves in, and dropped whenever the song isn't advancing, since a pause or
+// the lead-in shifts it.
 #[derive(Default)]
-pub struct SdlRects(Vec<(SdlColor, sdl2::rect::Rect)>);
+pub struct ClockOffset(pub Option<f64>);
 
-pub struct SdlSystem {
-    _sdl: Sdl,
-    canvas: Canvas<Window>,
+impl ClockOffset {
+    pub fn observe(&mut self, audio_time: i64, ticks: f64, rate: f64) {
+        let observed = audio_time as f64 - ticks * rate;
+        self.0 = Some(match self.0 {
+            Some(offset) => offset + (observed - offset) * CLOCK_OFFSET_SMOOTHING,
+            None => observed,
+        });
+    }
+
+    // Once a frame: observes while the song is advancing, and otherwise drops the offset so
+    // events fall back to the polled time until it advances again.
+    pub fn update(&mut self, advancing: bool, audio_time: i64, ticks: f64, rate: f64) {
+        if advancing {
+            self.observe(audio_time, ticks, rate);
+        } else {
+            self.0 = None;
+        }
+    }
+
+    pub fn to_audio_time(&self, timestamp: u32, rate: f64) -> Option<i64> {
+        self.0.map(|offset| (timestamp as f64 * rate + offset).round() as i64)
+    }
+}
+
+// The interpolated audio time is floored at zero like audio_time, which SongTime isn't, so below
+// zero SongTime stands in for it.
+fn signed_audio_time(interpolated_audio_time: &InterpolatedAudioTime, song_time: &SongTime) -> i64 {
+    interpolated_audio_time.0 as i64 + song_time.0.min(0)
+}
+
+// Where the window's top left corner goes: the configured position if a connected display shows it,
+// otherwise centered on the configured display, or on the primary one if that display doesn't
+// exist. None when SDL can't tell where any display is, leaving it to center the window itself.
+pub fn window_position(video_subsystem: &VideoSubsystem, config: &WindowConfig) -> Option<(i32, i32)> {
+    let displays = video_subsystem.num_video_displays().unwrap_or_else(|err| {
+        log::warn!("Couldn't count displays: {}", err);
+        0
+    });
+    let bounds: Vec<Rect> = (0..displays).filter_map(|index| video_subsystem.display_bounds(index).ok()).collect();
+
+    if let Some((x, y)) = config.position {
+        if bounds.iter().any(|display| display.contains_point((x, y))) {
+            return Some((x, y));
+        }
+        log::warn!("Window position {},{} is on no connected display, centering it instead", x, y);
+    }
+    let display = if config.display >= 0 && config.display < displays {
+        config.display
+    } else {
+        log::warn!("There's no display {}, using the primary one", config.display);
+        0
+    };
+    let display = video_subsystem.display_bounds(display).ok()?;
+    Some((
+        display.x() + (display.width() as i32 - config.width as i32) / 2,
+        display.y() + (display.height() as i32 - config.height as i32) / 2,
+    ))
+}
+
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(Copy)]
+pub enum WindowCommand {
+    ToggleFullscreen,
+}
+
+#[derive(Default)]
+pub struct WindowCommands(pub Vec<WindowCommand>);
+
+#[derive(Default)]
+pub struct WindowState {
+    pub fullscreen: bool,
+}
+
+// Drains SDL's event queue into the pending InputEvents and HeldKeys. It runs by hand at the top
+// of the frame, before any update step, so everything polled here is judged by the step its
+// timestamp falls in and drawn the same frame. As a thread-local it could only run after every
+// other system, a frame too late.
+pub(crate) struct SdlInputSystem {
     event_pump: EventPump,
+    timer: TimerSubsystem,
+    // The lane the left button went down on, released with the button.
+    clicked_lane: Option<Keycode>,
 }
 
-impl SdlSystem {
-    pub fn new(sdl: Sdl, canvas: Canvas<Window>, event_pump: EventPump) -> SdlSystem {
-        SdlSystem { _sdl: sdl, canvas, event_pump }
+impl SdlInputSystem {
+    pub fn new(event_pump: EventPump, timer: TimerSubsystem) -> SdlInputSystem {
+        SdlInputSystem { event_pump, timer, clicked_lane: None }
     }
 }
 
-impl<'a> System<'a> for SdlSystem {
+// A click on a receptor presses its lane key and holds it until the button comes back up, so it
+// goes down the same hit and hold paths as the keyboard. Receptors already carry lane keys, no
+// binding lookup needed.
+fn mouse_event(
+    event: &Event,
+    clicked_lane: &mut Option<Keycode>,
+    lane_at: impl Fn(i32, i32) -> Option<Keycode>,
+    held_keys: &mut HeldKeys,
+    pending: &mut Vec<InputEvent>,
+    audio_time: impl Fn(u32) -> i64,
+) {
+    match *event {
+        Event::MouseButtonDown { mouse_btn: MouseButton::Left, x, y, timestamp, .. } => {
+            if let Some(lane) = lane_at(x, y) {
+                held_keys.0.insert(lane);
+                *clicked_lane = Some(lane);
+                pending.push(InputEvent { keycode: Some(lane), timestamp, audio_time: audio_time(timestamp) });
+            }
+        },
+        Event::MouseButtonUp { mouse_btn: MouseButton::Left, .. } => {
+            if let Some(lane) = clicked_lane.take() {
+                held_keys.0.remove(&lane);
+            }
+        },
+        _ => {},
+    }
+}
+
+// Presses and releases a lane key. Held keys repeat KeyDown; only the first one is a press.
+fn key_event(
+    event: &Event,
+    key_bindings: &KeyBindings,
+    held_keys: &mut HeldKeys,
+    pending: &mut Vec<InputEvent>,
+    audio_time: impl Fn(u32) -> i64,
+) {
+    match *event {
+        Event::KeyDown { repeat: true, .. } => {},
+        Event::KeyDown { keycode, timestamp, .. } => {
+            let keycode = keycode.map(|keycode| key_bindings.lane_key(keycode));
+            if let Some(keycode) = keycode {
+                held_keys.0.insert(keycode);
+            }
+            pending.push(InputEvent { keycode, timestamp, audio_time: audio_time(timestamp) });
+        },
+        Event::KeyUp { keycode: Some(keycode), .. } => {
+            held_keys.0.remove(&key_bindings.lane_key(keycode));
+        },
+        _ => {},
+    }
+}
+
+impl<'a> System<'a> for SdlInputSystem {
     type SystemData = (Write<'a, InputEvents>,
-                       Read<'a, SdlRects>,
-                       Read<'a, ClearColor>);
+                       Write<'a, HeldKeys>,
+                       Write<'a, Viewport>,
+                       Read<'a, KeyBindings>,
+                       Read<'a, InterpolatedAudioTime>,
+                       Read<'a, SongTime>,
+                       Read<'a, PlaybackRate>,
+                       Read<'a, ClockOffset>,
+                       ReadStorage<'a, Receptor>,
+                       ReadStorage<'a, Position>,
+                       ReadStorage<'a, Rectangle>,
+                       ReadStorage<'a, Circle>);
 
     fn run(&mut self, data: Self::SystemData) {
-        let (mut input_events, sdl_rects, clear_color) = data;
+        let (
+            mut input_events,
+            mut held_keys,
+            mut viewport,
+            key_bindings,
+            audio_time,
+            song_time,
+            playback_rate,
+            clock_offset,
+            receptor_storage,
+            position_storage,
+            rect_storage,
+            circle_storage,
+        ) = data;
+
+        // Nothing has advanced the clock yet this frame, so the fallback is last frame's time.
+        let rate = playback_rate.0 as f64;
+        let polled_audio_time = signed_audio_time(&audio_time, &song_time);
+        let event_audio_time = |timestamp: u32| clock_offset.to_audio_time(timestamp, rate).unwrap_or(polled_audio_time);
+
+        // The lane of the receptor at a logical position, if any.
+        let receptor_lane = |(x, y): (f64, f64)| {
+            (&receptor_storage, &position_storage, rect_storage.maybe(), circle_storage.maybe()).join()
+                .find(|(_, pos, rect, circle)| {
+                    let in_rect = rect.is_some_and(|rect| (x - pos.x).abs() <= rect.width / 2.0 && (y - pos.y).abs() <= rect.height / 2.0);
+                    let in_circle = circle.is_some_and(|circle| (x - pos.x).hypot(y - pos.y) <= circle.radius);
+                    in_rect || in_circle
+                })
+                .map(|(receptor, _, _, _)| receptor.0)
+        };
 
-        input_events.0.clear();
-        input_events.0.append(&mut self.event_pump.poll_iter().filter_map(|e| {
-            match e {
-                Event::KeyDown { keycode, timestamp, .. } => Some(InputEvent { keycode, timestamp }),
-                _ => None,
+        input_events.polled_at = self.timer.ticks();
+        for event in self.event_pump.poll_iter() {
+            match event {
+                Event::KeyDown { .. } | Event::KeyUp { .. } => {
+                    key_event(&event, &key_bindings, &mut held_keys, &mut input_events.pending, event_audio_time);
+                },
+                Event::MouseButtonDown { .. } | Event::MouseButtonUp { .. } => {
+                    let viewport = *viewport;
+                    let lane_at = |x: i32, y: i32| receptor_lane(viewport.to_logical(x as f64, y as f64));
+                    mouse_event(&event, &mut self.clicked_lane, lane_at, &mut held_keys, &mut input_events.pending, event_audio_time);
+                },
+                Event::Window { win_event: WindowEvent::Resized(width, height), .. } => {
+                    viewport.resize(width.max(0) as u32, height.max(0) as u32);
+                },
+                _ => {},
             }
-        }).collect::<Vec<InputEvent>>());
+        }
+    }
+}
 
-        self.canvas.set_draw_color(clear_color.0);
+// Images are loaded into textures the first time they're drawn and kept, failures too, so a
+// missing image is logged once rather than every frame. Textures borrow the creator they came
+// from, which run() owns alongside the ttf context the font borrows, so both outlive the renderer.
+pub struct SdlRenderer<'r> {
+    canvas: Canvas<Window>,
+    font: Font<'r, 'static>,
+    texture_creator: &'r TextureCreator<WindowContext>,
+    images: HashMap<PathBuf, Option<Texture<'r>>>,
+}
+
+impl<'r> Renderer for SdlRenderer<'r> {
+    fn clear(&mut self, color: Color) {
+        self.canvas.set_draw_color(color);
         self.canvas.clear();
-        for (color, rect) in sdl_rects.0.iter() {
-            self.canvas.set_draw_color(color.clone());
-            if let Err(e) = self.canvas.fill_rect(Some(rect.clone())) { dbg!(e); }
+    }
+
+    fn fill_rect(&mut self, color: Color, rect: DrawRect) -> Result<(), String> {
+        self.canvas.set_draw_color(color);
+        self.canvas.fill_rect(Some(rect.into()))
+    }
+
+    fn fill_rects(&mut self, color: Color, rects: &[DrawRect]) -> Result<(), String> {
+        let rects: Vec<Rect> = rects.iter().map(|&rect| rect.into()).collect();
+        self.canvas.set_draw_color(color);
+        self.canvas.fill_rects(&rects)
+    }
+
+    fn fill_circle(&mut self, color: Color, center: (i16, i16), radius: i16) -> Result<(), String> {
+        let color: SdlColor = color.into();
+        self.canvas.filled_circle(center.0, center.1, radius, color)
+    }
+
+    fn stroke_circle(&mut self, color: Color, center: (i16, i16), radius: i16) -> Result<(), String> {
+        let color: SdlColor = color.into();
+        self.canvas.circle(center.0, center.1, radius, color)
+    }
+
+    fn draw_text(&mut self, color: Color, position: (i32, i32), scale: f64, text: &str) -> Result<(), String> {
+        if text.is_empty() {
+            return Ok(());
+        }
+
+        let surface = self.font.render(text).blended(color).map_err(|e| e.to_string())?;
+        let texture = self.texture_creator.create_texture_from_surface(&surface).map_err(|e| e.to_string())?;
+        let target = Rect::new(
+            position.0,
+            position.1,
+            (surface.width() as f64 * scale).round() as u32,
+            (surface.height() as f64 * scale).round() as u32,
+        );
+        self.canvas.copy(&texture, None, Some(target))
+    }
+
+    fn draw_image(&mut self, path: &Path, rect: DrawRect) -> Result<(), String> {
+        let texture_creator = self.texture_creator;
+        let image = self.images.entry(path.to_path_buf()).or_insert_with(|| {
+            let texture = Surface::load_bmp(path)
+                .and_then(|surface| texture_creator.create_texture_from_surface(&surface).map_err(|e| e.to_string()));
+            match texture {
+                Ok(texture) => Some(texture),
+                Err(e) => {
+                    log::error!("Couldn't load image {}: {}", path.display(), e);
+                    None
+                },
+            }
+        });
+        match image {
+            Some(texture) => self.canvas.copy(texture, None, Some(rect.into())),
+            None => Ok(()),
         }
+    }
+
+    fn present(&mut self) {
         self.canvas.present();
     }
 }
 
-pub struct RenderingSystem;
+pub(crate) struct SdlSystem<'r> {
+    _sdl: Sdl,
+    renderer: SdlRenderer<'r>,
+    timer: TimerSubsystem,
+}
 
-impl<'a> System<'a> for RenderingSystem {
-    type SystemData = (ReadStorage<'a, Rectangle>,
-                       ReadStorage<'a, Position>,
-                       ReadStorage<'a, Color>,
-                       Write<'a, SdlRects>);
+impl<'r> SdlSystem<'r> {
+    pub fn new(
+        sdl: Sdl,
+        canvas: Canvas<Window>,
+        texture_creator: &'r TextureCreator<WindowContext>,
+        timer: TimerSubsystem,
+        font: Font<'r, 'static>,
+    ) -> SdlSystem<'r> {
+        SdlSystem { _sdl: sdl, renderer: SdlRenderer { canvas, font, texture_creator, images: HashMap::new() }, timer }
+    }
+}
+
+impl<'a, 'r> System<'a> for SdlSystem<'r> {
+    type SystemData = (Write<'a, Viewport>,
+                       Write<'a, WindowCommands>,
+                       Write<'a, WindowState>,
+                       Read<'a, DrawList>,
+                       Read<'a, GameState>,
+                       Read<'a, InterpolatedAudioTime>,
+                       Read<'a, SongTime>,
+                       Read<'a, PlaybackRate>,
+                       Read<'a, LeadIn>,
+                       Read<'a, StepLag>,
+                       Write<'a, ClockOffset>);
 
     fn run(&mut self, data: Self::SystemData) {
-        let (rect_storage, position_storage, color_storage, mut sdl_rects) = data;
-
-        sdl_rects.0.clear();
-
-        for (rect, pos, color) in (&rect_storage, &position_storage, &color_storage).join() {
-            sdl_rects.0.push((
-                    color.clone().into(),
-                    sdl2::rect::Rect::from_center(
-                        (pos.x.round() as i32, pos.y.round() as i32),
-                        rect.width.round() as u32,
-                        rect.height.round() as u32,
-                    )
-            ));
+        let (
+            mut viewport,
+            mut window_commands,
+            mut window_state,
+            draw_list,
+            game_state,
+            audio_time,
+            song_time,
+            playback_rate,
+            lead_in,
+            step_lag,
+            mut clock_offset,
+        ) = data;
+
+        for command in window_commands.0.drain(..) {
+            match command {
+                WindowCommand::ToggleFullscreen => {
+                    let fullscreen_type = if window_state.fullscreen {
+                        FullscreenType::Off
+                    } else {
+                        FullscreenType::Desktop
+                    };
+                    match self.renderer.canvas.window_mut().set_fullscreen(fullscreen_type) {
+                        Ok(()) => window_state.fullscreen = !window_state.fullscreen,
+                        Err(e) => log::warn!("Couldn't change fullscreen mode: {}", e),
+                    }
+                    match self.renderer.canvas.output_size() {
+                        Ok((width, height)) => viewport.resize(width, height),
+                        Err(e) => log::warn!("Couldn't read window size: {}", e),
+                    }
+                },
+            }
         }
+
+        // Pairs this frame's audio_time, read by AudioClockSystem in the last update step, with the
+        // ticks that step stood for, StepLag behind now. What's left of the gap between the two
+        // is part of the measured offset, but it's about the same every frame so it mostly cancels
+        // out. Next frame's SdlInputSystem uses it to place events where they happened instead of
+        // where they were polled.
+        let rate = playback_rate.0 as f64;
+        clock_offset.update(
+            *game_state == GameState::Playing && lead_in.0 == 0,
+            signed_audio_time(&audio_time, &song_time),
+            self.timer.ticks() as f64 - step_lag.0,
+            rate,
+        );
+
+        draw_list.draw(&mut self.renderer);
+        self.renderer.present();
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sdl2::keyboard::Mod;
 
+    fn press(timestamp: u32) -> InputEvent {
+        InputEvent { timestamp, keycode: Some(Keycode::D), audio_time: timestamp as i64 }
+    }
+
+    #[test]
+    fn events_between_frames_get_their_own_audio_time() {
+        let mut clock_offset = ClockOffset::default();
+        clock_offset.update(true, 500, 1000.0, 1.0);
+        // Polled at the next frame, 16ms on, but pressed 4ms into this one.
+        assert_eq!(clock_offset.to_audio_time(1004, 1.0), Some(504));
+
+        // Paused or in the lead-in the offset is forgotten, not observed again.
+        clock_offset.update(false, 500, 1016.0, 1.0);
+        assert_eq!(clock_offset.0, None);
+        assert_eq!(clock_offset.to_audio_time(1020, 1.0), None);
+
+        clock_offset.update(true, 2000, 3000.0, 1.0);
+        assert_eq!(clock_offset.to_audio_time(3008, 1.0), Some(2008));
+    }
+
+    fn key_down(keycode: Keycode, timestamp: u32, repeat: bool) -> Event {
+        Event::KeyDown { timestamp, window_id: 0, keycode: Some(keycode), scancode: None, keymod: Mod::empty(), repeat }
+    }
+
+    fn key_up(keycode: Keycode, timestamp: u32) -> Event {
+        Event::KeyUp { timestamp, window_id: 0, keycode: Some(keycode), scancode: None, keymod: Mod::empty(), repeat: false }
+    }
+
+    #[test]
+    fn held_key_repeats_are_not_presses() {
+        let mut key_bindings = KeyBindings::default();
+        key_bindings.0.insert(Keycode::J, Keycode::D);
+        let mut held_keys = HeldKeys::default();
+        let mut pending = Vec::new();
+        let events = [
+            key_down(Keycode::J, 100, false),
+            key_down(Keycode::J, 130, true),
+            key_down(Keycode::J, 160, true),
+            key_up(Keycode::J, 190),
+            key_down(Keycode::J, 220, false),
+        ];
+        for (i, event) in events.iter().enumerate() {
+            key_event(event, &key_bindings, &mut held_keys, &mut pending, |timestamp| timestamp as i64);
+            // Repeats leave the bound lane key held, the release lets it go.
+            assert_eq!(held_keys.0.contains(&Keycode::D), i != 3, "after event {}", i);
+        }
+
+        let presses: Vec<_> = pending.iter().map(|event| (event.keycode, event.timestamp)).collect();
+        assert_eq!(presses, vec![(Some(Keycode::D), 100), (Some(Keycode::D), 220)]);
+    }
+
+    fn mouse_button(down: bool, x: i32, timestamp: u32) -> Event {
+        let (window_id, which, mouse_btn, clicks, y) = (0, 0, MouseButton::Left, 1, 0);
+        if down {
+            Event::MouseButtonDown { timestamp, window_id, which, mouse_btn, clicks, x, y }
+        } else {
+            Event::MouseButtonUp { timestamp, window_id, which, mouse_btn, clicks, x, y }
+        }
+    }
+
+    #[test]
+    fn clicked_lane_is_held_until_the_button_comes_up() {
+        // One receptor, for D, left of x = 100.
+        let lane_at = |x: i32, _| if x < 100 { Some(Keycode::D) } else { None };
+        let mut clicked_lane = None;
+        let mut held_keys = HeldKeys::default();
+        let mut pending = Vec::new();
+        let mut click = |event: Event, held_keys: &mut HeldKeys| {
+            mouse_event(&event, &mut clicked_lane, lane_at, held_keys, &mut pending, |timestamp| timestamp as i64);
+        };
+
+        click(mouse_button(true, 50, 100), &mut held_keys);
+        assert!(held_keys.0.contains(&Keycode::D));
+        click(mouse_button(false, 150, 200), &mut held_keys);
+        assert!(held_keys.0.is_empty());
+
+        // Clicks outside any receptor neither press nor hold anything.
+        click(mouse_button(true, 150, 300), &mut held_keys);
+        assert!(held_keys.0.is_empty());
+        click(mouse_button(false, 150, 400), &mut held_keys);
+
+        let presses: Vec<_> = pending.iter().map(|event| (event.keycode, event.timestamp)).collect();
+        assert_eq!(presses, vec![(Some(Keycode::D), 100)]);
+    }
+
+    #[test]
+    fn flooded_step_keeps_the_newest_events() {
+        let mut input_events = InputEvents::default();
+        let flood = INPUT_EVENT_CAPACITY as u32 + 10;
+        input_events.pending.extend((0..flood).map(press));
+        input_events.pending.push(press(1000));
+        input_events.release_until(flood as f64);
+
+        assert_eq!(input_events.events.len(), INPUT_EVENT_CAPACITY);
+        assert_eq!(input_events.dropped, 10);
+        assert_eq!(input_events.events.first().map(|event| event.timestamp), Some(10));
+        assert_eq!(input_events.events.last().map(|event| event.timestamp), Some(flood - 1));
+        // Events past the step stay pending for the next one.
+        assert_eq!(input_events.pending.len(), 1);
+    }
+
+    #[test]
+    fn pushing_past_capacity_counts_what_it_drops() {
+        let mut input_events = InputEvents::default();
+        for timestamp in 0..INPUT_EVENT_CAPACITY as u32 + 3 {
+            input_events.push(press(timestamp));
+        }
+        assert_eq!(input_events.events.len(), INPUT_EVENT_CAPACITY);
+        assert_eq!(input_events.dropped, 3);
+        assert_eq!(input_events.events[0].timestamp, 3);
+
+        // The count carries on across steps.
+        input_events.release_until(0.0);
+        assert!(input_events.events.is_empty());
+        assert_eq!(input_events.dropped, 3);
+    }
+}
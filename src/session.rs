@@ -0,0 +1,298 @@
+use sdl2::keyboard::Keycode;
+
+use serde::{Deserialize, Serialize};
+
+use specs::prelude::*;
+
+use std::{
+    fmt,
+    fs::File,
+    io::{self, BufReader},
+    path::Path,
+};
+
+use crate::{
+    calibration::{GlobalOffset, InputLatency},
+    chart::{Chart, ChartError},
+    replay::{ReplayEntry, ReplayRecorder},
+    results::Results,
+    rhythm::{
+        ComboGrace,
+        HitWindow,
+        Judged,
+        Judgement,
+        JudgementWindows,
+        NoteIndex,
+        SingleKeyMode,
+        TargetBarTime,
+        TimingFilter,
+    },
+    sdl::InputEvent,
+    simulator::Simulator,
+};
+
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(PartialEq)]
+#[derive(Serialize, Deserialize)]
+pub struct SessionSettings {
+    // The early bound, and the late one too unless `hit_window_late` says otherwise, which
+    // sessions from before the bounds could differ never do.
+    pub hit_window: u64,
+    #[serde(default)]
+    pub hit_window_late: Option<u64>,
+    pub perfect_window: u64,
+    pub great_window: u64,
+    // Sessions from before `good` was its own window were judged with it at the widest HitWindow.
+    #[serde(default)]
+    pub good_window: Option<u64>,
+    pub combo_grace: u64,
+    pub input_latency: i64,
+    // The offset as the session ended. Nudging it mid-song isn't captured, so such a session
+    // won't replay exactly.
+    #[serde(default)]
+    pub global_offset: i64,
+    pub timing_filter: TimingFilter,
+    #[serde(default)]
+    pub single_key_mode: bool,
+}
+
+// The default windows with no grace, latency or offset, the plainest way to judge a chart.
+impl Default for SessionSettings {
+    fn default() -> SessionSettings {
+        let hit_window = HitWindow::default();
+        let judgement_windows = JudgementWindows::default();
+        SessionSettings {
+            hit_window: hit_window.early,
+            hit_window_late: Some(hit_window.late),
+            perfect_window: judgement_windows.perfect,
+            great_window: judgement_windows.great,
+            good_window: Some(judgement_windows.good),
+            combo_grace: 0,
+            input_latency: 0,
+            global_offset: 0,
+            timing_filter: TimingFilter::default(),
+            single_key_mode: false,
+        }
+    }
+}
+
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(PartialEq)]
+#[derive(Serialize, Deserialize)]
+pub struct SessionInput {
+    pub audio_time: i64,
+    pub timestamp: u32,
+    pub key: Option<String>,
+    // When the press happened. Sessions exported before presses carried their own time were
+    // judged at `audio_time`.
+    #[serde(default)]
+    pub press_time: Option<i64>,
+}
+
+impl From<&ReplayEntry> for SessionInput {
+    fn from(entry: &ReplayEntry) -> SessionInput {
+        SessionInput {
+            audio_time: entry.audio_time,
+            timestamp: entry.event.timestamp,
+            key: entry.event.keycode.map(|keycode| keycode.name()),
+            press_time: Some(entry.event.audio_time),
+        }
+    }
+}
+
+// Everything needed to reproduce a run without a window or audio device: the chart, the timing
+// settings that were active, every input with the audio time it was judged at, and the results
+// the live run produced, in total and note by note in chart order.
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(Serialize, Deserialize)]
+pub struct Session {
+    pub chart: Chart,
+    pub settings: SessionSettings,
+    pub inputs: Vec<SessionInput>,
+    pub results: Results,
+    // Empty in sessions exported before they were kept, which can only be checked on the totals.
+    #[serde(default)]
+    pub judgements: Vec<Option<Judgement>>,
+}
+
+// Each note's judgement, indexed by its place in the chart, None for notes not judged yet.
+pub fn note_judgements(world: &World) -> Vec<Option<Judgement>> {
+    let index_storage = world.read_storage::<NoteIndex>();
+    let judged_storage = world.read_storage::<Judged>();
+    let mut judgements = vec![None; index_storage.join().count()];
+    for (index, judged) in (&index_storage, &judged_storage).join() {
+        if let Some(slot) = judgements.get_mut(index.0) {
+            *slot = Some(judged.0);
+        }
+    }
+    judgements
+}
+
+#[derive(Debug)]
+pub enum Divergence {
+    Load(ChartError),
+    // The first note, in chart order, whose replayed judgement differs from the recorded one.
+    Note { index: usize, recorded: Option<Judgement>, replayed: Option<Judgement> },
+    // Only for sessions without per-note judgements.
+    Results { recorded: Results, replayed: Results },
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Divergence::Load(err) => write!(f, "couldn't load session: {}", err),
+            Divergence::Note { index, recorded, replayed } => {
+                write!(f, "note {} diverged: recorded {:?}, replayed {:?}", index, recorded, replayed)
+            },
+            Divergence::Results { recorded, replayed } => {
+                write!(f, "results diverged\nrecorded: {:?}\nreplayed: {:?}", recorded, replayed)
+            },
+        }
+    }
+}
+
+// Replays an exported session against the current code and checks every note comes out the way
+// it was recorded, a golden-file check that timing changes haven't moved any judgement. The
+// plain `--record` replay files only hold inputs, so this takes a session, which also has the
+// chart, the settings and the judgements.
+pub fn verify_replay<P: AsRef<Path>>(path: P) -> Result<(), Divergence> {
+    let session = File::open(path)
+        .map_err(ChartError::from)
+        .and_then(|file| Session::from_json(BufReader::new(file)))
+        .map_err(Divergence::Load)?;
+
+    let simulator = session.replay();
+    if session.judgements.is_empty() {
+        let replayed = simulator.results();
+        return if replayed == session.results {
+            Ok(())
+        } else {
+            Err(Divergence::Results { recorded: session.results, replayed })
+        };
+    }
+
+    let replayed = simulator.note_judgements();
+    let note_count = session.judgements.len().max(replayed.len());
+    match (0..note_count).find(|&index| session.judgements.get(index) != replayed.get(index)) {
+        Some(index) => Err(Divergence::Note {
+            index,
+            recorded: session.judgements.get(index).cloned().unwrap_or(None),
+            replayed: replayed.get(index).cloned().unwrap_or(None),
+        }),
+        None => Ok(()),
+    }
+}
+
+impl Session {
+    pub fn capture(world: &World, chart: &Chart) -> Session {
+        let judgement_windows = world.read_resource::<JudgementWindows>();
+        let hit_window = *world.read_resource::<HitWindow>();
+        let settings = SessionSettings {
+            hit_window: hit_window.early,
+            hit_window_late: Some(hit_window.late),
+            perfect_window: judgement_windows.perfect,
+            great_window: judgement_windows.great,
+            good_window: Some(judgement_windows.good),
+            combo_grace: world.read_resource::<ComboGrace>().0,
+            input_latency: world.read_resource::<InputLatency>().0,
+            global_offset: world.read_resource::<GlobalOffset>().0,
+            timing_filter: *world.read_resource::<TimingFilter>(),
+            single_key_mode: world.read_resource::<SingleKeyMode>().0,
+        };
+
+        let inputs = world.read_resource::<ReplayRecorder>().entries.iter()
+            .map(SessionInput::from)
+            .collect();
+
+        let results = Results::from_judgements(
+            (&world.read_storage::<TargetBarTime>(), world.read_storage::<Judged>().maybe())
+                .join()
+                .map(|(_, judged)| judged.map(|judged| judged.0))
+        );
+
+        let judgements = note_judgements(world);
+
+        Session { chart: chart.clone(), settings, inputs, results, judgements }
+    }
+
+    pub fn from_json<R: io::Read>(reader: R) -> Result<Session, ChartError> {
+        Ok(serde_json::from_reader(reader)?)
+    }
+
+    pub fn to_json<W: io::Write>(&self, writer: W) -> Result<(), ChartError> {
+        serde_json::to_writer_pretty(writer, self)?;
+        Ok(())
+    }
+
+    // Replays the recorded inputs through the tagging system at their recorded audio times and
+    // returns the simulator as it ends, to read the results and judgements from. The simulator has
+    // no lead-in, so inputs from during one run at 0, though they're still judged at their own
+    // press times.
+    pub fn replay(&self) -> Simulator {
+        let mut simulator = Simulator::new(&self.chart, &self.settings);
+        for input in &self.inputs {
+            simulator.input(InputEvent {
+                timestamp: input.timestamp,
+                keycode: input.key.as_ref().and_then(|name| Keycode::from_name(name)),
+                audio_time: input.press_time.unwrap_or(input.audio_time),
+            }, input.audio_time.max(0) as u64);
+        }
+        simulator
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn press(key: Keycode, time: i64) -> SessionInput {
+        SessionInput { audio_time: time, timestamp: time as u32, key: Some(key.name()), press_time: Some(time) }
+    }
+
+    #[test]
+    fn imported_session_reproduces_its_stats() {
+        let chart = Chart::from_reader("bpm 120000\naudio song.ogg\nnote 1 1 1 D\nnote 1 1 2 F\nnote 1 1 3 D\n".as_bytes()).unwrap();
+        let judgements = vec![Some(Judgement::Perfect), Some(Judgement::Great), None];
+        let session = Session {
+            chart,
+            settings: SessionSettings::default(),
+            inputs: vec![press(Keycode::D, 505), press(Keycode::F, 1040), press(Keycode::J, 1200)],
+            results: Results::from_judgements(judgements.clone()),
+            judgements,
+        };
+
+        let mut json = Vec::new();
+        session.to_json(&mut json).unwrap();
+        let imported = Session::from_json(&json[..]).unwrap();
+        let simulator = imported.replay();
+        assert_eq!(simulator.results(), session.results);
+        assert_eq!(simulator.note_judgements(), session.judgements);
+    }
+
+    const SAMPLE_SESSION: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/replays/sample-session.json");
+
+    #[test]
+    fn sample_replay_reproduces_its_judgements() {
+        assert!(verify_replay(SAMPLE_SESSION).is_ok());
+    }
+
+    #[test]
+    fn first_divergent_note_is_reported() {
+        let mut session = Session::from_json(BufReader::new(File::open(SAMPLE_SESSION).unwrap())).unwrap();
+        assert_eq!(session.judgements[2], Some(Judgement::Good));
+        session.judgements[2] = Some(Judgement::Perfect);
+        session.judgements[3] = None;
+        let path = std::env::temp_dir().join(format!("rhythm-{}-diverged.json", std::process::id()));
+        session.to_json(File::create(&path).unwrap()).unwrap();
+        let verified = verify_replay(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        match verified {
+            Err(Divergence::Note { index: 2, recorded: Some(Judgement::Perfect), replayed: Some(Judgement::Good) }) => {},
+            other => panic!("expected note 2 to diverge, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,144 @@
+use sdl2::keyboard::Keycode;
+
+use serde::{Deserialize, Serialize};
+
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, BufReader, BufWriter},
+    path::Path,
+};
+
+use crate::{
+    audio::{SfxOffset, Volume},
+    calibration::{GlobalOffset, InputLatency},
+    effects::ColorblindMode,
+    lanes::{ApproachTime, KeyLegend, LaneCount, ReceptorPulse},
+    score::ScoreWeights,
+    render::{LOGICAL_HEIGHT, LOGICAL_WIDTH},
+    theme::ThemeName,
+};
+
+pub const SETTINGS_PATH: &str = "settings.json";
+
+// Maps the physical keys pressed to the lane keys charts are written against, so a chart's `Left`
+// lane can be played on `D`. Keys without a binding pass through as themselves.
+#[derive(Debug)]
+#[derive(Default)]
+#[derive(Clone)]
+#[derive(PartialEq)]
+#[derive(Serialize, Deserialize)]
+pub struct KeyBindings(#[serde(with = "key_binding_names")] pub HashMap<Keycode, Keycode>);
+
+impl KeyBindings {
+    pub fn lane_key(&self, pressed: Keycode) -> Keycode {
+        self.0.get(&pressed).cloned().unwrap_or(pressed)
+    }
+}
+
+mod key_binding_names {
+    use sdl2::keyboard::Keycode;
+    use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
+    use std::collections::{BTreeMap, HashMap};
+
+    pub fn serialize<S: Serializer>(bindings: &HashMap<Keycode, Keycode>, serializer: S) -> Result<S::Ok, S::Error> {
+        bindings.iter()
+            .map(|(pressed, lane)| (pressed.name(), lane.name()))
+            .collect::<BTreeMap<_, _>>()
+            .serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<HashMap<Keycode, Keycode>, D::Error> {
+        let key = |name: &str| Keycode::from_name(name).ok_or_else(|| D::Error::custom(format!("unknown key `{}`", name)));
+        BTreeMap::<String, String>::deserialize(deserializer)?
+            .iter()
+            .map(|(pressed, lane)| Ok((key(pressed)?, key(lane)?)))
+            .collect()
+    }
+}
+
+// Whether presenting a frame waits for the display's vertical blank. While it does that paces the
+// main loop, and the frame limiter stands aside rather than limit it a second time.
+#[derive(Debug)]
+#[derive(Default)]
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(PartialEq)]
+#[derive(Serialize, Deserialize)]
+pub struct VsyncEnabled(pub bool);
+
+// How the window opens. Without a position, or with one that's on no connected display, it's
+// centered on `display`, an index into SDL's displays, 0 being the primary one.
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(PartialEq)]
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct WindowConfig {
+    pub title: String,
+    pub width: u32,
+    pub height: u32,
+    pub position: Option<(i32, i32)>,
+    pub display: i32,
+    pub vsync: VsyncEnabled,
+}
+
+impl Default for WindowConfig {
+    fn default() -> WindowConfig {
+        WindowConfig {
+            title: "Rhythm Game".to_string(),
+            width: LOGICAL_WIDTH as u32,
+            height: LOGICAL_HEIGHT as u32,
+            position: None,
+            display: 0,
+            vsync: VsyncEnabled::default(),
+        }
+    }
+}
+
+#[derive(Debug)]
+#[derive(Default)]
+#[derive(Clone)]
+#[derive(PartialEq)]
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    pub volume: Volume,
+    pub key_bindings: KeyBindings,
+    pub input_latency: InputLatency,
+    pub global_offset: GlobalOffset,
+    pub sfx_offset: SfxOffset,
+    pub lane_count: LaneCount,
+    pub key_legend: KeyLegend,
+    pub receptor_pulse: ReceptorPulse,
+    pub approach_time: ApproachTime,
+    pub colorblind_mode: ColorblindMode,
+    pub score_weights: ScoreWeights,
+    pub theme: ThemeName,
+    pub window: WindowConfig,
+}
+
+impl Settings {
+    pub fn load(path: &Path) -> io::Result<Settings> {
+        let file = File::open(path)?;
+        Ok(serde_json::from_reader(BufReader::new(file))?)
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let file = File::create(path)?;
+        Ok(serde_json::to_writer_pretty(BufWriter::new(file), self)?)
+    }
+
+    // A missing or malformed settings file is replaced by the defaults rather than stopping the
+    // game, so the next launch finds a valid one.
+    pub fn load_or_default(path: &Path) -> Settings {
+        Settings::load(path).unwrap_or_else(|err| {
+            log::warn!("Couldn't load settings from {}, using defaults: {}", path.display(), err);
+            let settings = Settings::default();
+            if let Err(err) = settings.save(path) {
+                log::warn!("Couldn't write default settings to {}: {}", path.display(), err);
+            }
+            settings
+        })
+    }
+}
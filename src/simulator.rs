@@ -0,0 +1,380 @@
+#[cfg(test)]
+use sdl2::keyboard::Keycode;
+
+use specs::{prelude::*, RunNow};
+
+use crate::{
+    AudioClockSystem,
+    AudioTime,
+    AudioTimeInterpolator,
+    DebugFlag,
+    FrameTime,
+    InterpolatedAudioTime,
+    ManualClock,
+    SongTime,
+    StepLag,
+    TargetInput,
+    TimeSource,
+    WallClock,
+    autoplay::Autoplay,
+    calibration::{GlobalOffset, InputLatency, LatencyCalibration},
+    chart::Chart,
+    combo::ComboEvents,
+    countdown::LeadIn,
+    lanes::NoteColor,
+    results::Results,
+    rhythm::{
+        BarIndex,
+        BarIndexTaggingSystem,
+        ChordDiagnostics,
+        ChordGroup,
+        Combo,
+        ComboBreaks,
+        ComboGrace,
+        HitOffset,
+        HitWindow,
+        HoldTickCount,
+        InputTimeline,
+        Judged,
+        Judgement,
+        JudgementHistory,
+        JudgementWindows,
+        LaneIndex,
+        LastHitError,
+        LastHitKey,
+        LastHitNote,
+        NoteIndex,
+        PendingChords,
+        RhythmCombo,
+        SingleKeyMode,
+        TargetBarTime,
+    },
+    score::{Score, ScoreWeights, ScoringSystem},
+    sdl::{InputEvent, InputEvents},
+    session::{note_judgements, SessionSettings},
+    song::{PlaybackRate, SeekBase},
+    state::GameState,
+};
+
+// Plays a chart through AudioClockSystem, BarIndexTaggingSystem and ScoringSystem without a
+// window or audio device, a ManualClock standing in for the sink. The world holds only what those
+// three read:
+//
+//  - the clock: TimeSource::Manual, ManualClock, AudioTime, SongTime, InterpolatedAudioTime,
+//    AudioTimeInterpolator, WallClock, FrameTime, a 1x PlaybackRate, a zero SeekBase and StepLag,
+//    with no LeadIn, the GameState already Playing and the chart's AudioContext
+//  - judging: HitWindow, JudgementWindows, ComboGrace, InputLatency, GlobalOffset, TimingFilter
+//    and SingleKeyMode from the settings, plus the InputEvents, LaneIndex, PendingChords,
+//    ChordDiagnostics, ComboBreaks, JudgementHistory, InputTimeline, LastHitError, LastHitKey,
+//    LastHitNote, LatencyCalibration and DebugFlag it writes to, with Autoplay off since the
+//    presses are the point
+//  - scoring: the default ScoreWeights, an empty HoldTickCount since nothing is held, and the
+//    Score, Combo and ComboEvents it keeps
+pub struct Simulator {
+    world: World,
+    audio_clock_system: AudioClockSystem,
+    scoring_system: ScoringSystem,
+}
+
+impl Simulator {
+    pub fn new(chart: &Chart, settings: &SessionSettings) -> Simulator {
+        let mut world = World::new();
+        world.register::<TargetBarTime>();
+        world.register::<TargetInput>();
+        world.register::<RhythmCombo>();
+        world.register::<ChordGroup>();
+        world.register::<NoteIndex>();
+        world.register::<NoteColor>();
+        world.register::<BarIndex>();
+        world.register::<Judged>();
+        world.register::<HitOffset>();
+
+        world.add_resource(TimeSource::Manual);
+        world.add_resource(ManualClock(0));
+        world.add_resource(AudioTime(0));
+        world.add_resource(SongTime(0));
+        world.add_resource(InterpolatedAudioTime(0));
+        world.add_resource(AudioTimeInterpolator::default());
+        world.add_resource(WallClock::default());
+        world.add_resource(FrameTime(0.0));
+        world.add_resource(PlaybackRate::default());
+        world.add_resource(SeekBase::default());
+        world.add_resource(StepLag::default());
+        world.add_resource(LeadIn(0));
+        world.add_resource(GameState::Playing);
+
+        world.add_resource(chart.audio_context());
+        world.add_resource(HitWindow {
+            early: settings.hit_window,
+            late: settings.hit_window_late.unwrap_or(settings.hit_window),
+        });
+        world.add_resource(JudgementWindows {
+            perfect: settings.perfect_window,
+            great: settings.great_window,
+            good: settings.good_window.unwrap_or_else(|| settings.hit_window.max(settings.hit_window_late.unwrap_or(0))),
+        });
+        world.add_resource(ComboGrace(settings.combo_grace));
+        world.add_resource(InputLatency(settings.input_latency));
+        world.add_resource(GlobalOffset(settings.global_offset));
+        world.add_resource(settings.timing_filter);
+        world.add_resource(SingleKeyMode(settings.single_key_mode));
+        world.add_resource(InputEvents::default());
+        world.add_resource(LaneIndex::default());
+        world.add_resource(PendingChords::default());
+        world.add_resource(ChordDiagnostics::default());
+        world.add_resource(ComboBreaks::default());
+        world.add_resource(JudgementHistory::default());
+        world.add_resource(InputTimeline::default());
+        world.add_resource(LastHitError::default());
+        world.add_resource(LastHitKey::default());
+        world.add_resource(LastHitNote::default());
+        world.add_resource(LatencyCalibration::default());
+        world.add_resource(DebugFlag::default());
+        world.add_resource(Autoplay(false));
+
+        world.add_resource(ScoreWeights::default());
+        world.add_resource(HoldTickCount::default());
+        world.add_resource(Score::default());
+        world.add_resource(Combo::default());
+        world.add_resource(ComboEvents::default());
+
+        chart.spawn_notes(&mut world);
+
+        Simulator { world, audio_clock_system: AudioClockSystem::default(), scoring_system: ScoringSystem::default() }
+    }
+
+    pub fn input(&mut self, event: InputEvent, time: u64) {
+        self.step(time, vec![event]);
+    }
+
+    fn step(&mut self, time: u64, events: Vec<InputEvent>) {
+        self.world.write_resource::<ManualClock>().0 = time;
+        self.world.write_resource::<InputEvents>().events = events;
+        self.audio_clock_system.run_now(&self.world.res);
+        BarIndexTaggingSystem.run_now(&self.world.res);
+        self.scoring_system.run_now(&self.world.res);
+        self.world.maintain();
+    }
+
+    pub fn note_judgements(&self) -> Vec<Option<Judgement>> {
+        note_judgements(&self.world)
+    }
+
+    pub fn results(&self) -> Results {
+        Results::from_judgements(
+            (&self.world.read_storage::<TargetBarTime>(), self.world.read_storage::<Judged>().maybe())
+                .join()
+                .map(|(_, judged)| judged.map(|judged| judged.0))
+        )
+    }
+}
+
+// Shortcuts for the tests, which press single keys and read the scoring resources directly.
+#[cfg(test)]
+impl Simulator {
+    // Moves the clock to `time` without any input, letting incomplete chords expire.
+    pub fn advance_to(&mut self, time: u64) {
+        self.step(time, Vec::new());
+    }
+
+    pub fn press(&mut self, key: Keycode, time: u64) {
+        self.input(InputEvent { timestamp: time as u32, keycode: Some(key), audio_time: time as i64 }, time);
+    }
+
+    pub fn judgements(&self) -> Vec<Judgement> {
+        self.world.read_resource::<JudgementHistory>().0.clone()
+    }
+
+    pub fn timeline(&self) -> InputTimeline {
+        self.world.read_resource::<InputTimeline>().clone()
+    }
+
+    pub fn combo(&self) -> u32 {
+        self.world.read_resource::<Combo>().0
+    }
+
+    pub fn score(&self) -> u64 {
+        self.world.read_resource::<Score>().0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // One note on the second beat at 120 BPM, 500ms in.
+    fn one_note() -> Chart {
+        Chart::from_reader("bpm 120000\naudio song.ogg\nnote 1 1 1 D\n".as_bytes()).unwrap()
+    }
+
+    fn note_state(simulator: &Simulator) -> (Option<u64>, Option<Judgement>, Option<i64>) {
+        let world = &simulator.world;
+        let (bar_index_storage, judged_storage, hit_offset_storage) =
+            (world.read_storage::<BarIndex>(), world.read_storage::<Judged>(), world.read_storage::<HitOffset>());
+        let entity = (&world.entities(), &world.read_storage::<TargetBarTime>()).join().map(|(entity, _)| entity).next().unwrap();
+        (
+            bar_index_storage.get(entity).map(|bar_index| bar_index.0),
+            judged_storage.get(entity).map(|judged| judged.0),
+            hit_offset_storage.get(entity).map(|hit_offset| hit_offset.0),
+        )
+    }
+
+    #[test]
+    fn dead_on_press_is_perfect() {
+        let mut simulator = Simulator::new(&one_note(), &SessionSettings::default());
+        simulator.advance_to(480);
+        simulator.press(Keycode::D, 500);
+        assert_eq!(note_state(&simulator), (Some(0), Some(Judgement::Perfect), Some(0)));
+    }
+
+    #[test]
+    fn press_50ms_late_misses_a_40ms_window() {
+        let settings = SessionSettings { hit_window: 40, hit_window_late: None, good_window: None, ..SessionSettings::default() };
+        let mut simulator = Simulator::new(&one_note(), &settings);
+        simulator.press(Keycode::D, 550);
+        // The press hits nothing, and by then the note has gone past.
+        assert_eq!(note_state(&simulator), (Some(0), Some(Judgement::Miss), None));
+
+        // The default 100ms window takes the same press, right at the edge of Great.
+        let mut simulator = Simulator::new(&one_note(), &SessionSettings::default());
+        simulator.press(Keycode::D, 550);
+        assert_eq!(note_state(&simulator), (Some(0), Some(Judgement::Great), Some(50)));
+    }
+
+    #[test]
+    fn perfect_run_scores_exactly_its_weights() {
+        // A bar of sixteenths, 125ms apart.
+        let notes: String = (0..16).map(|index| format!("note 1 4 {} D\n", index)).collect();
+        let chart = Chart::from_reader(format!("bpm 120000\naudio song.ogg\n{}", notes).as_bytes()).unwrap();
+        let mut simulator = Simulator::new(&chart, &SessionSettings::default());
+        for index in 0..16 {
+            simulator.press(Keycode::D, index * 125);
+        }
+        assert_eq!(simulator.judgements(), vec![Judgement::Perfect; 16]);
+        assert_eq!(simulator.combo(), 16);
+        // Ten at 1x, then the multiplier goes up to 2x for the last six.
+        assert_eq!(simulator.score(), 10 * 300 + 6 * 600);
+    }
+
+    // Plays one_note at `rate` by the wall clock, pressing D once it reads `wall` ms, and returns
+    // the note's judgement.
+    fn judge_at_wall_time(rate: f32, wall: f64) -> Option<Judgement> {
+        let mut simulator = Simulator::new(&one_note(), &SessionSettings::default());
+        simulator.world.add_resource(TimeSource::Wallclock);
+        simulator.world.add_resource(PlaybackRate::new(rate));
+        simulator.world.add_resource(WallClock(wall));
+        simulator.advance_to(0);
+        let audio_time = simulator.world.read_resource::<AudioTime>().0;
+        simulator.press(Keycode::D, audio_time);
+        note_state(&simulator).1
+    }
+
+    #[test]
+    fn half_speed_windows_land_at_twice_the_wall_clock_time() {
+        // The note is 500ms into the song, a second of wall clock in at 0.5x, and each window
+        // stretches to twice its width: 50ms Perfect, 100ms Great, 200ms Good.
+        assert_eq!(judge_at_wall_time(0.5, 1000.0), Some(Judgement::Perfect));
+        assert_eq!(judge_at_wall_time(0.5, 1040.0), Some(Judgement::Perfect));
+        assert_eq!(judge_at_wall_time(0.5, 1090.0), Some(Judgement::Great));
+        assert_eq!(judge_at_wall_time(0.5, 810.0), Some(Judgement::Good));
+        assert_eq!(judge_at_wall_time(0.5, 1190.0), Some(Judgement::Good));
+        // Too early hits nothing yet, too late finds the note already missed.
+        assert_eq!(judge_at_wall_time(0.5, 790.0), None);
+        assert_eq!(judge_at_wall_time(0.5, 1210.0), Some(Judgement::Miss));
+
+        // The same wall-clock press at full speed is well past the note.
+        assert_eq!(judge_at_wall_time(1.0, 810.0), Some(Judgement::Miss));
+    }
+
+    // Plays `note` with a D press stamped at `press_time`, judged by a step taken with `lead_in` ms
+    // of the lead-in still to go.
+    fn lead_in_press(note: &str, lead_in: u64, press_time: i64) -> Simulator {
+        let chart = Chart::from_reader(format!("bpm 120000\naudio song.ogg\n{}\n", note).as_bytes()).unwrap();
+        let mut simulator = Simulator::new(&chart, &SessionSettings::default());
+        simulator.world.add_resource(LeadIn(lead_in));
+        simulator.input(InputEvent { timestamp: 0, keycode: Some(Keycode::D), audio_time: press_time }, 0);
+        simulator
+    }
+
+    #[test]
+    fn presses_before_the_song_starts_are_early_not_on_time() {
+        // 20ms early, with the lead-in just over.
+        let simulator = lead_in_press("note 1 1000 100 D", 0, 30);
+        assert_eq!(note_state(&simulator), (Some(0), Some(Judgement::Perfect), Some(-20)));
+
+        // 40ms into the lead-in it's 90ms early, not 50 as though the press were at 0.
+        let simulator = lead_in_press("note 1 1000 100 D", 40, -40);
+        assert_eq!(note_state(&simulator), (Some(0), Some(Judgement::Good), Some(-90)));
+    }
+
+    #[test]
+    fn presses_either_side_of_the_bar_wrap_find_notes_at_the_start() {
+        let chart = Chart::from_reader("bpm 120000\naudio song.ogg\nnote 1 1 0 D\nnote 1 1 2 F\n".as_bytes()).unwrap();
+        let mut simulator = Simulator::new(&chart, &SessionSettings::default());
+        simulator.world.add_resource(LeadIn(10));
+        // Judged together 5ms into the song, the F press from the end of the bar before it.
+        let press = |keycode, audio_time| InputEvent { timestamp: 0, keycode: Some(keycode), audio_time };
+        simulator.step(15, vec![press(Keycode::F, -10), press(Keycode::D, 5)]);
+        assert_eq!(note_state(&simulator), (Some(0), Some(Judgement::Perfect), Some(5)));
+    }
+
+    // Judges each press against the nearest unhit note in its lane by the note's time in the song,
+    // scanning every note, with the default windows. Notes nothing hit are misses.
+    fn linear_judgements(notes: &[(u64, Keycode)], presses: &[(Keycode, u64)]) -> Vec<Option<Judgement>> {
+        let (hit_window, judgement_windows) = (HitWindow::default(), JudgementWindows::default());
+        let mut judgements = vec![None; notes.len()];
+        for &(key, time) in presses {
+            let nearest = notes.iter().enumerate()
+                .filter(|&(index, &(_, note_key))| note_key == key && judgements[index].is_none())
+                .map(|(index, &(note_time, _))| (index, time as i64 - note_time as i64))
+                .filter(|&(_, error)| hit_window.accepts(error.unsigned_abs(), error < 0))
+                .min_by_key(|&(_, error)| error.unsigned_abs());
+            if let Some((index, error)) = nearest {
+                judgements[index] = Some(judgement_windows.judge(error.unsigned_abs()));
+            }
+        }
+        judgements.into_iter().map(|judgement| judgement.or(Some(Judgement::Miss))).collect()
+    }
+
+    #[test]
+    fn notes_past_the_first_bar_are_judged_like_a_linear_scan() {
+        // Twenty notes 750ms apart over eight 2000ms bars, alternating D and F.
+        let notes: Vec<(u64, Keycode)> = (0..20)
+            .map(|index| (index * 750, if index % 2 == 0 { Keycode::D } else { Keycode::F }))
+            .collect();
+        let text: String = notes.iter().enumerate()
+            .map(|(index, &(_, key))| format!("note 3 2 {} {}\n", index, key.name()))
+            .collect();
+        let chart = Chart::from_reader(format!("bpm 120000\naudio song.ogg\n{}", text).as_bytes()).unwrap();
+
+        // Every offset the windows tell apart, one too late to hit anything, and note 7 left alone.
+        let offsets = [0, -30, 45, -80, 90, 130];
+        let presses: Vec<(Keycode, u64)> = notes.iter().enumerate()
+            .filter(|&(index, _)| index != 7)
+            .map(|(index, &(time, key))| (key, (time as i64 + offsets[index % offsets.len()]) as u64))
+            .collect();
+
+        let mut simulator = Simulator::new(&chart, &SessionSettings::default());
+        for &(key, time) in &presses {
+            simulator.press(key, time);
+        }
+        simulator.advance_to(20 * 750);
+
+        let expected = linear_judgements(&notes, &presses);
+        // Note 6 is dead on, 4500ms in, in the third bar.
+        assert_eq!(expected[6], Some(Judgement::Perfect));
+        assert_eq!(simulator.note_judgements(), expected);
+    }
+
+    #[test]
+    fn osu_sample_played_on_time_is_all_perfect() {
+        let chart = Chart::from_osu(include_str!("../charts/sample-mania.osu").as_bytes()).unwrap();
+        let audio_context = chart.audio_context();
+        let mut simulator = Simulator::new(&chart, &SessionSettings::default());
+        for note in &chart.notes {
+            simulator.press(note.keys[0], audio_context.make_bar_time(note.multiple, note.division, note.index).0);
+        }
+        simulator.advance_to(10_000);
+        assert_eq!(simulator.note_judgements(), vec![Some(Judgement::Perfect); chart.notes.len()]);
+    }
+}
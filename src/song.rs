@@ -0,0 +1,246 @@
+use rodio::{Decoder, Sample, Sink, Source};
+
+use specs::prelude::*;
+
+use std::{
+    fmt,
+    fs::File,
+    io::{self, BufReader},
+    panic::{self, AssertUnwindSafe},
+    path::Path,
+};
+
+use crate::{
+    AudioTime,
+    FrameTime,
+    countdown::LeadIn,
+    rhythm::SongLength,
+    state::GameState,
+};
+
+// The formats rodio is built with in Cargo.toml. Anything else is turned away with
+// UnsupportedFormat instead of failing to sniff.
+pub const SUPPORTED_FORMATS: &[&str] = &["ogg", "wav", "flac", "mp3"];
+
+#[derive(Debug)]
+pub enum SongError {
+    Io { path: String, err: io::Error },
+    UnsupportedFormat { path: String, extension: String },
+    Decode { path: String, format: String, message: String },
+}
+
+impl fmt::Display for SongError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SongError::Io { path, err } => write!(f, "couldn't open {}: {}", path, err),
+            SongError::UnsupportedFormat { path, extension } => write!(
+                f, "{} is `.{}`, which isn't a supported audio format (supported: {})",
+                path, extension, SUPPORTED_FORMATS.join(", "),
+            ),
+            SongError::Decode { path, format, message } => write!(f, "{} isn't valid {}: {}", path, format, message),
+        }
+    }
+}
+
+// Opens an audio file by its extension, so an unsupported format is named as such rather than
+// surfacing as a generic decoder failure. Anything rodio can't read past the header is reported
+// as corrupt for that format. rodio's decoders don't promise not to panic on malformed input, the
+// mp3 one least of all, so a panic is caught and reported the same way; the default hook still
+// prints it.
+pub fn open_audio(path: &str) -> Result<Decoder<BufReader<File>>, SongError> {
+    let extension = Path::new(path)
+        .extension()
+        .map_or_else(String::new, |extension| extension.to_string_lossy().to_lowercase());
+    if !SUPPORTED_FORMATS.contains(&extension.as_str()) {
+        return Err(SongError::UnsupportedFormat { path: path.to_string(), extension });
+    }
+
+    let file = File::open(path).map_err(|err| SongError::Io { path: path.to_string(), err })?;
+    let decode_error = |message| SongError::Decode { path: path.to_string(), format: extension.clone(), message };
+    match panic::catch_unwind(AssertUnwindSafe(|| Decoder::new(BufReader::new(file)))) {
+        Ok(Ok(decoder)) => Ok(decoder),
+        Ok(Err(err)) => Err(decode_error(format!("{:?}", err))),
+        Err(_) => Err(decode_error("the decoder panicked".to_string())),
+    }
+}
+
+// Length of audio analyzed for the first onset and the energy window it's measured in.
+pub const ANALYSIS_MILLIS: u64 = 2000;
+pub const ENERGY_WINDOW_MILLIS: u64 = 10;
+// An onset is the first window whose rise in energy reaches this fraction of the largest rise.
+pub const ONSET_THRESHOLD: f32 = 0.3;
+
+pub const MIN_PLAYBACK_RATE: f32 = 0.25;
+pub const MAX_PLAYBACK_RATE: f32 = 2.0;
+
+// Speed the song plays at, 1.0 being normal. Chart times, hit windows and audio_time all stay in
+// song time, the clock scales wall time into song time, so at 0.5x a note charted at 1000ms is hit
+// two wall-clock seconds in. Only read when a song is loaded; rodio can't retime a queued source.
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(Copy)]
+pub struct PlaybackRate(pub f32);
+
+impl Default for PlaybackRate {
+    fn default() -> PlaybackRate { PlaybackRate(1.0) }
+}
+
+impl PlaybackRate {
+    pub fn new(rate: f32) -> PlaybackRate {
+        PlaybackRate(rate.clamp(MIN_PLAYBACK_RATE, MAX_PLAYBACK_RATE))
+    }
+}
+
+// Song time in ms at which the sink's current source starts, 0 unless a seek skipped into the
+// song. The sink's sample counter, and the wall clock with it, count from there.
+#[derive(Default)]
+pub struct SeekBase(pub u64);
+
+// A seek to that fraction of the song, set by the number keys and carried out by the main loop,
+// which has to rebuild the sink to do it.
+#[derive(Default)]
+pub struct SeekRequest(pub Option<f64>);
+
+// How a song's first beat offset is chosen. A manual override always wins, otherwise the offset
+// is detected from the audio when `auto_offset` is set and taken from the chart when it isn't.
+#[derive(Debug)]
+#[derive(Default)]
+#[derive(Clone)]
+#[derive(Copy)]
+pub struct SongConfig {
+    pub auto_offset: bool,
+    pub offset_override: Option<u64>,
+}
+
+impl SongConfig {
+    pub fn first_beat_offset<F: FnOnce() -> u64>(&self, chart_offset: u64, analyze: F) -> u64 {
+        match self.offset_override {
+            Some(offset) => offset,
+            None if self.auto_offset => analyze(),
+            None => chart_offset,
+        }
+    }
+}
+
+// Estimates the first beat offset in ms from the first sharp rise in energy in the opening
+// ANALYSIS_MILLIS of audio. It's only as precise as ENERGY_WINDOW_MILLIS, finds the first attack
+// rather than the first downbeat, so quiet pickups or a pad fading in before the beat will fool it,
+// and returns 0 when nothing in the analyzed audio stands out.
+pub fn analyze_offset<S>(source: S) -> u64
+where
+    S: Source,
+    S::Item: Sample,
+{
+    let channels = source.channels().max(1) as usize;
+    let frames_per_window = (source.sample_rate() as u64 * ENERGY_WINDOW_MILLIS / 1000).max(1) as usize;
+    let samples_per_window = frames_per_window * channels;
+    let window_count = (ANALYSIS_MILLIS / ENERGY_WINDOW_MILLIS) as usize;
+
+    let samples: Vec<f32> = source.convert_samples().take(samples_per_window * window_count).collect();
+    let energies: Vec<f32> = samples
+        .chunks(samples_per_window)
+        .map(|window| window.iter().map(|sample| sample * sample).sum::<f32>() / window.len() as f32)
+        .collect();
+
+    let rises: Vec<f32> = energies.windows(2).map(|pair| (pair[1] - pair[0]).max(0.0)).collect();
+    let largest_rise = rises.iter().cloned().fold(0.0, f32::max);
+    if largest_rise <= 0.0 {
+        return 0;
+    }
+
+    rises.iter()
+        .position(|&rise| rise >= largest_rise * ONSET_THRESHOLD)
+        .map_or(0, |index| (index as u64 + 1) * ENERGY_WINDOW_MILLIS)
+}
+
+// How long play carries on after the song ends so the last notes can still be judged.
+pub const SONG_END_GRACE_MILLIS: f64 = 2000.0;
+
+#[derive(Default)]
+pub struct SongEnd {
+    pub ended: bool,
+    pub grace_elapsed: f64,
+    pub finished: bool,
+}
+
+// The song has ended once audio_time passes SongLength, or when the decoder couldn't report a
+// length, once the sink has drained. Play moves on to the results SONG_END_GRACE_MILLIS of frame
+// time after that, counted in frames since audio_time stops advancing along with the sink.
+pub(crate) struct SongEndSystem;
+
+impl<'a> System<'a> for SongEndSystem {
+    type SystemData = (Read<'a, AudioTime>,
+                       Read<'a, FrameTime>,
+                       Read<'a, SongLength>,
+                       Read<'a, LeadIn>,
+                       Option<Read<'a, Sink>>,
+                       Write<'a, SongEnd>,
+                       Write<'a, GameState>);
+
+    fn run(&mut self, data: Self::SystemData) {
+        let (audio_time, frame_time, song_length, lead_in, maybe_sink, mut song_end, mut game_state) = data;
+
+        if song_end.finished || lead_in.0 > 0 || *game_state != GameState::Playing {
+            return;
+        }
+
+        if !song_end.ended {
+            song_end.ended = if song_length.0 > 0 {
+                audio_time.0 >= song_length.0
+            } else {
+                maybe_sink.is_some_and(|sink| sink.empty())
+            };
+            return;
+        }
+
+        song_end.grace_elapsed += frame_time.0;
+        if song_end.grace_elapsed >= SONG_END_GRACE_MILLIS {
+            song_end.finished = true;
+            *game_state = GameState::Results;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{env, fs};
+
+    // Writes `bytes` to a file of that name in the temp directory and opens it.
+    fn open_bytes(name: &str, bytes: &[u8]) -> Result<Decoder<BufReader<File>>, SongError> {
+        let path = env::temp_dir().join(format!("rhythm-{}-{}", std::process::id(), name));
+        fs::write(&path, bytes).unwrap();
+        let opened = open_audio(&path.to_string_lossy());
+        fs::remove_file(&path).unwrap();
+        opened
+    }
+
+    #[test]
+    fn truncated_files_are_decode_errors() {
+        // A RIFF header cut off before its fmt chunk, an Ogg page header cut off mid-way, and an
+        // MPEG frame header with none of the frame after it.
+        let truncated: &[(&str, &[u8])] = &[
+            ("truncated.wav", b"RIFF\x24\x08\x00\x00WAVEfm"),
+            ("truncated.ogg", b"OggS\x00\x02\x00\x00"),
+            ("truncated.flac", b"fLaC\x00\x00"),
+            ("truncated.mp3", b"\xff\xfb\x90\x64\x00"),
+        ];
+        for &(name, bytes) in truncated {
+            match open_bytes(name, bytes) {
+                Err(SongError::Decode { format, .. }) => assert_eq!(format, name.rsplit('.').next().unwrap()),
+                Err(err) => panic!("{}: expected a decode error, got {}", name, err),
+                Ok(_) => panic!("{}: decoded", name),
+            }
+        }
+    }
+
+    #[test]
+    fn unsupported_extensions_are_turned_away_before_opening() {
+        // The file doesn't exist, so getting past the extension would be an Io error.
+        match open_audio("missing/song.mid") {
+            Err(SongError::UnsupportedFormat { extension, .. }) => assert_eq!(extension, "mid"),
+            other => panic!("expected UnsupportedFormat, got {:?}", other.map(|_| ())),
+        }
+    }
+}
@@ -0,0 +1,86 @@
+use sdl2::keyboard::Keycode;
+
+use specs::prelude::*;
+
+use crate::{
+    IsRunning,
+    render::{Position, Text, LOGICAL_WIDTH},
+    sdl::{InputEvent, InputEvents},
+    theme::Theme,
+};
+
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(PartialEq)]
+#[derive(Eq)]
+#[derive(Default)]
+pub enum GameState {
+    #[default]
+    Menu,
+    Playing,
+    Paused,
+    Results,
+}
+
+// Ties an entity to the screen it belongs to. RenderingSystem draws an entity with a Scene only
+// while that state is current, and an entity without one (the notes, lanes and receptors) in
+// every state but the menu, so gameplay stays visible behind the pause and results screens.
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(Copy)]
+pub struct Scene(pub GameState);
+
+impl Component for Scene {
+    type Storage = VecStorage<Self>;
+}
+
+impl Scene {
+    pub fn visible(scene: Option<&Scene>, state: GameState) -> bool {
+        match scene {
+            Some(scene) => scene.0 == state,
+            None => state != GameState::Menu,
+        }
+    }
+}
+
+pub fn spawn_scene_text(world: &mut World, state: GameState, lines: &[&str]) {
+    let text_color = world.read_resource::<Theme>().text;
+    for (line, text) in lines.iter().enumerate() {
+        world.create_entity()
+            .with(Scene(state))
+            .with(Text(text.to_string()))
+            .with(Position { x: LOGICAL_WIDTH / 2.0 - 120.0, y: 250.0 + line as f64 * 30.0 })
+            .with(text_color)
+            .build();
+    }
+}
+
+// Enter resumes play and leaves the results screen, Escape pauses play and quits from everywhere
+// else. Leaving the menu is up to MenuSystem, which has to load a chart first.
+pub(crate) struct StateTransitionSystem;
+
+impl<'a> System<'a> for StateTransitionSystem {
+    type SystemData = (Read<'a, InputEvents>,
+                       Write<'a, GameState>,
+                       Write<'a, IsRunning>);
+
+    fn run(&mut self, data: Self::SystemData) {
+        let (input_events, mut game_state, mut is_running) = data;
+
+        for event in &input_events.events {
+            let next = match (*game_state, *event) {
+                (GameState::Playing, InputEvent { keycode: Some(Keycode::Escape), .. }) => GameState::Paused,
+                (GameState::Paused, InputEvent { keycode: Some(Keycode::Return), .. }) => GameState::Playing,
+                (_, InputEvent { keycode: Some(Keycode::Escape), .. }) |
+                (GameState::Results, InputEvent { keycode: Some(Keycode::Return), .. }) => {
+                    is_running.0 = false;
+                    continue;
+                },
+                _ => continue,
+            };
+            log::info!("{:?} -> {:?}", *game_state, next);
+            *game_state = next;
+        }
+    }
+}
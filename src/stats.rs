@@ -0,0 +1,103 @@
+use specs::prelude::*;
+
+use std::collections::VecDeque;
+
+use crate::{
+    AudioTime,
+    rhythm::{Judgement, JudgementHistory},
+};
+
+pub const NPS_WINDOW_MILLIS: u64 = 1000;
+
+// Notes hit per second over the last NPS_WINDOW_MILLIS of audio time.
+#[derive(Default)]
+pub struct Nps(pub f64);
+
+// Stamps every new non-miss judgement with the audio time it showed up at and counts the ones still
+// inside the window. The count is always over the full window, so for the first second of a song
+// it reads low instead of spiking off a couple of early hits.
+#[derive(Default)]
+pub(crate) struct NpsSystem {
+    hits: VecDeque<u64>,
+    judgements_seen: usize,
+}
+
+impl<'a> System<'a> for NpsSystem {
+    type SystemData = (Read<'a, AudioTime>,
+                       Read<'a, JudgementHistory>,
+                       Write<'a, Nps>);
+
+    fn run(&mut self, data: Self::SystemData) {
+        let (audio_time, judgement_history, mut nps) = data;
+
+        let new_judgements = judgement_history.0.get(self.judgements_seen..).unwrap_or(&[]);
+        for _ in new_judgements.iter().filter(|&&judgement| judgement != Judgement::Miss) {
+            self.hits.push_back(audio_time.0);
+        }
+        self.judgements_seen = judgement_history.0.len();
+
+        // After a seek back the hits stamped past the new audio_time haven't happened yet.
+        while self.hits.back().is_some_and(|&hit_time| hit_time > audio_time.0) {
+            self.hits.pop_back();
+        }
+        let window_start = audio_time.0.saturating_sub(NPS_WINDOW_MILLIS);
+        while self.hits.front().is_some_and(|&hit_time| hit_time < window_start) {
+            self.hits.pop_front();
+        }
+
+        nps.0 = self.hits.len() as f64 * 1000.0 / NPS_WINDOW_MILLIS as f64;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use specs::RunNow;
+
+    // Runs the system at `time` after `hits` more Perfects.
+    fn run_at(world: &mut World, nps_system: &mut NpsSystem, time: u64, hits: usize) -> f64 {
+        world.add_resource(AudioTime(time));
+        world.write_resource::<JudgementHistory>().0.extend(vec![Judgement::Perfect; hits]);
+        nps_system.run_now(&world.res);
+        world.read_resource::<Nps>().0
+    }
+
+    fn nps_world() -> (World, NpsSystem) {
+        let mut world = World::new();
+        let mut nps_system = NpsSystem::default();
+        System::setup(&mut nps_system, &mut world.res);
+        (world, nps_system)
+    }
+
+    #[test]
+    fn window_slides_with_audio_time() {
+        let (mut world, mut nps_system) = nps_world();
+        run_at(&mut world, &mut nps_system, 1100, 1);
+        run_at(&mut world, &mut nps_system, 1200, 2);
+        assert_eq!(run_at(&mut world, &mut nps_system, 1900, 1), 4.0);
+        // The hit at 1100 drops out first, then the two at 1200.
+        assert_eq!(run_at(&mut world, &mut nps_system, 2150, 0), 3.0);
+        assert_eq!(run_at(&mut world, &mut nps_system, 2250, 0), 1.0);
+        // Misses don't count.
+        world.write_resource::<JudgementHistory>().0.push(Judgement::Miss);
+        assert_eq!(run_at(&mut world, &mut nps_system, 2300, 0), 1.0);
+    }
+
+    #[test]
+    fn first_second_counts_over_the_full_window() {
+        let (mut world, mut nps_system) = nps_world();
+        assert_eq!(run_at(&mut world, &mut nps_system, 0, 0), 0.0);
+        // Two hits in the first 250ms are two a second, not eight.
+        assert_eq!(run_at(&mut world, &mut nps_system, 250, 2), 2.0);
+    }
+
+    #[test]
+    fn seeking_back_forgets_hits_after_the_new_time() {
+        let (mut world, mut nps_system) = nps_world();
+        run_at(&mut world, &mut nps_system, 5000, 2);
+        run_at(&mut world, &mut nps_system, 5500, 3);
+        assert_eq!(run_at(&mut world, &mut nps_system, 5200, 0), 2.0);
+        assert_eq!(run_at(&mut world, &mut nps_system, 1000, 0), 0.0);
+    }
+}
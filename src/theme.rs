@@ -0,0 +1,130 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    lanes::LaneLayout,
+    render::Color,
+    rhythm::Judgement,
+};
+
+// Every color the game draws with, apart from the debug overlay. Lane colors go by lane index and
+// are copied onto the LaneLayout when the theme is applied, the rest are read where they're drawn.
+#[derive(Debug)]
+#[derive(Clone)]
+pub struct Theme {
+    pub background: Color,
+    // The background flashes to this on each bar or beat, see BeatFlashSystem.
+    pub beat_pulse: Color,
+    pub judgement_line: Color,
+    pub text: Color,
+    pub selected_text: Color,
+    pub beat_line: Color,
+    pub bar_line: Color,
+    pub perfect: Color,
+    pub great: Color,
+    pub good: Color,
+    pub miss: Color,
+    // Hit error ticks, see HitTickSystem.
+    pub early: Color,
+    pub late: Color,
+    pub progress: Color,
+    pub lanes: Vec<Color>,
+}
+
+impl Default for Theme {
+    fn default() -> Theme { Theme::light() }
+}
+
+impl Theme {
+    pub fn light() -> Theme {
+        Theme {
+            background: Color::rgb(255, 255, 255),
+            beat_pulse: Color::rgb(127, 127, 127),
+            judgement_line: Color::rgb(0, 0, 0),
+            text: Color::rgb(0, 0, 0),
+            selected_text: Color::rgb(200, 0, 0),
+            beat_line: Color::rgb(170, 170, 170),
+            bar_line: Color::rgb(255, 140, 0),
+            perfect: Color::rgb(255, 200, 0),
+            great: Color::rgb(80, 200, 80),
+            good: Color::rgb(80, 140, 255),
+            miss: Color::rgb(160, 160, 160),
+            early: Color::rgb(30, 90, 230),
+            late: Color::rgb(220, 40, 40),
+            progress: Color::rgb(0, 120, 215),
+            lanes: vec![
+                Color::rgb(200, 40, 40),
+                Color::rgb(40, 40, 200),
+                Color::rgb(40, 160, 40),
+                Color::rgb(200, 140, 0),
+            ],
+        }
+    }
+
+    pub fn dark() -> Theme {
+        let background = Color::rgb(20, 20, 28);
+        Theme {
+            background,
+            // Brightened rather than darkened, pulsing toward black wouldn't show.
+            beat_pulse: background.scale(3.5),
+            judgement_line: Color::rgb(220, 220, 220),
+            text: Color::rgb(230, 230, 230),
+            selected_text: Color::rgb(255, 90, 90),
+            beat_line: Color::rgb(70, 70, 80),
+            bar_line: Color::rgb(255, 140, 0),
+            perfect: Color::rgb(255, 210, 60),
+            great: Color::rgb(100, 220, 100),
+            good: Color::rgb(100, 160, 255),
+            miss: Color::rgb(110, 110, 110),
+            early: Color::rgb(80, 140, 255),
+            late: Color::rgb(255, 80, 80),
+            progress: Color::rgb(90, 160, 255),
+            lanes: vec![
+                Color::rgb(240, 80, 80),
+                Color::rgb(90, 110, 255),
+                Color::rgb(80, 210, 80),
+                Color::rgb(240, 180, 40),
+            ],
+        }
+    }
+
+    pub fn judgement(&self, judgement: Judgement) -> Color {
+        match judgement {
+            Judgement::Perfect => self.perfect,
+            Judgement::Great => self.great,
+            Judgement::Good => self.good,
+            Judgement::Miss => self.miss,
+        }
+    }
+
+    // Lanes past the end of `lanes` keep the color they were configured with.
+    pub fn apply_to_lanes(&self, lane_layout: &mut LaneLayout) {
+        if self.lanes.is_empty() {
+            return;
+        }
+        for (lane, &color) in lane_layout.0.iter_mut().zip(self.lanes.iter().cycle()) {
+            lane.color = color;
+        }
+    }
+}
+
+// The preset a settings file picks.
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(PartialEq)]
+#[derive(Default)]
+#[derive(Serialize, Deserialize)]
+pub enum ThemeName {
+    #[default]
+    Light,
+    Dark,
+}
+
+impl ThemeName {
+    pub fn theme(&self) -> Theme {
+        match self {
+            ThemeName::Light => Theme::light(),
+            ThemeName::Dark => Theme::dark(),
+        }
+    }
+}